@@ -1,136 +1,285 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use colored::*;
-use num_cpus;
 use dashmap::DashMap;
 use indicatif::{ProgressBar, ProgressStyle};
 use memmap2::Mmap;
 use rayon::prelude::*;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::{Instant, UNIX_EPOCH};
-use walkdir::WalkDir;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+
+// Progress/status messages go to stderr when a structured report is written to stdout,
+// so that `loco -f csv | ...` only ever pipes the report itself
+macro_rules! status {
+    ($args:expr, $($arg:tt)*) => {
+        if is_structured_format(&$args.format) && $args.output.is_none() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "loco")]
 #[command(about = "🚀 Fast Line Counter")]
 #[command(version = "0.2.0")]
 struct Args {
-    /// Path to analyze
-    #[arg(short, long)]
-    path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Paths to analyze (repeatable; overlapping paths are counted once)
+    #[arg(short, long, num_args = 1.., default_value = ".", global = true)]
+    path: Vec<PathBuf>,
+
+    /// Analyze the files in this newline- or NUL-separated list ("-" for stdin) that lie inside
+    /// the paths, instead of walking the paths (e.g. `git ls-files -z | loco --files-from -`)
+    #[arg(long, value_name = "FILE", conflicts_with = "git_ref", global = true)]
+    files_from: Option<PathBuf>,
+
+    /// Count the files of a git commit, tag or branch, read from the repository without a checkout
+    #[arg(long, value_name = "REV", global = true)]
+    git_ref: Option<String>,
+
+    /// Show a per-path breakdown next to the combined totals
+    #[arg(long, global = true)]
+    per_root: bool,
 
     /// Verbose output with detailed statistics
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
     /// Output format: text, json, csv, markdown, xml, html
-    #[arg(short, long, default_value = "text")]
+    #[arg(short, long, default_value = "text", global = true)]
     format: String,
 
+    /// Table written by --format csv: one row per language, per file, or per --fail-if/--policy violation
+    #[arg(long, default_value = "languages", value_parser = ["languages", "files", "violations"], global = true)]
+    csv_table: String,
+
     /// Exclude directories (regex supported)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     exclude: Option<String>,
 
     /// Include only specific extensions (comma-separated)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     include: Option<String>,
 
+    /// Include only paths matching this gitignore-style glob, relative to the scan root (repeatable)
+    #[arg(long, value_name = "GLOB", global = true)]
+    include_glob: Vec<String>,
+
+    /// Exclude paths matching this gitignore-style glob, relative to the scan root (repeatable;
+    /// combined with --include-glob, the last matching glob on the command line wins)
+    #[arg(long, value_name = "GLOB", global = true)]
+    exclude_glob: Vec<String>,
+
+    // --include-glob/--exclude-glob interleaved in command-line order, filled in by main
+    #[arg(skip)]
+    path_globs: Vec<PathGlob>,
+
+    // Record FileInfo::content_hash for every analyzed file (set for the sides of `loco diff`)
+    #[arg(skip)]
+    content_hashes: bool,
+
+    /// Don't respect .gitignore, .ignore, .locoignore or git exclude files
+    #[arg(long, global = true)]
+    no_ignore: bool,
+
+    /// Don't skip the built-in list of dependency/build directories
+    #[arg(long, global = true)]
+    no_default_excludes: bool,
+
     /// Maximum file size to analyze (in MB)
-    #[arg(long, default_value = "100")]
+    #[arg(long, default_value = "100", global = true)]
     max_size: u64,
 
     /// Number of threads (0 = auto)
-    #[arg(short, long, default_value = "0")]
+    #[arg(short, long, default_value = "0", global = true)]
     threads: usize,
 
     /// Show progress bar
-    #[arg(short = 'P', long)]
+    #[arg(short = 'P', long, global = true)]
     progress: bool,
 
     /// Analyze code complexity
-    #[arg(short = 'C', long)]
+    #[arg(short = 'C', long, global = true)]
     complexity: bool,
 
     /// Show file size statistics
-    #[arg(short = 'S', long)]
+    #[arg(short = 'S', long, global = true)]
     size_stats: bool,
 
     /// Group by directory structure
-    #[arg(short = 'G', long)]
+    #[arg(short = 'G', long, global = true)]
     group_by_dir: bool,
 
-    /// Show git statistics (if in git repo)
-    #[arg(long)]
+    /// Directory depth for --group-by-dir (0 = unlimited)
+    #[arg(long, default_value = "2", global = true)]
+    dir_depth: usize,
+
+    /// Show git statistics (if in git repo; they describe the whole repository, so not with --git-ref)
+    #[arg(long, global = true)]
     git_stats: bool,
 
+    /// Count per-file commits, lines added/deleted and authors in this git log window (e.g. 6.months,
+    /// 2024-01-01) and rank --hotspots by churn × complexity
+    #[arg(long, value_name = "WHEN", global = true)]
+    since: Option<String>,
+
     /// Sort by: lines, files, size, name
-    #[arg(long, default_value = "lines")]
+    #[arg(long, default_value = "lines", global = true)]
     sort_by: String,
 
     /// Show top N languages only
-    #[arg(long)]
+    #[arg(long, global = true)]
     top: Option<usize>,
 
     /// Minimum lines to show language
-    #[arg(long, default_value = "1")]
+    #[arg(long, default_value = "1", global = true)]
     min_lines: usize,
 
     /// Save output to file
-    #[arg(short = 'o', long)]
+    #[arg(short = 'o', long, global = true)]
     output: Option<PathBuf>,
 
+    /// Generated, minified and vendored files: include (count normally), exclude (skip), or separate (own bucket,
+    /// left out of the totals). vendor/, node_modules/ and bower_components/ are default excludes and are only
+    /// walked with include or --no-default-excludes
+    #[arg(long, default_value = "separate", value_parser = ["include", "exclude", "separate"], global = true)]
+    generated: String,
+
+    /// List skipped files (binary, too large, unreadable, excluded, ...) with reasons
+    #[arg(long, global = true)]
+    show_skipped: bool,
+
     /// Show encoding information
-    #[arg(long)]
+    #[arg(long, global = true)]
     encoding: bool,
 
     /// Analyze file creation/modification times
-    #[arg(long)]
+    #[arg(long, global = true)]
     time_analysis: bool,
 
     /// Show duplicate code detection
-    #[arg(long)]
+    #[arg(long, global = true)]
     duplicates: bool,
 
+    /// Minimum block length (in normalized code lines) for duplicate detection
+    #[arg(long, default_value = "6", global = true)]
+    min_dup_lines: usize,
+
     /// Export detailed report (HTML/Markdown)
-    #[arg(long)]
+    #[arg(long, global = true)]
     report: bool,
 
     /// Show top files by metric (lines, complexity, todos, size)
-    #[arg(long)]
+    #[arg(long, global = true)]
     top_files: Option<String>,
 
     /// Show hotspot detection (risky files)
-    #[arg(long)]
+    #[arg(long, global = true)]
     hotspots: bool,
 
     /// Use memory mapping for large files
-    #[arg(long)]
+    #[arg(long, global = true)]
     use_mmap: bool,
 
     /// Enable caching for repeated analysis
-    #[arg(long)]
+    #[arg(long, global = true)]
     cache: bool,
 
+    /// Cache file location (default: .loco-cache in the analyzed directory)
+    #[arg(long, global = true)]
+    cache_file: Option<PathBuf>,
+
+    /// Validate cache entries by content hash instead of modification time
+    #[arg(long, global = true)]
+    cache_hash: bool,
+
+    /// Extra language definitions (TOML or JSON); .loco-languages.toml/.json in the analyzed directory is loaded automatically
+    #[arg(long, value_name = "FILE", global = true)]
+    languages: Option<PathBuf>,
+
     /// Include unknown file types with simple parsing
-    #[arg(long)]
+    #[arg(long, global = true)]
     include_unknown: bool,
 
     /// Fast mode - optimized for speed (basic counting only)
-    #[arg(long)]
+    #[arg(long, global = true)]
     fast: bool,
 
     /// Very fast mode - ultra optimized for maximum speed
-    #[arg(long)]
+    #[arg(long, global = true)]
     very_fast: bool,
 
     /// Benchmark mode - show detailed performance metrics
-    #[arg(long)]
+    #[arg(long, global = true)]
     benchmark: bool,
+
+    /// Save a versioned JSON snapshot of this run for later --baseline comparisons
+    #[arg(long, value_name = "FILE", global = true)]
+    save_baseline: Option<PathBuf>,
+
+    /// Compare this run against a snapshot written by --save-baseline
+    #[arg(long, value_name = "FILE", global = true)]
+    baseline: Option<PathBuf>,
+
+    /// With --baseline, fail when total lines grew by more than this percentage
+    #[arg(long, value_name = "PERCENT", global = true)]
+    max_line_growth: Option<f64>,
+
+    /// With --baseline, fail when overall maintainability dropped by more than this many points
+    #[arg(long, value_name = "POINTS", global = true)]
+    max_maintainability_drop: Option<f64>,
+
+    /// With --baseline, fail when more than this many files became hotspots
+    #[arg(long, value_name = "N", global = true)]
+    max_new_hotspots: Option<usize>,
+
+    /// With --baseline, fail when TODO and FIXME counts together rose by more than this
+    #[arg(long, value_name = "N", global = true)]
+    max_todo_increase: Option<u64>,
+
+    /// Fail (exit 4) when a rule holds, e.g. max-file-lines>2000, max-cyclomatic>15, min-comment-ratio<10,
+    /// max-todos>50, hotspots>0 (repeatable)
+    #[arg(long, value_name = "RULE", global = true)]
+    fail_if: Vec<String>,
+
+    /// Policy file (TOML or JSON) with a `fail-if` list of --fail-if rules
+    #[arg(long, value_name = "FILE", global = true)]
+    policy: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Compare two trees or git revisions: per-language and per-file line, complexity and TODO deltas
+    Diff {
+        /// Old side: a directory, or a git revision of the repository at --path
+        old: String,
+        /// New side: a directory, or a git revision of the repository at --path
+        new: String,
+    },
+    /// Analyze the repository at --path at points in its history, without checking them out
+    History {
+        /// Number of evenly spaced first-parent commits to analyze, up to --git-ref (default HEAD)
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+        /// Analyze each tag instead of sampled commits
+        #[arg(long, conflicts_with = "samples")]
+        tags: bool,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -182,11 +331,58 @@ impl Default for LanguageStats {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl LanguageStats {
+    // Fold another set of stats into this one; averages are weighted by file count
+    fn merge(&mut self, other: &LanguageStats) {
+        let old_count = self.files;
+        self.total_lines += other.total_lines;
+        self.code_lines += other.code_lines;
+        self.comment_lines += other.comment_lines;
+        self.blank_lines += other.blank_lines;
+        self.files += other.files;
+        self.total_size += other.total_size;
+
+        // Update weighted averages (optimized calculation)
+        if old_count > 0 {
+            let weight_old = old_count as f64;
+            let weight_other = other.files as f64;
+            let weight_new = self.files as f64;
+
+            self.avg_line_length = (self.avg_line_length * weight_old + other.avg_line_length * weight_other) / weight_new;
+            self.complexity_score = (self.complexity_score * weight_old + other.complexity_score * weight_other) / weight_new;
+            self.maintainability_index = (self.maintainability_index * weight_old + other.maintainability_index * weight_other) / weight_new;
+            self.cyclomatic_complexity = (self.cyclomatic_complexity * weight_old + other.cyclomatic_complexity * weight_other) / weight_new;
+        } else {
+            self.avg_line_length = other.avg_line_length;
+            self.complexity_score = other.complexity_score;
+            self.maintainability_index = other.maintainability_index;
+            self.cyclomatic_complexity = other.cyclomatic_complexity;
+        }
+
+        self.max_line_length = self.max_line_length.max(other.max_line_length);
+        self.functions += other.functions;
+        self.classes += other.classes;
+        self.imports += other.imports;
+        self.todos += other.todos;
+        self.fixmes += other.fixmes;
+
+        // Update percentages
+        if self.total_lines > 0 {
+            self.code_percentage = self.code_lines as f64 / self.total_lines as f64 * 100.0;
+            self.comment_percentage = self.comment_lines as f64 / self.total_lines as f64 * 100.0;
+            self.blank_percentage = self.blank_lines as f64 / self.total_lines as f64 * 100.0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileInfo {
     path: PathBuf,
     language: String,
     lines: u64,
+    code_lines: u64,
+    comment_lines: u64,
+    blank_lines: u64,
     size: u64,
     encoding: String,
     complexity: f64,
@@ -197,9 +393,23 @@ struct FileInfo {
     cyclomatic_complexity: f64,
     maintainability_index: f64,
     technical_debt_ratio: f64,
+    language_detection: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    embedded: Vec<EmbeddedStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notebook: Option<NotebookCells>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    generated: Option<GeneratedInfo>,
+    // Change history over the --since window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    churn: Option<FileChurn>,
+    // Hash of the raw bytes, recorded for the sides of `loco diff` so edits that leave every
+    // count alone still show up as modified (the same hash for working-tree files and blobs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_hash: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct GitStats {
     total_commits: usize,
     contributors: usize,
@@ -212,7 +422,16 @@ struct GitStats {
     most_active_author: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RootStats {
+    path: PathBuf,
+    total_files: u64,
+    total_lines: u64,
+    total_size: u64,
+    languages: HashMap<String, LanguageStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct ProjectStats {
     languages: HashMap<String, LanguageStats>,
     total_files: u64,
@@ -224,21 +443,46 @@ struct ProjectStats {
     modification_dates: Vec<u64>,
     files_info: Vec<FileInfo>,
     hotspots: Vec<FileInfo>,
+    duplication: Option<DuplicationReport>,
     directory_stats: HashMap<String, LanguageStats>,
+    // Host language name -> languages embedded in its files
+    embedded_languages: HashMap<String, Vec<EmbeddedStats>>,
+    skipped_files: Vec<SkippedFile>,
+    // Directories pruned from the walk (default excludes, glob excludes, unreadable); their
+    // contents are never listed, so they are not counted as skipped files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped_dirs: Vec<SkippedFile>,
+    // Generated, minified and vendored files when --generated separate (the default)
+    generated_languages: HashMap<String, LanguageStats>,
+    generated_files: Vec<FileInfo>,
+    // Per scan root with --per-root
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roots: Vec<RootStats>,
+    // The revision counted with --git-ref
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<GitRevision>,
+    // How this run compares to the --baseline snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    baseline: Option<BaselineComparison>,
+    // Broken --fail-if/--policy rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    policy_violations: Vec<PolicyViolation>,
     performance_metrics: PerformanceMetrics,
     quality_metrics: QualityMetrics,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct PerformanceMetrics {
     files_per_second: f64,
     lines_per_second: f64,
     bytes_per_second: f64,
     peak_memory_usage: u64,
     cpu_utilization: f64,
+    cache_hits: usize,
+    cache_misses: usize,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct QualityMetrics {
     overall_maintainability: f64,
     technical_debt_ratio: f64,
@@ -247,113 +491,280 @@ struct QualityMetrics {
     code_duplication_ratio: f64,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Hash)]
 struct LanguageConfig {
     single_line_comments: Vec<String>,
-    multi_line_comments: Vec<(String, String)>,
+    multi_line_comments: Vec<BlockMarker>,
     function_keywords: Vec<String>,
     class_keywords: Vec<String>,
     import_keywords: Vec<String>,
     complexity_keywords: Vec<String>,
     test_keywords: Vec<String>,
     doc_keywords: Vec<String>,
+    string_literals: Vec<StringLiteral>,
+}
+
+// A block comment delimiter pair; nesting markers treat `/* /* */ */` as one comment,
+// as in Rust, Swift, Haskell or OCaml (D nests `/+ +/` but not `/* */`)
+#[derive(Clone, Hash)]
+struct BlockMarker {
+    start: String,
+    end: String,
+    nests: bool,
+}
+
+impl BlockMarker {
+    fn flat(start: &str, end: &str) -> Self {
+        Self { start: start.into(), end: end.into(), nests: false }
+    }
+
+    fn nesting(start: &str, end: &str) -> Self {
+        Self { start: start.into(), end: end.into(), nests: true }
+    }
 }
 
 impl LanguageConfig {
-    fn get_config(extension: &str) -> Option<Self> {
+    fn builtin(extension: &str) -> Option<Self> {
         match extension.to_lowercase().as_str() {
             "rs" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::nesting("/*", "*/")],
                 function_keywords: vec!["fn ".into(), "async fn ".into()],
                 class_keywords: vec!["struct ".into(), "enum ".into(), "trait ".into(), "impl ".into()],
                 import_keywords: vec!["use ".into(), "extern ".into(), "mod ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "match ".into(), "loop ".into(), "else if ".into()],
                 test_keywords: vec!["#[test]".into(), "#[cfg(test)]".into(), "assert!".into()],
                 doc_keywords: vec!["///".into(), "//!".into(), "#[doc".into()],
+                string_literals: vec![
+                    StringLiteral::raw("br###\"", "\"###", true),
+                    StringLiteral::raw("r###\"", "\"###", true),
+                    StringLiteral::raw("br##\"", "\"##", true),
+                    StringLiteral::raw("r##\"", "\"##", true),
+                    StringLiteral::raw("br#\"", "\"#", true),
+                    StringLiteral::raw("r#\"", "\"#", true),
+                    StringLiteral::raw("br\"", "\"", true),
+                    StringLiteral::raw("r\"", "\"", true),
+                    StringLiteral::multiline("\"", "\""),
+                    StringLiteral::char_literal(),
+                ],
             }),
             "py" | "pyw" | "pyi" => Some(Self {
                 single_line_comments: vec!["#".into()],
-                multi_line_comments: vec![("\"\"\"".into(), "\"\"\"".into()), ("'''".into(), "'''".into())],
+                multi_line_comments: vec![BlockMarker::flat("\"\"\"", "\"\"\""), BlockMarker::flat("'''", "'''")],
                 function_keywords: vec!["def ".into(), "async def ".into(), "lambda ".into()],
                 class_keywords: vec!["class ".into()],
                 import_keywords: vec!["import ".into(), "from ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "try ".into(), "except ".into(), "with ".into(), "elif ".into()],
                 test_keywords: vec!["def test_".into(), "import unittest".into(), "import pytest".into()],
                 doc_keywords: vec!["\"\"\"".into(), "'''".into(), "# TODO".into(), "# FIXME".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::multiline("'''", "'''"),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::escaped("'"),
+                ],
             }),
             "js" | "ts" | "jsx" | "tsx" | "mjs" | "cjs" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["function ".into(), "=>".into(), "async ".into(), "const ".into(), "let ".into(), "var ".into()],
                 class_keywords: vec!["class ".into(), "interface ".into(), "type ".into(), "enum ".into()],
                 import_keywords: vec!["import ".into(), "require(".into(), "export ".into(), "from ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try ".into(), "catch ".into(), "else if ".into()],
                 test_keywords: vec!["describe(".into(), "it(".into(), "test(".into(), "expect(".into()],
                 doc_keywords: vec!["/**".into(), "//".into(), "@param".into(), "@return".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::escaped("'"), StringLiteral::multiline("`", "`")],
+            }),
+            "java" => Some(Self {
+                single_line_comments: vec!["//".into()],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
+                function_keywords: vec!["public ".into(), "private ".into(), "protected ".into(), "static ".into()],
+                class_keywords: vec!["class ".into(), "interface ".into(), "enum ".into(), "abstract ".into()],
+                import_keywords: vec!["import ".into(), "package ".into()],
+                complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try ".into(), "catch ".into(), "else if ".into()],
+                test_keywords: vec!["@Test".into(), "junit".into(), "testng".into()],
+                doc_keywords: vec!["/**".into(), "//".into(), "@param".into(), "@return".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::char_literal(),
+                ],
             }),
-            "java" | "kt" | "scala" => Some(Self {
+            "kt" | "kts" | "scala" | "sc" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::nesting("/*", "*/")],
                 function_keywords: vec!["public ".into(), "private ".into(), "protected ".into(), "static ".into()],
                 class_keywords: vec!["class ".into(), "interface ".into(), "enum ".into(), "abstract ".into()],
                 import_keywords: vec!["import ".into(), "package ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try ".into(), "catch ".into(), "else if ".into()],
                 test_keywords: vec!["@Test".into(), "junit".into(), "testng".into()],
                 doc_keywords: vec!["/**".into(), "//".into(), "@param".into(), "@return".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::char_literal(),
+                ],
             }),
             "c" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["int ".into(), "void ".into(), "char ".into(), "float ".into(), "double ".into(), "static ".into()],
                 class_keywords: vec!["struct ".into(), "union ".into(), "enum ".into(), "typedef ".into()],
                 import_keywords: vec!["#include".into(), "#import".into(), "#define".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "else if ".into()],
                 test_keywords: vec!["TEST(".into(), "ASSERT_".into(), "EXPECT_".into()],
                 doc_keywords: vec!["/**".into(), "//!".into(), "///".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::char_literal()],
             }),
             "h" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["extern ".into(), "static ".into(), "inline ".into()],
                 class_keywords: vec!["struct ".into(), "union ".into(), "enum ".into(), "typedef ".into()],
                 import_keywords: vec!["#include".into(), "#import".into(), "#define".into(), "#ifndef".into(), "#ifdef".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "else if ".into()],
                 test_keywords: vec!["TEST(".into(), "ASSERT_".into(), "EXPECT_".into()],
                 doc_keywords: vec!["/**".into(), "//!".into(), "///".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::char_literal()],
             }),
             "cpp" | "cc" | "cxx" | "hpp" | "c++" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["int ".into(), "void ".into(), "char ".into(), "float ".into(), "double ".into(), "bool ".into()],
                 class_keywords: vec!["class ".into(), "struct ".into(), "union ".into(), "enum ".into(), "namespace ".into()],
                 import_keywords: vec!["#include".into(), "#import".into(), "using ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "else if ".into()],
                 test_keywords: vec!["TEST(".into(), "ASSERT_".into(), "EXPECT_".into()],
                 doc_keywords: vec!["/**".into(), "//!".into(), "///".into()],
+                string_literals: vec![
+                    StringLiteral::raw("R\"(", ")\"", true),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::char_literal(),
+                ],
+            }),
+            "hs" => Some(Self {
+                single_line_comments: vec!["--".into()],
+                multi_line_comments: vec![BlockMarker::nesting("{-", "-}")],
+                function_keywords: vec![" :: ".into(), "where".into()],
+                class_keywords: vec!["data ".into(), "newtype ".into(), "class ".into(), "instance ".into(), "type ".into()],
+                import_keywords: vec!["import ".into(), "module ".into()],
+                complexity_keywords: vec!["if ".into(), "case ".into(), "| ".into(), "guard ".into()],
+                test_keywords: vec!["describe ".into(), "it ".into(), "prop_".into(), "testCase".into()],
+                doc_keywords: vec!["-- |".into(), "{-|".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::char_literal()],
+            }),
+            "ml" | "mli" => Some(Self {
+                single_line_comments: vec![],
+                multi_line_comments: vec![BlockMarker::nesting("(*", "*)")],
+                function_keywords: vec!["let ".into(), "fun ".into(), "function".into()],
+                class_keywords: vec!["type ".into(), "module ".into(), "class ".into()],
+                import_keywords: vec!["open ".into(), "include ".into()],
+                complexity_keywords: vec!["if ".into(), "match ".into(), "while ".into(), "for ".into(), "try ".into()],
+                test_keywords: vec!["assert ".into(), "test_".into()],
+                doc_keywords: vec!["(**".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::char_literal()],
+            }),
+            "d" => Some(Self {
+                single_line_comments: vec!["//".into()],
+                // Only /+ +/ nests in D; /* */ ends at the first */
+                multi_line_comments: vec![BlockMarker::nesting("/+", "+/"), BlockMarker::flat("/*", "*/")],
+                function_keywords: vec!["void ".into(), "int ".into(), "auto ".into(), "bool ".into(), "string ".into()],
+                class_keywords: vec!["class ".into(), "struct ".into(), "interface ".into(), "enum ".into(), "union ".into()],
+                import_keywords: vec!["import ".into(), "module ".into()],
+                complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "foreach ".into(), "switch ".into(), "else if ".into()],
+                test_keywords: vec!["unittest".into(), "assert(".into()],
+                doc_keywords: vec!["///".into(), "/**".into(), "/++".into()],
+                string_literals: vec![
+                    StringLiteral::raw("r\"", "\"", true),
+                    StringLiteral::raw("`", "`", true),
+                    StringLiteral::multiline("\"", "\""),
+                    StringLiteral::char_literal(),
+                ],
+            }),
+            "objc" | "mm" => Some(Self {
+                single_line_comments: vec!["//".into()],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
+                function_keywords: vec!["- (".into(), "+ (".into(), "void ".into(), "int ".into(), "static ".into()],
+                class_keywords: vec!["@interface".into(), "@implementation".into(), "@protocol".into(), "struct ".into()],
+                import_keywords: vec!["#import".into(), "#include".into(), "@import".into()],
+                complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "@try".into(), "@catch".into(), "else if ".into()],
+                test_keywords: vec!["XCTAssert".into(), "- (void)test".into()],
+                doc_keywords: vec!["/**".into(), "///".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::char_literal()],
+            }),
+            "matlab" => Some(Self {
+                single_line_comments: vec!["%".into()],
+                multi_line_comments: vec![BlockMarker::flat("%{", "%}")],
+                function_keywords: vec!["function ".into()],
+                class_keywords: vec!["classdef ".into()],
+                import_keywords: vec!["import ".into()],
+                complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try".into(), "elseif ".into()],
+                test_keywords: vec!["assert(".into(), "matlab.unittest".into()],
+                doc_keywords: vec!["%".into()],
+                // ' doubles as the transpose operator, so only "..." strings are tracked
+                string_literals: vec![StringLiteral::raw("\"", "\"", false)],
+            }),
+            "prolog" => Some(Self {
+                single_line_comments: vec!["%".into()],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
+                function_keywords: vec![":-".into()],
+                class_keywords: vec![],
+                import_keywords: vec![":- use_module".into(), ":- module".into(), ":- include".into()],
+                complexity_keywords: vec!["->".into(), ";".into()],
+                test_keywords: vec![":- begin_tests".into()],
+                doc_keywords: vec!["%".into(), "/**".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::escaped("'")],
+            }),
+            "verilog" | "sv" | "svh" => Some(Self {
+                single_line_comments: vec!["//".into()],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
+                function_keywords: vec!["function ".into(), "task ".into()],
+                class_keywords: vec!["module ".into(), "interface ".into(), "class ".into(), "package ".into()],
+                import_keywords: vec!["`include".into(), "import ".into()],
+                complexity_keywords: vec!["if ".into(), "case ".into(), "for ".into(), "while ".into(), "always".into()],
+                test_keywords: vec!["$display".into(), "initial ".into(), "assert ".into()],
+                doc_keywords: vec!["//".into()],
+                string_literals: vec![StringLiteral::escaped("\"")],
+            }),
+            "coq" => Some(Self {
+                single_line_comments: vec![],
+                multi_line_comments: vec![BlockMarker::nesting("(*", "*)")],
+                function_keywords: vec!["Definition ".into(), "Fixpoint ".into(), "Lemma ".into(), "Theorem ".into()],
+                class_keywords: vec!["Inductive ".into(), "Record ".into(), "Class ".into(), "Module ".into()],
+                import_keywords: vec!["Require ".into(), "Import ".into(), "Export ".into()],
+                complexity_keywords: vec!["match ".into(), "if ".into(), "destruct ".into(), "induction ".into()],
+                test_keywords: vec!["Example ".into()],
+                doc_keywords: vec!["(**".into()],
+                string_literals: vec![StringLiteral::raw("\"", "\"", true)],
             }),
             "go" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["func ".into()],
                 class_keywords: vec!["type ".into(), "struct ".into(), "interface ".into()],
                 import_keywords: vec!["import ".into(), "package ".into()],
                 complexity_keywords: vec!["if ".into(), "for ".into(), "switch ".into(), "select ".into(), "else if ".into()],
                 test_keywords: vec!["func Test".into(), "testing.T".into()],
                 doc_keywords: vec!["//".into(), "/*".into()],
+                string_literals: vec![
+                    StringLiteral::escaped("\""),
+                    StringLiteral::raw("`", "`", true),
+                    StringLiteral::char_literal(),
+                ],
             }),
             "php" => Some(Self {
                 single_line_comments: vec!["//".into(), "#".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["function ".into(), "public function ".into(), "private function ".into()],
                 class_keywords: vec!["class ".into(), "interface ".into(), "trait ".into(), "abstract ".into()],
                 import_keywords: vec!["require".into(), "include".into(), "use ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try ".into(), "catch ".into()],
                 test_keywords: vec!["function test".into(), "PHPUnit".into()],
                 doc_keywords: vec!["/**".into(), "//".into(), "*".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::multiline("'", "'")],
             }),
             // New languages added for better coverage
-            "json" => Some(Self {
+            "json" | "ipynb" => Some(Self {
                 single_line_comments: vec![],
                 multi_line_comments: vec![],
                 function_keywords: vec![],
@@ -362,6 +773,7 @@ impl LanguageConfig {
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec![],
+                string_literals: vec![StringLiteral::escaped("\"")],
             }),
             "yaml" | "yml" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -372,26 +784,29 @@ impl LanguageConfig {
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec!["#".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::raw("'", "'", false)],
             }),
-            "xml" | "html" | "htm" => Some(Self {
+            "xml" | "html" | "htm" | "qt-ts" | "vue" | "svelte" => Some(Self {
                 single_line_comments: vec![],
-                multi_line_comments: vec![("<!--".into(), "-->".into())],
+                multi_line_comments: vec![BlockMarker::flat("<!--", "-->")],
                 function_keywords: vec![],
                 class_keywords: vec![],
                 import_keywords: vec![],
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec!["<!--".into()],
+                string_literals: vec![],
             }),
-            "css" | "scss" | "sass" => Some(Self {
+            "css" | "scss" | "sass" | "less" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec![],
                 class_keywords: vec![".".into(), "#".into()],
                 import_keywords: vec!["@import".into(), "@use".into()],
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec!["/*".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::escaped("'")],
             }),
             "sh" | "bash" | "zsh" | "fish" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -402,16 +817,18 @@ impl LanguageConfig {
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "case ".into(), "elif ".into()],
                 test_keywords: vec!["test ".into(), "[ ".into()],
                 doc_keywords: vec!["#".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::raw("'", "'", true)],
             }),
             "sql" => Some(Self {
                 single_line_comments: vec!["--".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["CREATE FUNCTION".into(), "CREATE PROCEDURE".into()],
                 class_keywords: vec!["CREATE TABLE".into(), "CREATE VIEW".into()],
                 import_keywords: vec![],
                 complexity_keywords: vec!["IF ".into(), "WHILE ".into(), "CASE ".into()],
                 test_keywords: vec![],
                 doc_keywords: vec!["--".into(), "/*".into()],
+                string_literals: vec![StringLiteral::raw("'", "'", true), StringLiteral::raw("\"", "\"", true)],
             }),
             "r" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -422,76 +839,93 @@ impl LanguageConfig {
                 complexity_keywords: vec!["if(".into(), "while(".into(), "for(".into()],
                 test_keywords: vec!["test_that(".into(), "expect_".into()],
                 doc_keywords: vec!["#'".into(), "#".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::multiline("'", "'")],
             }),
             "rb" => Some(Self {
                 single_line_comments: vec!["#".into()],
-                multi_line_comments: vec![("=begin".into(), "=end".into())],
+                multi_line_comments: vec![BlockMarker::flat("=begin", "=end")],
                 function_keywords: vec!["def ".into()],
                 class_keywords: vec!["class ".into(), "module ".into()],
                 import_keywords: vec!["require ".into(), "load ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "case ".into(), "elsif ".into()],
                 test_keywords: vec!["describe ".into(), "it ".into(), "test_".into()],
                 doc_keywords: vec!["#".into(), "=begin".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::multiline("'", "'")],
             }),
             "swift" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::nesting("/*", "*/")],
                 function_keywords: vec!["func ".into()],
                 class_keywords: vec!["class ".into(), "struct ".into(), "enum ".into(), "protocol ".into()],
                 import_keywords: vec!["import ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "else if ".into()],
                 test_keywords: vec!["func test".into(), "XCTest".into()],
                 doc_keywords: vec!["///".into(), "/**".into()],
+                string_literals: vec![StringLiteral::multiline("\"\"\"", "\"\"\""), StringLiteral::escaped("\"")],
             }),
             "dart" => Some(Self {
                 single_line_comments: vec!["//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec!["void ".into(), "int ".into(), "String ".into(), "double ".into()],
                 class_keywords: vec!["class ".into(), "abstract class ".into(), "mixin ".into()],
                 import_keywords: vec!["import ".into(), "part ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "else if ".into()],
                 test_keywords: vec!["test(".into(), "group(".into()],
                 doc_keywords: vec!["///".into(), "/**".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::multiline("'''", "'''"),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::escaped("'"),
+                ],
             }),
             "lua" => Some(Self {
                 single_line_comments: vec!["--".into()],
-                multi_line_comments: vec![("--[[".into(), "]]".into())],
+                multi_line_comments: vec![BlockMarker::flat("--[[", "]]")],
                 function_keywords: vec!["function ".into(), "local function ".into()],
                 class_keywords: vec![],
                 import_keywords: vec!["require(".into(), "dofile(".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "elseif ".into()],
                 test_keywords: vec![],
                 doc_keywords: vec!["--".into(), "--[[".into()],
+                string_literals: vec![
+                    StringLiteral::raw("[[", "]]", true),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::escaped("'"),
+                ],
             }),
             "perl" | "pl" => Some(Self {
                 single_line_comments: vec!["#".into()],
-                multi_line_comments: vec![("=pod".into(), "=cut".into())],
+                multi_line_comments: vec![BlockMarker::flat("=pod", "=cut")],
                 function_keywords: vec!["sub ".into()],
                 class_keywords: vec!["package ".into()],
                 import_keywords: vec!["use ".into(), "require ".into()],
                 complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "elsif ".into()],
                 test_keywords: vec!["ok(".into(), "is(".into()],
                 doc_keywords: vec!["#".into(), "=pod".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::multiline("'", "'")],
             }),
             "asm" | "s" => Some(Self {
                 single_line_comments: vec![";".into(), "#".into(), "//".into()],
-                multi_line_comments: vec![("/*".into(), "*/".into())],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
                 function_keywords: vec![".globl".into(), ".global".into()],
                 class_keywords: vec![".section".into(), ".data".into(), ".text".into()],
                 import_keywords: vec![".include".into()],
                 complexity_keywords: vec!["jmp".into(), "je".into(), "jne".into(), "call".into()],
                 test_keywords: vec![],
                 doc_keywords: vec![";".into(), "//".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::char_literal()],
             }),
             "md" | "markdown" => Some(Self {
                 single_line_comments: vec![],
-                multi_line_comments: vec![("<!--".into(), "-->".into())],
+                multi_line_comments: vec![BlockMarker::flat("<!--", "-->")],
                 function_keywords: vec![],
                 class_keywords: vec![],
                 import_keywords: vec![],
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec!["#".into(), "<!--".into()],
+                string_literals: vec![],
             }),
             "toml" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -502,6 +936,12 @@ impl LanguageConfig {
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec!["#".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::raw("'''", "'''", true),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::raw("'", "'", false),
+                ],
             }),
             "ini" | "cfg" | "conf" => Some(Self {
                 single_line_comments: vec![";".into(), "#".into()],
@@ -512,6 +952,34 @@ impl LanguageConfig {
                 complexity_keywords: vec![],
                 test_keywords: vec![],
                 doc_keywords: vec![";".into(), "#".into()],
+                string_literals: vec![StringLiteral::escaped("\"")],
+            }),
+            "groovy" | "gradle" => Some(Self {
+                single_line_comments: vec!["//".into()],
+                multi_line_comments: vec![BlockMarker::flat("/*", "*/")],
+                function_keywords: vec!["def ".into(), "void ".into(), "public ".into(), "private ".into()],
+                class_keywords: vec!["class ".into(), "interface ".into(), "enum ".into(), "trait ".into()],
+                import_keywords: vec!["import ".into(), "package ".into(), "@Library".into()],
+                complexity_keywords: vec!["if ".into(), "while ".into(), "for ".into(), "switch ".into(), "try ".into(), "catch ".into(), "else if ".into()],
+                test_keywords: vec!["@Test".into(), "Spock".into(), "given:".into()],
+                doc_keywords: vec!["/**".into(), "//".into()],
+                string_literals: vec![
+                    StringLiteral::multiline("\"\"\"", "\"\"\""),
+                    StringLiteral::multiline("'''", "'''"),
+                    StringLiteral::escaped("\""),
+                    StringLiteral::escaped("'"),
+                ],
+            }),
+            "cmake" => Some(Self {
+                single_line_comments: vec!["#".into()],
+                multi_line_comments: vec![BlockMarker::flat("#[[", "]]")],
+                function_keywords: vec!["function(".into(), "macro(".into()],
+                class_keywords: vec![],
+                import_keywords: vec!["include(".into(), "find_package(".into(), "add_subdirectory(".into()],
+                complexity_keywords: vec!["if(".into(), "elseif(".into(), "foreach(".into(), "while(".into()],
+                test_keywords: vec!["add_test(".into(), "enable_testing(".into()],
+                doc_keywords: vec!["#".into()],
+                string_literals: vec![StringLiteral::multiline("\"", "\""), StringLiteral::raw("[[", "]]", true)],
             }),
             "dockerfile" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -522,6 +990,7 @@ impl LanguageConfig {
                 complexity_keywords: vec!["IF".into(), "ONBUILD".into()],
                 test_keywords: vec![],
                 doc_keywords: vec!["#".into()],
+                string_literals: vec![StringLiteral::escaped("\""), StringLiteral::raw("'", "'", false)],
             }),
             "make" | "makefile" => Some(Self {
                 single_line_comments: vec!["#".into()],
@@ -532,6 +1001,7 @@ impl LanguageConfig {
                 complexity_keywords: vec!["ifeq".into(), "ifneq".into(), "ifdef".into(), "ifndef".into()],
                 test_keywords: vec![],
                 doc_keywords: vec!["#".into()],
+                string_literals: vec![],
             }),
             _ => None,
         }
@@ -541,18 +1011,199 @@ impl LanguageConfig {
     fn get_simple_config() -> Self {
         Self {
             single_line_comments: vec!["#".into(), "//".into(), ";".into(), "--".into()],
-            multi_line_comments: vec![("/*".into(), "*/".into()), ("<!--".into(), "-->".into())],
+            multi_line_comments: vec![BlockMarker::flat("/*", "*/"), BlockMarker::flat("<!--", "-->")],
             function_keywords: vec!["function".into(), "def".into(), "fn".into()],
             class_keywords: vec!["class".into(), "struct".into(), "type".into()],
             import_keywords: vec!["import".into(), "include".into(), "use".into(), "require".into()],
             complexity_keywords: vec!["if".into(), "while".into(), "for".into(), "switch".into(), "case".into()],
             test_keywords: vec!["test".into(), "spec".into(), "assert".into()],
             doc_keywords: vec!["#".into(), "//".into(), "/*".into()],
+            string_literals: vec![StringLiteral::escaped("\"")],
+        }
+    }
+}
+
+// How a language spells string/char literals, so comment markers inside them are ignored
+#[derive(Clone, Hash)]
+struct StringLiteral {
+    start: String,
+    end: String,
+    escape: Option<char>,
+    multiline: bool,
+    // Char literals ('x', '\n') must close right away; otherwise the quote is code (e.g. Rust lifetimes)
+    char_like: bool,
+}
+
+impl StringLiteral {
+    fn escaped(delimiter: &str) -> Self {
+        Self { start: delimiter.into(), end: delimiter.into(), escape: Some('\\'), multiline: false, char_like: false }
+    }
+
+    fn multiline(start: &str, end: &str) -> Self {
+        Self { start: start.into(), end: end.into(), escape: Some('\\'), multiline: true, char_like: false }
+    }
+
+    fn raw(start: &str, end: &str, multiline: bool) -> Self {
+        Self { start: start.into(), end: end.into(), escape: None, multiline, char_like: false }
+    }
+
+    fn char_literal() -> Self {
+        Self { start: "'".into(), end: "'".into(), escape: Some('\\'), multiline: false, char_like: true }
+    }
+
+    // Does a char literal close within `rest` (the text after the opening quote)?
+    fn closes_as_char(&self, rest: &str) -> bool {
+        match rest.chars().next() {
+            Some(c) if Some(c) == self.escape => {
+                // Escape sequences such as \n, \x7f or \u{1F600}
+                rest.char_indices().skip(2).take(10)
+                    .find(|&(i, _)| rest[i..].starts_with(self.end.as_str()))
+                    .is_some_and(|(i, _)| !rest[..i].contains(char::is_whitespace))
+            },
+            Some(c) => rest[c.len_utf8()..].starts_with(self.end.as_str()),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum ScanState {
+    Code,
+    BlockComment { index: usize, depth: usize },
+    Literal { index: usize },
+}
+
+// Result of scanning one line: whether it holds code and/or comments, plus the code text
+// with comments removed and string contents blanked out (for keyword analysis)
+struct LineScan {
+    has_code: bool,
+    has_comment: bool,
+    code: String,
+}
+
+// Per-file state machine that classifies lines while tracking comments and string literals
+// across line boundaries
+struct CommentScanner<'a> {
+    config: &'a LanguageConfig,
+    state: ScanState,
+}
+
+impl<'a> CommentScanner<'a> {
+    fn new(config: &'a LanguageConfig) -> Self {
+        Self { config, state: ScanState::Code }
+    }
+
+    fn literal_start(&self, line: &str, pos: usize) -> Option<usize> {
+        let rest = &line[pos..];
+        self.config.string_literals.iter().position(|literal| {
+            if !rest.starts_with(literal.start.as_str()) {
+                return false;
+            }
+            // Prefixed literals (r"..", b"..") must not be the tail of an identifier
+            if literal.start.starts_with(|c: char| c.is_alphabetic()) {
+                let previous = line[..pos].chars().next_back();
+                if previous.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    return false;
+                }
+            }
+            !literal.char_like || literal.closes_as_char(&rest[literal.start.len()..])
+        })
+    }
+
+    fn scan_line(&mut self, line: &str) -> LineScan {
+        let mut scan = LineScan { has_code: false, has_comment: false, code: String::new() };
+        let mut pos = 0;
+
+        while pos < line.len() {
+            let rest = &line[pos..];
+            let current = rest.chars().next().unwrap_or(' ');
+
+            match self.state.clone() {
+                ScanState::BlockComment { index, depth } => {
+                    let marker = &self.config.multi_line_comments[index];
+                    if rest.starts_with(marker.end.as_str()) {
+                        self.state = if depth > 1 {
+                            ScanState::BlockComment { index, depth: depth - 1 }
+                        } else {
+                            ScanState::Code
+                        };
+                        pos += marker.end.len();
+                    } else if marker.nests && rest.starts_with(marker.start.as_str()) {
+                        self.state = ScanState::BlockComment { index, depth: depth + 1 };
+                        pos += marker.start.len();
+                    } else {
+                        pos += current.len_utf8();
+                    }
+                    scan.has_comment = true;
+                },
+                ScanState::Literal { index } => {
+                    let literal = &self.config.string_literals[index];
+                    scan.has_code = true;
+                    if Some(current) == literal.escape {
+                        pos += current.len_utf8();
+                        if let Some(escaped) = line[pos..].chars().next() {
+                            pos += escaped.len_utf8();
+                        }
+                    } else if rest.starts_with(literal.end.as_str()) {
+                        scan.code.push_str(&literal.end);
+                        self.state = ScanState::Code;
+                        pos += literal.end.len();
+                    } else {
+                        pos += current.len_utf8();
+                    }
+                },
+                ScanState::Code => {
+                    let code_before = !scan.code.trim().is_empty();
+
+                    if let Some(index) = self.config.multi_line_comments.iter()
+                        .position(|marker| rest.starts_with(marker.start.as_str()))
+                    {
+                        // Python-style docstrings: a block marker that is also a string opener
+                        // is only a comment when it starts the statement
+                        let is_string = code_before && self.literal_start(line, pos).is_some();
+                        if !is_string {
+                            self.state = ScanState::BlockComment { index, depth: 1 };
+                            scan.has_comment = true;
+                            pos += self.config.multi_line_comments[index].start.len();
+                            continue;
+                        }
+                    }
+
+                    if self.config.single_line_comments.iter().any(|marker| rest.starts_with(marker.as_str())) {
+                        scan.has_comment = true;
+                        break;
+                    }
+
+                    if let Some(index) = self.literal_start(line, pos) {
+                        let literal = &self.config.string_literals[index];
+                        scan.code.push_str(&literal.start);
+                        scan.has_code = true;
+                        self.state = ScanState::Literal { index };
+                        pos += literal.start.len();
+                        continue;
+                    }
+
+                    if !current.is_whitespace() {
+                        scan.has_code = true;
+                    }
+                    scan.code.push(current);
+                    pos += current.len_utf8();
+                },
+            }
+        }
+
+        // Unterminated single-line literals end with the line
+        if let ScanState::Literal { index } = self.state {
+            if !self.config.string_literals[index].multiline {
+                self.state = ScanState::Code;
+            }
         }
+
+        scan
     }
 }
 
-fn get_language_name(extension: &str) -> String {
+fn builtin_language_name(extension: &str) -> String {
     match extension.to_lowercase().as_str() {
         "rs" => "Rust 🦀".to_string(),
         "py" | "pyw" | "pyi" => "Python 🐍".to_string(),
@@ -561,8 +1212,8 @@ fn get_language_name(extension: &str) -> String {
         "jsx" => "React JSX ⚛️".to_string(),
         "tsx" => "React TypeScript ⚛️".to_string(),
         "java" => "Java ☕".to_string(),
-        "kt" => "Kotlin 🟪".to_string(),
-        "scala" => "Scala 🔴".to_string(),
+        "kt" | "kts" => "Kotlin 🟪".to_string(),
+        "scala" | "sc" => "Scala 🔴".to_string(),
         "c" => "C 🔧".to_string(),
         "cpp" | "cc" | "cxx" | "c++" => "C++ ⚡".to_string(),
         "h" => "C Header 📋".to_string(),
@@ -571,12 +1222,20 @@ fn get_language_name(extension: &str) -> String {
         "php" => "PHP 🐘".to_string(),
         "rb" => "Ruby 💎".to_string(),
         "swift" => "Swift 🦉".to_string(),
+        "hs" => "Haskell λ".to_string(),
+        "ml" | "mli" => "OCaml 🐫".to_string(),
+        "d" => "D 🔶".to_string(),
         "dart" => "Dart 🎯".to_string(),
         "lua" => "Lua 🌙".to_string(),
         "perl" | "pl" => "Perl 🐪".to_string(),
         "html" | "htm" => "HTML 🌐".to_string(),
-        "css" | "scss" | "sass" => "CSS 🎨".to_string(),
+        "css" => "CSS 🎨".to_string(),
+        "scss" | "sass" => "SCSS 🎨".to_string(),
+        "less" => "Less 🎨".to_string(),
+        "vue" => "Vue 💚".to_string(),
+        "svelte" => "Svelte 🔥".to_string(),
         "json" => "JSON 📊".to_string(),
+        "ipynb" => "Jupyter Notebook 📓".to_string(),
         "yaml" | "yml" => "YAML 📝".to_string(),
         "toml" => "TOML ⚙️".to_string(),
         "xml" => "XML 📄".to_string(),
@@ -584,102 +1243,811 @@ fn get_language_name(extension: &str) -> String {
         "sh" | "bash" | "zsh" | "fish" => "Shell 🐚".to_string(),
         "sql" => "SQL 🗃️".to_string(),
         "r" => "R 📈".to_string(),
-        "m" => "MATLAB 🧮".to_string(),
+        "m" | "matlab" => "MATLAB 🧮".to_string(),
+        "objc" | "mm" => "Objective-C 🍏".to_string(),
+        "prolog" => "Prolog 🧠".to_string(),
+        "verilog" | "v" | "sv" | "svh" => "Verilog 🔌".to_string(),
+        "coq" => "Coq 🐓".to_string(),
+        "qt-ts" => "Qt Translation 🌐".to_string(),
         "asm" | "s" => "Assembly ⚙️".to_string(),
         "dockerfile" => "Dockerfile 🐳".to_string(),
+        "groovy" | "gradle" => "Groovy ⭐".to_string(),
+        "cmake" => "CMake 🏗️".to_string(),
         "make" | "makefile" => "Makefile 🔨".to_string(),
         "ini" | "cfg" | "conf" => "Config 📋".to_string(),
         _ => format!("Unknown ({})", extension),
     }
 }
 
-fn detect_encoding_optimized(file_path: &Path) -> String {
-    match fs::read(file_path) {
-        Ok(bytes) => {
-            if bytes.is_empty() {
-                return "Empty".to_string();
+// Project-level language definitions picked up from the scan root, in order of preference
+const LANGUAGE_CONFIG_FILES: &[&str] = &[".loco-languages.toml", ".loco-languages.json"];
+
+// One `[languages.<key>]` entry. Omitted fields keep the value of the language being overridden
+// (`<key>` itself when built in, or `extends`), so an entry can patch a single keyword list.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LanguageDefinition {
+    name: Option<String>,
+    extends: Option<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    #[serde(default)]
+    filenames: Vec<String>,
+    line_comments: Option<Vec<String>>,
+    block_comments: Option<Vec<(String, String)>>,
+    // Applies to the `block_comments` given alongside it, or to the inherited ones
+    nested_comments: Option<bool>,
+    // Backslash-escaped, single-line string delimiters such as "\"" or "'"
+    strings: Option<Vec<String>>,
+    function_keywords: Option<Vec<String>>,
+    class_keywords: Option<Vec<String>>,
+    import_keywords: Option<Vec<String>>,
+    complexity_keywords: Option<Vec<String>>,
+    test_keywords: Option<Vec<String>>,
+    doc_keywords: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LanguageDefinitionFile {
+    languages: std::collections::BTreeMap<String, LanguageDefinition>,
+}
+
+// Languages from --languages and the project config, consulted before the built-in table. Each
+// analysis loads its own, so the two sides of `loco diff` can define languages differently.
+#[derive(Default)]
+struct CustomLanguages {
+    configs: HashMap<String, LanguageConfig>,
+    names: HashMap<String, String>,
+    extensions: HashMap<String, String>,
+    filenames: HashMap<String, String>,
+}
+
+impl CustomLanguages {
+    fn config(&self, key: &str) -> Option<LanguageConfig> {
+        let key = key.to_lowercase();
+        self.configs.get(&key).cloned().or_else(|| LanguageConfig::builtin(&key))
+    }
+
+    fn name(&self, key: &str) -> String {
+        let key = key.to_lowercase();
+        self.names.get(&key).cloned().unwrap_or_else(|| builtin_language_name(&key))
+    }
+
+    // One hash over every definition, for results that can involve any language
+    fn fingerprint(&self) -> u64 {
+        let sorted = |map: &HashMap<String, String>| {
+            let mut entries: Vec<(String, String)> = map.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            entries.sort();
+            entries
+        };
+        let mut configs: Vec<(&String, &LanguageConfig)> = self.configs.iter().collect();
+        configs.sort_by(|a, b| a.0.cmp(b.0));
+        hash_of(&(configs, sorted(&self.names), sorted(&self.extensions), sorted(&self.filenames)))
+    }
+
+    // Later files override earlier ones, so --languages wins over the project config
+    fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let file: LanguageDefinitionFile = if is_json {
+            serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?
+        } else {
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+
+        // Within one file an extension or filename may only be claimed once
+        let mut claimed: HashMap<String, String> = HashMap::new();
+        let count = file.languages.len();
+        for (key, definition) in file.languages {
+            let key = key.trim().to_lowercase();
+            for claim in definition.extensions.iter().map(|e| format!("extension '{}'", e.trim_start_matches('.').to_lowercase()))
+                .chain(definition.filenames.iter().map(|f| format!("filename '{}'", f)))
+            {
+                if let Some(other) = claimed.insert(claim.clone(), key.clone()) {
+                    if other != key {
+                        return Err(format!("{}: {} is claimed by both '{}' and '{}'", path.display(), claim, other, key));
+                    }
+                }
             }
+            self.define(&key, definition)
+                .map_err(|e| format!("{}: language '{}': {}", path.display(), key, e))?;
+        }
+        Ok(count)
+    }
+
+    fn define(&mut self, key: &str, definition: LanguageDefinition) -> Result<(), String> {
+        if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err("language keys must be non-empty and contain no whitespace or '/'".into());
+        }
+
+        let base_key = definition.extends.as_deref().map(|k| k.trim().to_lowercase());
+        let base = match &base_key {
+            Some(base_key) => Some(self.configs.get(base_key).cloned()
+                .or_else(|| LanguageConfig::builtin(base_key))
+                .ok_or_else(|| format!("`extends` names unknown language '{}'", base_key))?),
+            None => self.configs.get(key).cloned().or_else(|| LanguageConfig::builtin(key)),
+        };
+        let is_new = base.is_none();
+        if is_new && definition.extensions.is_empty() && definition.filenames.is_empty() {
+            return Err("a new language needs at least one entry in `extensions` or `filenames`".into());
+        }
 
-            // Check BOM first (most efficient)
-            if bytes.len() >= 3 && &bytes[0..3] == b"\xEF\xBB\xBF" {
-                return "UTF-8 BOM".to_string();
+        let non_empty = |field: &str, values: &[String]| -> Result<(), String> {
+            match values.iter().any(|value| value.trim().is_empty()) {
+                true => Err(format!("`{}` must not contain empty strings", field)),
+                false => Ok(()),
             }
-            if bytes.len() >= 2 {
-                if &bytes[0..2] == b"\xFF\xFE" {
-                    return "UTF-16 LE".to_string();
-                }
-                if &bytes[0..2] == b"\xFE\xFF" {
-                    return "UTF-16 BE".to_string();
+        };
+
+        let mut config = base.unwrap_or(LanguageConfig {
+            single_line_comments: vec![],
+            multi_line_comments: vec![],
+            function_keywords: vec![],
+            class_keywords: vec![],
+            import_keywords: vec![],
+            complexity_keywords: vec![],
+            test_keywords: vec![],
+            doc_keywords: vec![],
+            string_literals: vec![],
+        });
+
+        if let Some(markers) = definition.line_comments {
+            non_empty("line_comments", &markers)?;
+            config.single_line_comments = markers;
+        }
+        if let Some(pairs) = definition.block_comments {
+            if pairs.iter().any(|(start, end)| start.trim().is_empty() || end.trim().is_empty()) {
+                return Err("`block_comments` entries must be [start, end] pairs of non-empty markers".into());
+            }
+            let nests = definition.nested_comments.unwrap_or(false);
+            config.multi_line_comments = pairs.iter()
+                .map(|(start, end)| BlockMarker { start: start.clone(), end: end.clone(), nests })
+                .collect();
+        } else if let Some(nests) = definition.nested_comments {
+            config.multi_line_comments.iter_mut().for_each(|marker| marker.nests = nests);
+        }
+        if let Some(delimiters) = definition.strings {
+            non_empty("strings", &delimiters)?;
+            config.string_literals = delimiters.iter().map(|d| StringLiteral::escaped(d)).collect();
+        }
+        let keyword_lists = [
+            ("function_keywords", definition.function_keywords, &mut config.function_keywords),
+            ("class_keywords", definition.class_keywords, &mut config.class_keywords),
+            ("import_keywords", definition.import_keywords, &mut config.import_keywords),
+            ("complexity_keywords", definition.complexity_keywords, &mut config.complexity_keywords),
+            ("test_keywords", definition.test_keywords, &mut config.test_keywords),
+            ("doc_keywords", definition.doc_keywords, &mut config.doc_keywords),
+        ];
+        for (field, keywords, target) in keyword_lists {
+            if let Some(keywords) = keywords {
+                // Keywords are matched as substrings, so trailing spaces (`"fn "`) are meaningful
+                if keywords.iter().any(|keyword| keyword.is_empty()) {
+                    return Err(format!("`{}` must not contain empty strings", field));
                 }
+                *target = keywords;
+            }
+        }
+
+        for extension in &definition.extensions {
+            let extension = extension.trim().trim_start_matches('.').to_lowercase();
+            if extension.is_empty() || extension.contains('/') {
+                return Err(format!("invalid extension '{}'", extension));
             }
+            self.extensions.insert(extension, key.to_string());
+        }
+        for filename in &definition.filenames {
+            if filename.trim().is_empty() || filename.contains('/') {
+                return Err(format!("invalid filename '{}' (use a bare file name such as \"BUILD\")", filename));
+            }
+            self.filenames.insert(filename.clone(), key.to_string());
+        }
+
+        match definition.name {
+            Some(name) if name.trim().is_empty() => return Err("`name` must not be empty".into()),
+            Some(name) => { self.names.insert(key.to_string(), name); },
+            // New languages (including `extends` ones) are reported under their own key
+            None if is_new || base_key.is_some() => {
+                self.names.entry(key.to_string()).or_insert_with(|| key.to_string());
+            },
+            None => {},
+        }
+
+        self.configs.insert(key.to_string(), config);
+        Ok(())
+    }
+}
 
-            // Smaller sample for faster analysis
-            let sample_size = std::cmp::min(512, bytes.len());
-            let sample = &bytes[0..sample_size];
+// Maps interpreter names (from shebangs) and editor mode names (from modelines) to language keys
+fn language_key_for_name(name: &str) -> Option<&'static str> {
+    let key = match name.to_lowercase().as_str() {
+        "python" | "python2" | "python3" | "pypy" | "pypy3" => "py",
+        "sh" | "bash" | "zsh" | "ksh" | "dash" | "ash" | "fish" | "shell" | "shell-script" => "sh",
+        "node" | "nodejs" | "javascript" | "js" | "deno" | "bun" => "js",
+        "typescript" | "ts" | "ts-node" | "tsx" => "ts",
+        "ruby" | "jruby" | "rb" => "rb",
+        "perl" | "perl5" | "cperl" => "pl",
+        "php" => "php",
+        "lua" | "luajit" => "lua",
+        "rscript" | "r" => "r",
+        "rust" | "rs" => "rs",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "go" | "golang" => "go",
+        "java" => "java",
+        "kotlin" => "kt",
+        "scala" => "scala",
+        "swift" => "swift",
+        "dart" => "dart",
+        "haskell" | "runghc" | "runhaskell" => "hs",
+        "ocaml" | "tuareg" => "ml",
+        "groovy" => "groovy",
+        "make" | "makefile" | "gmake" => "makefile",
+        "cmake" => "cmake",
+        "dockerfile" => "dockerfile",
+        "yaml" => "yaml",
+        "json" => "json",
+        "toml" | "conf-toml" => "toml",
+        "ini" | "dosini" | "conf" => "ini",
+        "sql" => "sql",
+        "html" => "html",
+        "xml" | "nxml" => "xml",
+        "css" => "css",
+        "scss" => "scss",
+        "sass" => "sass",
+        "less" => "less",
+        "vue" => "vue",
+        "svelte" => "svelte",
+        "jsx" => "jsx",
+        "markdown" | "md" => "md",
+        "asm" | "nasm" | "assembly" => "asm",
+        "objective-c" | "objc" => "objc",
+        "objective-c++" => "mm",
+        "matlab" => "matlab",
+        "prolog" => "prolog",
+        "verilog" | "systemverilog" => "verilog",
+        "coq" => "coq",
+        "jupyter-notebook" | "ipynb" => "ipynb",
+        _ => return None,
+    };
+    Some(key)
+}
 
-            let ascii_count = sample.iter().filter(|&&b| b.is_ascii()).count();
-            let ascii_ratio = ascii_count as f64 / sample.len() as f64;
+// Language key for a GitHub linguist language name (`linguist-language=Objective-C`); also
+// accepts custom language keys and names, and loco's own keys
+fn language_key_for_linguist(name: &str, custom: &CustomLanguages) -> Option<String> {
+    let lower = name.to_lowercase();
+    if custom.configs.contains_key(&lower) {
+        return Some(lower);
+    }
+    if let Some((key, _)) = custom.names.iter().find(|(_, custom_name)| custom_name.eq_ignore_ascii_case(name)) {
+        return Some(key.clone());
+    }
+    if let Some(key) = language_key_for_name(name) {
+        return Some(key.to_string());
+    }
+    custom.config(&lower).map(|_| lower)
+}
 
-            if ascii_ratio == 1.0 {
-                "ASCII".to_string()
-            } else if std::str::from_utf8(sample).is_ok() {
-                "UTF-8".to_string()
+// Well-known files identified by their exact name
+fn language_from_name(file_path: &Path, custom: &CustomLanguages) -> Option<String> {
+    let file_name = file_path.file_name()?.to_str()?;
+    if let Some(key) = custom.filenames.get(file_name) {
+        return Some(key.clone());
+    }
+    let lower = file_name.to_lowercase();
+
+    let key = match file_name {
+        "Dockerfile" | "Containerfile" => "dockerfile",
+        "Makefile" | "makefile" | "GNUmakefile" => "makefile",
+        "Jenkinsfile" => "groovy",
+        "CMakeLists.txt" => "cmake",
+        "Gemfile" | "Rakefile" | "Podfile" | "Vagrantfile" | "Guardfile" | "Capfile" | "Brewfile" | "Fastfile" => "rb",
+        ".bashrc" | ".bash_profile" | ".bash_login" | ".bash_logout" | ".bash_aliases" | ".profile"
+        | ".zshrc" | ".zshenv" | ".zprofile" | ".zlogin" | ".kshrc" | "PKGBUILD" => "sh",
+        "BUILD" | "BUILD.bazel" | "WORKSPACE" | "WORKSPACE.bazel" | "Tiltfile" | "SConstruct" | "SConscript" => "py",
+        "Pipfile" | "Cargo.lock" | "poetry.lock" => "toml",
+        _ => {
+            // Filename patterns: Dockerfile.dev, app.Dockerfile, Makefile.inc, rules.mk, ...
+            if lower.starts_with("dockerfile.") || lower.ends_with(".dockerfile") {
+                "dockerfile"
+            } else if lower.starts_with("makefile.") || lower.ends_with(".mk") || lower.ends_with(".mak") {
+                "makefile"
+            } else if lower.starts_with("jenkinsfile.") || lower.ends_with(".jenkinsfile") {
+                "groovy"
+            } else if lower.ends_with(".cmake") || lower.ends_with(".cmake.in") {
+                "cmake"
+            } else if lower.ends_with(".gemspec") || lower.ends_with(".podspec") || lower.ends_with(".rake") {
+                "rb"
+            } else if lower.ends_with(".bashrc") || lower.ends_with(".zshrc") {
+                "sh"
             } else {
-                "Binary".to_string()
+                return None;
             }
         },
-        Err(_) => "Unreadable".to_string(),
+    };
+
+    Some(key.to_string())
+}
+
+fn language_from_shebang(first_line: &str) -> Option<&'static str> {
+    let interpreter_line = first_line.strip_prefix("#!")?.trim();
+    let mut words = interpreter_line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+
+    // `#!/usr/bin/env [-S] [VAR=value] python3 -u`
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
     }
+
+    // python3.11 -> python3 -> python
+    let base = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    language_key_for_name(program).or_else(|| language_key_for_name(base))
 }
 
-fn get_file_times(file_path: &Path) -> (Option<u64>, Option<u64>) {
-    fs::metadata(file_path).ok().map_or((None, None), |metadata| {
-        let created = metadata.created().ok()
-            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
-            .map(|d| d.as_secs());
+fn language_from_modeline(line: &str) -> Option<&'static str> {
+    static VIM: OnceLock<Regex> = OnceLock::new();
+    static EMACS: OnceLock<Regex> = OnceLock::new();
+    let vim = VIM.get_or_init(|| {
+        Regex::new(r"(?:^|\s)(?:vi|vim|ex)(?:[<=>]?\d+)?:.*?\b(?:ft|filetype|syntax)=([\w+-]+)").unwrap()
+    });
+    let emacs = EMACS.get_or_init(|| {
+        Regex::new(r"-\*-\s*(?:.*?\bmode:\s*([\w+-]+)|([\w+-]+))\s*(?:;.*?)?-\*-").unwrap()
+    });
 
-        let modified = metadata.modified().ok()
-            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
-            .map(|d| d.as_secs());
+    if let Some(caps) = vim.captures(line) {
+        return language_key_for_name(&caps[1]);
+    }
+    emacs.captures(line)
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .and_then(|mode| language_key_for_name(mode.as_str()))
+}
 
-        (created, modified)
-    })
+// Shebang on the first line, or a vim/emacs modeline in the first or last five lines
+fn language_from_content(file_path: &Path, source: Source) -> Option<String> {
+    const SAMPLE: u64 = 1024;
+    let size = source.size(file_path).ok()?;
+
+    let head = source.read_range(file_path, 0, SAMPLE).ok()?;
+    let head = String::from_utf8_lossy(&head);
+
+    if let Some(key) = head.lines().next().and_then(language_from_shebang) {
+        return Some(key.to_string());
+    }
+    if let Some(key) = head.lines().take(5).find_map(language_from_modeline) {
+        return Some(key.to_string());
+    }
+
+    if size > SAMPLE {
+        let tail = source.read_range(file_path, size - SAMPLE, SAMPLE).ok()?;
+        let tail = String::from_utf8_lossy(&tail);
+        let last_lines: Vec<&str> = tail.lines().rev().take(5).collect();
+        return last_lines.into_iter().find_map(language_from_modeline).map(str::to_string);
+    }
+    head.lines().rev().take(5).find_map(language_from_modeline).map(str::to_string)
 }
 
-fn get_git_stats(path: &Path) -> Option<GitStats> {
-    let mut current_path = path;
-    let mut git_root = None;
-    
-    // Better git repository detection
-    loop {
-        let git_dir = current_path.join(".git");
-        if git_dir.exists() && (git_dir.is_dir() || git_dir.is_file()) {
-            git_root = Some(current_path);
-            break;
-        }
-        
-        match current_path.parent() {
-            Some(parent) => current_path = parent,
-            None => break,
-        }
+// Extensions shared by several languages; resolved by sampling the file for telltale tokens
+const AMBIGUOUS_EXTENSIONS: &[&str] = &["h", "m", "pl", "ts", "v"];
+
+// The language an ambiguous extension maps to when nothing in the file points elsewhere
+fn ambiguous_default(extension: &str) -> Option<&'static str> {
+    match extension {
+        "h" => Some("h"),
+        "m" => Some("matlab"),
+        "pl" => Some("pl"),
+        "ts" => Some("ts"),
+        "v" => Some("verilog"),
+        _ => None,
     }
-    
-    let git_path = git_root?;
+}
 
-    // Verify this is actually a git repository
-    if let Ok(output) = std::process::Command::new("git")
-        .args(&["rev-parse", "--git-dir"])
-        .current_dir(git_path)
-        .output()
-    {
-        if !output.status.success() {
-            return None;
-        }
-    } else {
+fn disambiguate_extension(file_path: &Path, extension: &str, sample: bool, source: Source, custom: &CustomLanguages) -> Option<(&'static str, String)> {
+    if !AMBIGUOUS_EXTENSIONS.contains(&extension) {
         return None;
     }
+    if !sample {
+        let key = ambiguous_default(extension)?;
+        return Some((key, format!(".{} → {} (default, not sampled in very fast mode)", extension, custom.name(key))));
+    }
 
-    let mut git_stats = GitStats {
+    let sample = source.read_range(file_path, 0, 16 * 1024).ok()?;
+    let sample = String::from_utf8_lossy(&sample);
+
+    let found = |tokens: &[&'static str]| -> Vec<&'static str> {
+        tokens.iter().copied().filter(|token| sample.contains(token)).collect()
+    };
+    let starts_line = |tokens: &[&'static str]| -> Vec<&'static str> {
+        tokens.iter().copied()
+            .filter(|token| sample.lines().any(|line| line.trim_start().starts_with(token)))
+            .collect()
+    };
+    let decide = |key: &'static str, evidence: Vec<&'static str>| {
+        let reason = if evidence.is_empty() {
+            format!(".{} → {} (default, no distinguishing tokens)", extension, custom.name(key))
+        } else {
+            let evidence: Vec<&str> = evidence.iter().map(|token| token.trim()).collect();
+            format!(".{} → {} (found {})", extension, custom.name(key), evidence.join(", "))
+        };
+        Some((key, reason))
+    };
+
+    match extension {
+        "h" => {
+            let objc = found(&["@interface", "@implementation", "@protocol", "@property", "@end", "#import"]);
+            let cpp = found(&[
+                "namespace ", "template<", "template <", "std::", "public:", "private:", "protected:",
+                "virtual ", "constexpr", "nullptr", "class ", "#include <iostream>", "#include <string>",
+                "#include <vector>", "#include <memory>",
+            ]);
+            if !objc.is_empty() && objc.len() >= cpp.len() {
+                decide("objc", objc)
+            } else if !cpp.is_empty() {
+                decide("hpp", cpp)
+            } else {
+                decide("h", vec![])
+            }
+        },
+        "m" => {
+            let objc = found(&["@interface", "@implementation", "@protocol", "@property", "@end", "#import", "#include", "NSString", "alloc] init"]);
+            let matlab = starts_line(&["function ", "%", "end", "classdef ", "disp(", "fprintf(", "plot("]);
+            if !objc.is_empty() && objc.len() >= matlab.len() {
+                decide("objc", objc)
+            } else {
+                decide("matlab", matlab)
+            }
+        },
+        "pl" => {
+            let perl = found(&["use strict", "use warnings", "my $", "my @", "my %", "sub ", "=~", "$_", "#!/usr/bin/perl", "print "]);
+            let mut prolog = starts_line(&[":- module", ":- use_module", ":- dynamic", ":- initialization", ":-"]);
+            if sample.lines().any(|line| line.contains(") :-") || line.trim_end().ends_with(":-")) {
+                prolog.push("head :- body");
+            }
+            if prolog.len() > perl.len() {
+                decide("prolog", prolog)
+            } else {
+                decide("pl", perl)
+            }
+        },
+        "ts" => {
+            let qt = found(&["<!DOCTYPE TS>", "<TS version", "<TS ", "<context>"]);
+            if !qt.is_empty() && sample.trim_start().starts_with('<') {
+                decide("qt-ts", qt)
+            } else {
+                decide("ts", vec![])
+            }
+        },
+        "v" => {
+            let coq = starts_line(&["Theorem ", "Lemma ", "Proof.", "Qed.", "Require ", "From ", "Definition ", "Inductive ", "Fixpoint "]);
+            let verilog = starts_line(&["module ", "endmodule", "always ", "always@", "wire ", "reg ", "assign ", "input ", "output ", "`timescale", "`include"]);
+            if coq.len() > verilog.len() {
+                decide("coq", coq)
+            } else {
+                decide("verilog", verilog)
+            }
+        },
+        _ => None,
+    }
+}
+
+fn language_from_extension(file_path: &Path, sample: bool, source: Source, custom: &CustomLanguages) -> Option<(String, Option<String>)> {
+    let extension = file_path.extension()?.to_str()?.to_lowercase();
+    if let Some(key) = custom.extensions.get(&extension) {
+        return Some((key.clone(), None));
+    }
+    if let Some((key, reason)) = disambiguate_extension(file_path, &extension, sample, source, custom) {
+        return Some((key.to_string(), Some(reason)));
+    }
+    custom.config(&extension).map(|_| (extension, None))
+}
+
+// Detected language key plus, for ambiguous extensions, why that language was picked
+struct LanguageDetection {
+    key: String,
+    reason: Option<String>,
+}
+
+// Language key (as understood by CustomLanguages::config and CustomLanguages::name) for a file:
+// exact filename, filename pattern, then a known extension; only files without one are opened
+// for a shebang or modeline. With `content_first`, ambiguous extensions (.h, .pl, ...) check the
+// shebang/modeline before sampling for telltale tokens; without it (very-fast mode) they take
+// their default language unread.
+// Unknown files fall back to their raw extension so they are reported as "Unknown (ext)".
+fn detect_language_detailed(file_path: &Path, content_first: bool, source: Source, custom: &CustomLanguages) -> LanguageDetection {
+    let by_name = || language_from_name(file_path, custom).map(|key| (key, None));
+    let by_content = || language_from_content(file_path, source).map(|key| (key, None));
+    let by_extension = || language_from_extension(file_path, content_first, source, custom);
+
+    let ambiguous = file_path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AMBIGUOUS_EXTENSIONS.contains(&e.to_lowercase().as_str()));
+
+    let detected = if content_first && ambiguous {
+        by_name().or_else(by_content).or_else(by_extension)
+    } else {
+        by_name().or_else(by_extension).or_else(by_content)
+    };
+
+    match detected {
+        Some((key, reason)) => LanguageDetection { key, reason },
+        None => LanguageDetection {
+            key: file_path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase(),
+            reason: None,
+        },
+    }
+}
+
+fn detect_language(file_path: &Path, content_first: bool, source: Source, custom: &CustomLanguages) -> String {
+    detect_language_detailed(file_path, content_first, source, custom).key
+}
+
+// File contents decoded to UTF-8, with the encoding they were read as
+struct DecodedText {
+    text: String,
+    encoding: String,
+}
+
+// Why a file under the scan root contributed no statistics. Files hidden by ignore files or
+// filtered out by --include are deliberate selections and are not logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SkipReason {
+    TooLarge,
+    Binary,
+    Unreadable,
+    ExcludedByRegex,
+    ExcludedByGlob,
+    DefaultExcluded,
+    UnknownExtension,
+    DecodeError,
+    Missing,
+    OutsideRoots,
+    Generated,
+    Minified,
+    Vendored,
+    Documentation,
+    NotDetectable,
+}
+
+impl SkipReason {
+    fn label(self) -> &'static str {
+        match self {
+            SkipReason::TooLarge => "too large",
+            SkipReason::Binary => "binary",
+            SkipReason::Unreadable => "unreadable",
+            SkipReason::ExcludedByRegex => "excluded by regex",
+            SkipReason::ExcludedByGlob => "excluded by glob",
+            SkipReason::DefaultExcluded => "default-excluded",
+            SkipReason::UnknownExtension => "unknown extension",
+            SkipReason::DecodeError => "decode error",
+            SkipReason::Missing => "not found",
+            SkipReason::OutsideRoots => "outside roots",
+            SkipReason::Generated => "generated",
+            SkipReason::Minified => "minified",
+            SkipReason::Vendored => "vendored",
+            SkipReason::Documentation => "documentation",
+            SkipReason::NotDetectable => "not detectable",
+        }
+    }
+}
+
+impl From<std::io::Error> for SkipReason {
+    fn from(_: std::io::Error) -> Self {
+        SkipReason::Unreadable
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SkippedFile {
+    path: PathBuf,
+    reason: SkipReason,
+}
+
+// Files with a NUL byte this early are binary, the same test git uses; so are files where
+// more than a tenth of this sample is control characters other than whitespace and escape
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+// BOM-marked UTF-8/16/32 first (content that contradicts its BOM is a decode error). Anything
+// else is binary if it contains NUL bytes or mostly control characters, then strict UTF-8, otherwise text in a legacy
+// encoding (Windows-1252, Shift_JIS, GBK, ...) guessed by chardetng and decoded with
+// replacement characters for any stray bytes.
+fn decode_text(bytes: &[u8]) -> Result<DecodedText, SkipReason> {
+    let decoded = |text: String, encoding: &str| Ok(DecodedText { text, encoding: encoding.to_string() });
+    let utf16 = |encoding: &'static encoding_rs::Encoding, rest: &[u8]| {
+        match encoding.decode_without_bom_handling(rest) {
+            (_, true) => Err(SkipReason::DecodeError),
+            (text, false) => Ok(text.into_owned()),
+        }
+    };
+
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE\x00\x00") {
+        return decoded(decode_utf32(rest, false)?, "UTF-32 LE");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\x00\x00\xFE\xFF") {
+        return decoded(decode_utf32(rest, true)?, "UTF-32 BE");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        let text = std::str::from_utf8(rest).map_err(|_| SkipReason::DecodeError)?;
+        return decoded(text.to_string(), "UTF-8 BOM");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        return decoded(utf16(encoding_rs::UTF_16LE, rest)?, "UTF-16 LE");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        return decoded(utf16(encoding_rs::UTF_16BE, rest)?, "UTF-16 BE");
+    }
+    if bytes.is_empty() {
+        return decoded(String::new(), "Empty");
+    }
+
+    let sniff = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    let control_bytes = sniff.iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B))
+        .count();
+    if sniff.contains(&0) || control_bytes * 10 > sniff.len() {
+        return Err(SkipReason::Binary);
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        let encoding = if text.is_ascii() { "ASCII" } else { "UTF-8" };
+        return decoded(text.to_string(), encoding);
+    }
+
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, true);
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    decoded(text.into_owned(), encoding.name())
+}
+
+fn decode_utf32(bytes: &[u8], big_endian: bool) -> Result<String, SkipReason> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(SkipReason::DecodeError);
+    }
+    bytes.chunks_exact(4)
+        .map(|chunk| {
+            let word = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let value = if big_endian { u32::from_be_bytes(word) } else { u32::from_le_bytes(word) };
+            char::from_u32(value).ok_or(SkipReason::DecodeError)
+        })
+        .collect()
+}
+
+fn read_text(file_path: &Path, use_mmap: bool, source: Source) -> Result<DecodedText, SkipReason> {
+    if use_mmap && !source.is_revision() && fs::metadata(file_path)?.len() > 1024 * 1024 {
+        let file = File::open(file_path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        return decode_text(&mmap);
+    }
+    decode_text(&source.read(file_path)?)
+}
+
+// Encodings that need no conversion; everything else was decoded to be counted
+fn is_utf8_encoding(encoding: &str) -> bool {
+    matches!(encoding, "ASCII" | "UTF-8" | "UTF-8 BOM" | "Empty")
+}
+
+fn get_file_times(file_path: &Path, source: Source) -> (Option<u64>, Option<u64>) {
+    // Blobs of a --git-ref revision have no file times
+    if source.is_revision() {
+        return (None, None);
+    }
+    fs::metadata(file_path).ok().map_or((None, None), |metadata| {
+        let created = metadata.created().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        let modified = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        (created, modified)
+    })
+}
+
+// Directory keys a file rolls up into: "." for the root, then each ancestor down to `depth` levels
+fn directory_keys(file_path: &Path, root: &Path, depth: usize) -> Vec<String> {
+    let relative = file_path.strip_prefix(root).unwrap_or(file_path);
+    let mut keys = vec![".".to_string()];
+    let mut current = String::new();
+
+    if let Some(parent) = relative.parent() {
+        for (level, component) in parent.components().enumerate() {
+            if depth > 0 && level >= depth {
+                break;
+            }
+            let name = match component {
+                std::path::Component::Normal(name) => name.to_string_lossy(),
+                _ => continue,
+            };
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(&name);
+            keys.push(current.clone());
+        }
+    }
+
+    keys
+}
+
+fn parent_directory_key(key: &str) -> Option<&str> {
+    if key == "." {
+        None
+    } else {
+        Some(key.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("."))
+    }
+}
+
+// Directories as (depth, key) in tree order, children sorted consistently with --sort-by
+fn directory_tree_order<'a>(stats: &'a ProjectStats, args: &Args) -> Vec<(usize, &'a String)> {
+    let mut children: HashMap<&str, Vec<&String>> = HashMap::new();
+    for key in stats.directory_stats.keys() {
+        if let Some(parent) = parent_directory_key(key) {
+            children.entry(parent).or_default().push(key);
+        }
+    }
+
+    for siblings in children.values_mut() {
+        match args.sort_by.as_str() {
+            "files" => siblings.sort_by_key(|k| std::cmp::Reverse(stats.directory_stats[*k].files)),
+            "size" => siblings.sort_by_key(|k| std::cmp::Reverse(stats.directory_stats[*k].total_size)),
+            "name" => siblings.sort(),
+            _ => siblings.sort_by_key(|k| std::cmp::Reverse(stats.directory_stats[*k].total_lines)),
+        }
+    }
+
+    let mut ordered = Vec::new();
+    let mut stack: Vec<(usize, &String)> = stats.directory_stats.get_key_value(".")
+        .map(|(key, _)| vec![(0, key)])
+        .unwrap_or_default();
+    while let Some((depth, key)) = stack.pop() {
+        ordered.push((depth, key));
+        if let Some(siblings) = children.get(key.as_str()) {
+            for child in siblings.iter().rev() {
+                stack.push((depth + 1, child));
+            }
+        }
+    }
+
+    ordered
+}
+
+fn get_git_stats(path: &Path) -> Option<GitStats> {
+    let mut current_path = path;
+    let mut git_root = None;
+    
+    // Better git repository detection
+    loop {
+        let git_dir = current_path.join(".git");
+        if git_dir.exists() && (git_dir.is_dir() || git_dir.is_file()) {
+            git_root = Some(current_path);
+            break;
+        }
+        
+        match current_path.parent() {
+            Some(parent) => current_path = parent,
+            None => break,
+        }
+    }
+    
+    let git_path = git_root?;
+
+    // Verify this is actually a git repository
+    if let Ok(output) = std::process::Command::new("git")
+        .args(["rev-parse", "--git-dir"])
+        .current_dir(git_path)
+        .output()
+    {
+        if !output.status.success() {
+            return None;
+        }
+    } else {
+        return None;
+    }
+
+    let mut git_stats = GitStats {
         total_commits: 0,
         contributors: 0,
         last_commit_date: None,
@@ -693,7 +2061,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get total commits with better command and timeout
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["rev-list", "--count", "--all"])
+        .args(["rev-list", "--count", "--all"])
         .current_dir(git_path)
         .output()
     {
@@ -710,7 +2078,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
     // Fallback to HEAD count if --all failed
     if git_stats.total_commits == 0 {
         if let Ok(output) = std::process::Command::new("git")
-            .args(&["rev-list", "--count", "HEAD"])
+            .args(["rev-list", "--count", "HEAD"])
             .current_dir(git_path)
             .output()
         {
@@ -724,7 +2092,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get contributors and most active author
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["shortlog", "-sn", "HEAD"])
+        .args(["shortlog", "-sn", "HEAD"])
         .current_dir(git_path)
         .output()
     {
@@ -744,7 +2112,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get last commit date
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["log", "-1", "--format=%cd", "--date=short", "HEAD"])
+        .args(["log", "-1", "--format=%cd", "--date=short", "HEAD"])
         .current_dir(git_path)
         .output()
     {
@@ -760,7 +2128,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get repository age
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["log", "--reverse", "--format=%ct", "-1", "HEAD"])
+        .args(["log", "--reverse", "--format=%ct", "-1", "HEAD"])
         .current_dir(git_path)
         .output()
     {
@@ -786,7 +2154,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get current branch
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["rev-parse", "--abbrev-ref", "HEAD"])
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
         .current_dir(git_path)
         .output()
     {
@@ -802,7 +2170,7 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
 
     // Get lines statistics (limited to avoid timeout)
     if let Ok(output) = std::process::Command::new("git")
-        .args(&["log", "--numstat", "--pretty=format:", "-20"]) // Reduced from 50
+        .args(["log", "--numstat", "--pretty=format:", "-20"]) // Reduced from 50
         .current_dir(git_path)
         .output()
     {
@@ -826,9 +2194,212 @@ fn get_git_stats(path: &Path) -> Option<GitStats> {
     Some(git_stats)
 }
 
-fn analyze_file_very_fast(file_path: &Path, _args: &Args) -> Option<(LanguageStats, FileInfo)> {
-    let metadata = fs::metadata(file_path).ok()?;
-    let file_size = metadata.len();
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileChurn {
+    commits: u64,
+    lines_added: u64,
+    lines_deleted: u64,
+    authors: u64,
+    // commits × cyclomatic complexity: frequently changed, complicated code ranks first
+    score: f64,
+}
+
+// Per-file change counts from `git log --numstat` over the --since window, keyed by absolute
+// path. Renames are not followed, so a moved file starts over.
+fn collect_churn(roots: &[PathBuf], since: &str, end: &str) -> Result<HashMap<PathBuf, FileChurn>, String> {
+    let mut toplevels: Vec<PathBuf> = Vec::new();
+    for root in roots {
+        let dir = if root.is_dir() { root.as_path() } else { root.parent().unwrap_or(Path::new(".")) };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let toplevel = PathBuf::from(String::from_utf8_lossy(&run_git(dir, &["rev-parse", "--show-toplevel"])?).trim());
+        if !toplevels.contains(&toplevel) {
+            toplevels.push(toplevel);
+        }
+    }
+
+    let mut churn: HashMap<PathBuf, FileChurn> = HashMap::new();
+    for toplevel in &toplevels {
+        // -z keeps paths unquoted (git C-quotes non-ASCII names otherwise) and NUL-terminates
+        // every record; the \x01 marker tells the author header apart from numstat records
+        let log = run_git(toplevel, &["log", "-z", "--no-renames", "--numstat", "--format=%x01%aE", &format!("--since={}", since), end, "--"])?;
+        add_numstat_churn(&log, toplevel, &mut churn);
+    }
+    Ok(churn)
+}
+
+// Folds `git log -z --numstat --format=%x01%aE` output into churn for files below `toplevel`
+fn add_numstat_churn(log: &[u8], toplevel: &Path, churn: &mut HashMap<PathBuf, FileChurn>) {
+    let mut authors: HashMap<PathBuf, std::collections::HashSet<String>> = HashMap::new();
+    let mut author = String::new();
+    for record in log.split(|&b| b == 0) {
+        let record = String::from_utf8_lossy(record);
+        let record = record.trim_start_matches('\n');
+        if let Some(email) = record.strip_prefix('\x01') {
+            author = email.to_string();
+            continue;
+        }
+        // <added>\t<deleted>\t<path>; binary files show "-" for both counts
+        let mut fields = record.splitn(3, '\t');
+        let (Some(added), Some(deleted), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let path = toplevel.join(path);
+        let entry = churn.entry(path.clone()).or_default();
+        entry.commits += 1;
+        entry.lines_added += added.parse::<u64>().unwrap_or(0);
+        entry.lines_deleted += deleted.parse::<u64>().unwrap_or(0);
+        authors.entry(path).or_default().insert(author.clone());
+    }
+    for (path, names) in authors {
+        if let Some(entry) = churn.get_mut(&path) {
+            entry.authors = names.len() as u64;
+        }
+    }
+}
+
+// Attach churn to the analyzed files, matching them below each root's canonical location
+fn apply_churn(files_info: &mut [FileInfo], roots: &[PathBuf], churn: &HashMap<PathBuf, FileChurn>) {
+    let canonical_roots: Vec<(&PathBuf, PathBuf)> = roots.iter()
+        .filter_map(|root| root.canonicalize().ok().map(|canonical| (root, canonical)))
+        .collect();
+    for file in files_info.iter_mut() {
+        let absolute = canonical_roots.iter().find_map(|(root, canonical)| {
+            file.path.strip_prefix(root).ok().map(|relative| {
+                if relative.as_os_str().is_empty() { canonical.clone() } else { canonical.join(relative) }
+            })
+        });
+        file.churn = absolute.and_then(|path| churn.get(&path)).map(|entry| FileChurn {
+            score: entry.commits as f64 * file.cyclomatic_complexity,
+            ..entry.clone()
+        });
+    }
+}
+
+// With churn available, hotspots are the files changed most often weighted by their complexity
+fn detect_churn_hotspots(files_info: &[FileInfo]) -> Vec<FileInfo> {
+    let mut hotspots: Vec<FileInfo> = files_info.iter()
+        .filter(|file| file.churn.as_ref().is_some_and(|churn| churn.commits > 0))
+        .cloned()
+        .collect();
+    let score = |file: &FileInfo| file.churn.as_ref().map_or(0.0, |churn| churn.score);
+    hotspots.sort_by(|a, b| {
+        score(b).partial_cmp(&score(a)).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.path.cmp(&b.path))
+    });
+    hotspots.truncate(15);
+    hotspots
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GeneratedKind {
+    Generated,
+    Minified,
+    Vendored,
+    Documentation,
+}
+
+impl GeneratedKind {
+    fn label(self) -> &'static str {
+        match self {
+            GeneratedKind::Generated => "generated",
+            GeneratedKind::Minified => "minified",
+            GeneratedKind::Vendored => "vendored",
+            GeneratedKind::Documentation => "documentation",
+        }
+    }
+
+    fn skip_reason(self) -> SkipReason {
+        match self {
+            GeneratedKind::Generated => SkipReason::Generated,
+            GeneratedKind::Minified => SkipReason::Minified,
+            GeneratedKind::Vendored => SkipReason::Vendored,
+            GeneratedKind::Documentation => SkipReason::Documentation,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GeneratedInfo {
+    kind: GeneratedKind,
+    reason: String,
+}
+
+// Third-party code checked into the tree, matched against directories below the scan root
+const VENDOR_DIRS: &[&str] = &[
+    "vendor", "vendors", "third_party", "third-party", "thirdparty", "node_modules",
+    "bower_components", "Pods", "Carthage",
+];
+
+const LOCKFILES: &[&str] = &[
+    "Cargo.lock", "package-lock.json", "npm-shrinkwrap.json", "yarn.lock", "pnpm-lock.yaml",
+    "poetry.lock", "Pipfile.lock", "Gemfile.lock", "composer.lock", "go.sum", "flake.lock",
+    "mix.lock", "pubspec.lock", "Podfile.lock", "packages.lock.json",
+];
+
+// Output of protoc, gRPC, build_runner, the WinForms designer, ...
+const GENERATED_SUFFIXES: &[&str] = &[
+    ".pb.go", ".pb.cc", ".pb.h", ".pb.swift", "_pb2.py", "_pb2.pyi", "_pb2_grpc.py",
+    "_pb.js", "_pb.d.ts", "_generated.rs", "_generated.go", "_generated.h",
+    ".g.dart", ".freezed.dart", ".designer.cs", ".g.cs",
+];
+
+// Average line length above which a file is treated as minified
+const MINIFIED_LINE_LENGTH: usize = 300;
+
+// Files under a vendor directory below the scan root (the root itself may be one)
+fn classify_vendored(file_path: &Path, root: &Path) -> Option<GeneratedInfo> {
+    let relative = file_path.strip_prefix(root).unwrap_or(file_path);
+    let vendor_dir = relative.parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+        .filter_map(|component| component.as_os_str().to_str())
+        .find(|name| VENDOR_DIRS.contains(name))?;
+    Some(GeneratedInfo { kind: GeneratedKind::Vendored, reason: format!("vendored path ({}/)", vendor_dir) })
+}
+
+// Name checks (lockfiles, generator suffixes, *.min.*) always apply; the "DO NOT EDIT" banner
+// and line-length checks only when the file's text is available
+fn classify_generated(file_path: &Path, content: Option<&str>) -> Option<GeneratedInfo> {
+    static MARKER: OnceLock<Regex> = OnceLock::new();
+    let found = |kind: GeneratedKind, reason: String| Some(GeneratedInfo { kind, reason });
+
+    let file_name = file_path.file_name()?.to_str()?;
+    let lower = file_name.to_lowercase();
+    if LOCKFILES.contains(&file_name) {
+        return found(GeneratedKind::Generated, "lockfile".to_string());
+    }
+    if let Some(suffix) = GENERATED_SUFFIXES.iter().find(|suffix| lower.ends_with(*suffix)) {
+        return found(GeneratedKind::Generated, format!("file name (*{})", suffix));
+    }
+    if lower.contains(".generated.") {
+        return found(GeneratedKind::Generated, "file name (*.generated.*)".to_string());
+    }
+    if lower.contains(".min.") {
+        return found(GeneratedKind::Minified, "file name (*.min.*)".to_string());
+    }
+
+    let content = content?;
+    let marker = MARKER.get_or_init(|| {
+        Regex::new(r"(?i)code generated\b.*\bdo not edit|@generated\b|<auto-generated|\bauto-?generated by\b|automatically generated by|generated by\b.*\bdo not (?:edit|modify)|do not edit\b.*\bgenerated").unwrap()
+    });
+    // Generators put their banner at the top of the file
+    if let Some(m) = content.lines().take(40).find_map(|line| marker.find(line)) {
+        return found(GeneratedKind::Generated, format!("marker \"{}\"", m.as_str()));
+    }
+
+    let line_count = content.lines().count();
+    if line_count > 0 && content.len() > 1024 {
+        let avg_line_length = content.len() / line_count;
+        if avg_line_length > MINIFIED_LINE_LENGTH {
+            return found(GeneratedKind::Minified, format!("average line length {}", avg_line_length));
+        }
+    }
+
+    None
+}
+
+fn analyze_file_very_fast(file_path: &Path, language_key: &str, _args: &Args, source: Source, custom: &CustomLanguages) -> Result<(LanguageStats, FileInfo), SkipReason> {
+    let file_size = source.size(file_path)?;
 
     // Ultra-fast: only read file size and estimate lines
     let estimated_lines = if file_size > 0 {
@@ -836,8 +2407,7 @@ fn analyze_file_very_fast(file_path: &Path, _args: &Args) -> Option<(LanguageSta
         ((file_size as f64 / 50.0) as u64).max(1)
     } else { 0 };
 
-    let extension = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let language = get_language_name(extension);
+    let language = custom.name(language_key);
 
     let lang_stats = LanguageStats {
         total_lines: estimated_lines,
@@ -865,6 +2435,9 @@ fn analyze_file_very_fast(file_path: &Path, _args: &Args) -> Option<(LanguageSta
         path: file_path.to_path_buf(),
         language,
         lines: estimated_lines,
+        code_lines: lang_stats.code_lines,
+        comment_lines: lang_stats.comment_lines,
+        blank_lines: lang_stats.blank_lines,
         size: file_size,
         encoding: "UTF-8".to_string(),
         complexity: 0.05,
@@ -875,25 +2448,40 @@ fn analyze_file_very_fast(file_path: &Path, _args: &Args) -> Option<(LanguageSta
         cyclomatic_complexity: 1.0,
         maintainability_index: 70.0,
         technical_debt_ratio: 0.0,
+        language_detection: None,
+        embedded: Vec::new(),
+        notebook: None,
+        generated: classify_generated(file_path, None),
+        churn: None,
+        content_hash: None,
     };
 
-    Some((lang_stats, file_info))
+    Ok((lang_stats, file_info))
 }
 
-fn analyze_file_fast(file_path: &Path, args: &Args) -> Option<(LanguageStats, FileInfo)> {
-    let metadata = fs::metadata(file_path).ok()?;
-    let file_size = metadata.len();
+fn analyze_file_fast(file_path: &Path, language_key: &str, args: &Args, source: Source, custom: &CustomLanguages) -> Result<(LanguageStats, FileInfo), SkipReason> {
+    let file_size = source.size(file_path)?;
 
     // Fast reading - optimized for speed
-    let content = if file_size > 5 * 1024 * 1024 { // 5MB threshold
+    let decoded = if file_size > 5 * 1024 * 1024 { // 5MB threshold
         // For large files, read in chunks and estimate
-        let file = File::open(file_path).ok()?;
-        let mut buffer = vec![0; 8192]; // 8KB sample
-        let bytes_read = std::io::Read::read(&mut &file, &mut buffer).ok()?;
-        String::from_utf8_lossy(&buffer[..bytes_read]).to_string()
+        let sample = source.read_range(file_path, 0, 8192)?; // 8KB sample
+        let bytes_read = sample.len();
+        // Cut at the last newline so the sample does not end mid-character
+        let end = sample.iter().rposition(|&b| b == b'\n').map_or(bytes_read, |i| i + 1);
+        let buffer = &sample[..end];
+        // A cut UTF-16/32 sample may not decode cleanly; only binary content is fatal here
+        match decode_text(buffer) {
+            Err(SkipReason::DecodeError) => DecodedText {
+                text: String::from_utf8_lossy(buffer).into_owned(),
+                encoding: "Unknown".to_string(),
+            },
+            decoded => decoded?,
+        }
     } else {
-        fs::read_to_string(file_path).ok()?
+        read_text(file_path, false, source)?
     };
+    let DecodedText { text: content, encoding } = decoded;
 
     let sample_lines = content.lines().count() as u64;
     let total_lines = if file_size > 5 * 1024 * 1024 && !content.is_empty() {
@@ -904,18 +2492,10 @@ fn analyze_file_fast(file_path: &Path, args: &Args) -> Option<(LanguageStats, Fi
         sample_lines
     };
 
-    let extension = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let language = get_language_name(extension);
-
-    // Minimal encoding detection
-    let encoding = if args.encoding {
-        detect_encoding_optimized(file_path)
-    } else {
-        "UTF-8".to_string()
-    };
+    let language = custom.name(language_key);
 
     let (created, modified) = if args.time_analysis {
-        get_file_times(file_path)
+        get_file_times(file_path, source)
     } else {
         (None, None)
     };
@@ -946,6 +2526,9 @@ fn analyze_file_fast(file_path: &Path, args: &Args) -> Option<(LanguageStats, Fi
         path: file_path.to_path_buf(),
         language,
         lines: total_lines,
+        code_lines: lang_stats.code_lines,
+        comment_lines: lang_stats.comment_lines,
+        blank_lines: lang_stats.blank_lines,
         size: file_size,
         encoding,
         complexity: 0.1,
@@ -956,54 +2539,150 @@ fn analyze_file_fast(file_path: &Path, args: &Args) -> Option<(LanguageStats, Fi
         cyclomatic_complexity: 1.0,
         maintainability_index: 75.0,
         technical_debt_ratio: 0.0,
+        language_detection: None,
+        embedded: Vec::new(),
+        notebook: None,
+        generated: classify_generated(file_path, Some(&content)),
+        churn: None,
+        content_hash: None,
     };
 
-    Some((lang_stats, file_info))
+    Ok((lang_stats, file_info))
+}
+
+// Line classification and keyword counts for a run of lines in a single language
+#[derive(Clone, Default)]
+struct LineCounts {
+    lines: u64,
+    code_lines: u64,
+    comment_lines: u64,
+    blank_lines: u64,
+    functions: u64,
+    classes: u64,
+    imports: u64,
+    todos: u64,
+    fixmes: u64,
+    complexity_score: f64,
+    cyclomatic_complexity: f64,
+    max_line_length: usize,
+    total_chars: usize,
+    test_indicators: u64,
+    doc_indicators: u64,
 }
 
-fn analyze_file_advanced(file_path: &Path, config: &LanguageConfig, args: &Args) -> Option<(LanguageStats, FileInfo)> {
-    let metadata = fs::metadata(file_path).ok()?;
-    let file_size = metadata.len();
+impl LineCounts {
+    fn add(&mut self, other: &LineCounts) {
+        self.lines += other.lines;
+        self.code_lines += other.code_lines;
+        self.comment_lines += other.comment_lines;
+        self.blank_lines += other.blank_lines;
+        self.functions += other.functions;
+        self.classes += other.classes;
+        self.imports += other.imports;
+        self.todos += other.todos;
+        self.fixmes += other.fixmes;
+        self.complexity_score += other.complexity_score;
+        self.cyclomatic_complexity += other.cyclomatic_complexity;
+        self.max_line_length = self.max_line_length.max(other.max_line_length);
+        self.total_chars += other.total_chars;
+        self.test_indicators += other.test_indicators;
+        self.doc_indicators += other.doc_indicators;
+    }
 
-    // Optimized reading strategy
-    let content = if args.use_mmap && file_size > 1024 * 1024 {
-        let file = File::open(file_path).ok()?;
-        let mmap = unsafe { Mmap::map(&file).ok()? };
-        std::str::from_utf8(&mmap).ok()?.to_string()
-    } else {
-        fs::read_to_string(file_path).ok()?
-    };
+    fn to_stats(&self, file_size: u64, files: u64) -> LanguageStats {
+        let total_lines = self.lines;
+        let code_lines = self.code_lines;
+        let comment_lines = self.comment_lines;
+        let blank_lines = self.blank_lines;
+        let functions = self.functions;
 
-    let lines: Vec<&str> = content.lines().collect();
-    let total_lines = lines.len() as u64;
-
-    let mut code_lines = 0u64;
-    let mut comment_lines = 0u64;
-    let mut blank_lines = 0u64;
-    let mut functions = 0u64;
-    let mut classes = 0u64;
-    let mut imports = 0u64;
-    let mut todos = 0u64;
-    let mut fixmes = 0u64;
-    let mut complexity_score = 0.0;
-    let mut cyclomatic_complexity = 0.0;
-    let mut max_line_length = 0;
-    let mut total_chars = 0;
-    let mut test_indicators = 0u64;
-    let mut doc_indicators = 0u64;
-
-    let mut in_multi_comment = false;
-    let mut multi_comment_end = String::new();
+        let avg_line_length = if total_lines > 0 {
+            self.total_chars as f64 / total_lines as f64
+        } else { 0.0 };
+
+        // Enhanced complexity calculations (optimized)
+        let complexity_score = if code_lines > 0 {
+            self.complexity_score / code_lines as f64
+        } else { 0.0 };
+
+        let cyclomatic_complexity = if functions > 0 {
+            (self.cyclomatic_complexity + functions as f64) / functions as f64
+        } else { 1.0 };
+
+        // Calculate maintainability index (improved with size penalty)
+        let maintainability_index = if code_lines > 0 && total_lines > 0 {
+            let volume = (total_lines as f64 * 2.0).ln().max(1.0);
+            let complexity_factor = cyclomatic_complexity.max(1.0).ln();
+            let comment_ratio = comment_lines as f64 / total_lines as f64;
+            let comment_factor = if comment_ratio > 0.0 { 
+                (comment_ratio * 50.0).min(50.0) 
+            } else { 0.0 };
+        
+            // Test coverage factor
+            let test_factor = if self.test_indicators > 0 { 5.0 } else { 0.0 };
+        
+            // Documentation factor
+            let doc_factor = if self.doc_indicators > 0 { 3.0 } else { 0.0 };
+        
+            // Size penalty for very large files
+            let size_penalty = if total_lines > 1000 {
+                let excess_lines = (total_lines - 1000) as f64;
+                let penalty = (excess_lines / 5000.0) * 30.0; // Progressive penalty
+                penalty.min(50.0) // Cap at 50 point penalty
+            } else { 0.0 };
+        
+            // Large file penalty (exponential for huge files)
+            let huge_file_penalty = if total_lines > 10000 {
+                let ratio = total_lines as f64 / 10000.0;
+                (ratio.ln() * 20.0).min(40.0) // Additional penalty for massive files
+            } else { 0.0 };
+        
+            let base_score = 171.0 - 5.2 * volume - 0.23 * complexity_factor + comment_factor + test_factor + doc_factor - size_penalty - huge_file_penalty;
+            base_score.clamp(0.0, 100.0)
+        } else { 50.0 };
+
+        // Calculate percentages
+        let code_percentage = if total_lines > 0 { code_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
+        let comment_percentage = if total_lines > 0 { comment_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
+        let blank_percentage = if total_lines > 0 { blank_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
+
+        LanguageStats {
+            total_lines,
+            code_lines,
+            comment_lines,
+            blank_lines,
+            files,
+            total_size: file_size,
+            avg_line_length,
+            max_line_length: self.max_line_length,
+            complexity_score,
+            functions,
+            classes: self.classes,
+            imports: self.imports,
+            todos: self.todos,
+            fixmes: self.fixmes,
+            code_percentage,
+            comment_percentage,
+            blank_percentage,
+            cyclomatic_complexity,
+            maintainability_index,
+        }
+    }
+}
+
+fn count_lines(lines: &[&str], config: &LanguageConfig) -> LineCounts {
+    let mut counts = LineCounts { lines: lines.len() as u64, ..LineCounts::default() };
+    let mut scanner = CommentScanner::new(config);
     let mut nesting_level = 0;
 
-    for line in &lines {
+    for line in lines {
         let trimmed = line.trim();
         let line_length = line.len();
-        max_line_length = max_line_length.max(line_length);
-        total_chars += line_length;
+        counts.max_line_length = counts.max_line_length.max(line_length);
+        counts.total_chars += line_length;
 
         if trimmed.is_empty() {
-            blank_lines += 1;
+            counts.blank_lines += 1;
             continue;
         }
 
@@ -1017,7 +2696,7 @@ fn analyze_file_advanced(file_path: &Path, config: &LanguageConfig, args: &Args)
            (line_upper.contains("// TODO")) ||
            (line_upper.contains("# TODO")) ||
            (line_upper.contains("* TODO")) {
-            todos += 1;
+            counts.todos += 1;
         }
         
         // Check for FIXME/HACK/BUG in comment context
@@ -1027,13 +2706,13 @@ fn analyze_file_advanced(file_path: &Path, config: &LanguageConfig, args: &Args)
            (line_upper.contains("// FIXME")) || (line_upper.contains("// HACK")) || (line_upper.contains("// BUG")) ||
            (line_upper.contains("# FIXME")) || (line_upper.contains("# HACK")) || (line_upper.contains("# BUG")) ||
            (line_upper.contains("* FIXME")) || (line_upper.contains("* HACK")) || (line_upper.contains("* BUG")) {
-            fixmes += 1;
+            counts.fixmes += 1;
         }
 
         // Test detection (improved)
         for test_keyword in &config.test_keywords {
             if line_upper.contains(&test_keyword.to_uppercase()) {
-                test_indicators += 1;
+                counts.test_indicators += 1;
                 break;
             }
         }
@@ -1041,344 +2720,1433 @@ fn analyze_file_advanced(file_path: &Path, config: &LanguageConfig, args: &Args)
         // Documentation detection (improved)
         for doc_keyword in &config.doc_keywords {
             if trimmed.contains(doc_keyword) {
-                doc_indicators += 1;
+                counts.doc_indicators += 1;
                 break;
             }
         }
 
-        let mut is_comment = false;
-        let mut line_content = trimmed.to_string();
+        // Lexer-aware classification: markers inside string literals are not comments
+        let scan = scanner.scan_line(line);
+        if !scan.has_code {
+            counts.comment_lines += 1;
+            continue;
+        }
 
-        // Multi-line comment handling (optimized)
-        if in_multi_comment {
-            comment_lines += 1;
-            if let Some(end_pos) = line_content.find(&multi_comment_end) {
-                in_multi_comment = false;
-                line_content = line_content[end_pos + multi_comment_end.len()..].trim().to_string();
-                if line_content.is_empty() {
-                    continue;
-                }
-            } else {
-                continue;
+        counts.code_lines += 1;
+        let line_content = scan.code.trim();
+
+        // Enhanced complexity analysis (optimized)
+        for keyword in &config.complexity_keywords {
+            if line_content.contains(keyword) {
+                counts.complexity_score += 1.0;
+                counts.cyclomatic_complexity += 1.0;
+                break; // Only count once per line
             }
         }
 
-        // Check for multi-line comment start
-        for (start, end) in &config.multi_line_comments {
-            if let Some(start_pos) = line_content.find(start) {
-                let before_comment = line_content[..start_pos].trim();
+        // Nesting level tracking (simplified)
+        let open_braces = line_content.matches('{').count();
+        let close_braces = line_content.matches('}').count();
+        nesting_level += open_braces as i32 - close_braces as i32;
+        if nesting_level > 0 {
+            counts.complexity_score += 0.05; // Reduced impact
+        }
 
-                if let Some(end_pos) = line_content[start_pos + start.len()..].find(end) {
-                    let after_comment = line_content[start_pos + start.len() + end_pos + end.len()..].trim();
-                    if !before_comment.is_empty() || !after_comment.is_empty() {
-                        code_lines += 1;
-                    } else {
-                        comment_lines += 1;
-                    }
-                    is_comment = true;
-                } else {
-                    in_multi_comment = true;
-                    multi_comment_end = end.clone();
-                    if !before_comment.is_empty() {
-                        code_lines += 1;
-                    } else {
-                        comment_lines += 1;
-                    }
-                    is_comment = true;
-                }
-                break;
+        // Pattern analysis (optimized)
+        for keyword in &config.function_keywords {
+            if line_content.contains(keyword) { 
+                counts.functions += 1; 
+                break; 
+            }
+        }
+        for keyword in &config.class_keywords {
+            if line_content.contains(keyword) { 
+                counts.classes += 1; 
+                break; 
+            }
+        }
+        for keyword in &config.import_keywords {
+            if line_content.contains(keyword) { 
+                counts.imports += 1; 
+                break; 
             }
         }
+    }
 
-        if !is_comment {
-            // Check for single-line comments (optimized)
-            let mut found_comment = false;
-            for comment_start in &config.single_line_comments {
-                if let Some(pos) = line_content.find(comment_start) {
-                    let before_comment = line_content[..pos].trim();
-                    if before_comment.is_empty() {
-                        comment_lines += 1;
-                        found_comment = true;
-                    } else {
-                        code_lines += 1;
-                    }
-                    break;
+    counts
+}
+
+fn embeds_languages(host_key: &str) -> bool {
+    matches!(host_key, "html" | "htm" | "vue" | "svelte" | "md" | "markdown")
+}
+
+// Lines of a host file written in another language: a `<script>`/`<style>` body or a fenced code block
+struct EmbeddedRegion {
+    language_key: String,
+    start: usize,
+    end: usize,
+}
+
+// Per-language totals of the embedded blocks found in one file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EmbeddedStats {
+    language: String,
+    blocks: u64,
+    stats: LanguageStats,
+}
+
+// Opening/closing tag and fence lines stay with the host language, as do one-line blocks
+// such as `<script>init()</script>`. Blocks in languages loco has no config for stay too.
+fn embedded_regions(host_key: &str, lines: &[&str], custom: &CustomLanguages) -> Vec<EmbeddedRegion> {
+    match host_key {
+        "html" | "htm" | "vue" | "svelte" => tag_regions(lines, custom),
+        "md" | "markdown" => fence_regions(lines, custom),
+        _ => Vec::new(),
+    }
+}
+
+fn tag_regions(lines: &[&str], custom: &CustomLanguages) -> Vec<EmbeddedRegion> {
+    static OPEN_TAG: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let open_tag = OPEN_TAG.get_or_init(|| Regex::new(r"(?i)<(script|style)(\s[^>]*)?>").unwrap());
+    let attribute = ATTRIBUTE.get_or_init(|| {
+        Regex::new(r#"(?i)\b(lang|type)\s*=\s*["']?([^"'\s>]+)"#).unwrap()
+    });
+
+    let mut regions = Vec::new();
+    let mut open: Option<(String, &str, usize)> = None;
+
+    for (index, line) in lines.iter().enumerate() {
+        let lower = line.to_ascii_lowercase();
+        if let Some((language_key, close_tag, start)) = &open {
+            if lower.contains(close_tag) {
+                if index > *start {
+                    regions.push(EmbeddedRegion { language_key: language_key.clone(), start: *start, end: index });
                 }
+                open = None;
             }
+            continue;
+        }
 
-            if !found_comment {
-                code_lines += 1;
+        let Some(caps) = open_tag.captures(line) else { continue };
+        let tag = caps[1].to_lowercase();
+        let close_tag = if tag == "script" { "</script" } else { "</style" };
+        if lower[caps.get(0).unwrap().end()..].contains(close_tag) {
+            continue;
+        }
 
-                // Enhanced complexity analysis (optimized)
-                for keyword in &config.complexity_keywords {
-                    if line_content.contains(keyword) {
-                        complexity_score += 1.0;
-                        cyclomatic_complexity += 1.0;
-                        break; // Only count once per line
-                    }
-                }
+        let mut lang = None;
+        let mut mime = None;
+        for attr in attribute.captures_iter(caps.get(2).map_or("", |m| m.as_str())) {
+            match attr[1].to_lowercase().as_str() {
+                "lang" => lang = Some(attr[2].to_lowercase()),
+                _ => mime = Some(attr[2].to_lowercase()),
+            }
+        }
 
-                // Nesting level tracking (simplified)
-                let open_braces = line_content.matches('{').count();
-                let close_braces = line_content.matches('}').count();
-                nesting_level += open_braces as i32 - close_braces as i32;
-                if nesting_level > 0 {
-                    complexity_score += 0.05; // Reduced impact
-                }
+        let language_key = match (tag.as_str(), lang.as_deref(), mime.as_deref()) {
+            ("script", Some("ts" | "typescript"), _) => Some("ts"),
+            ("script", Some("tsx" | "jsx" | "js"), _) => lang.as_deref(),
+            ("script", Some(_), _) => None,
+            ("script", None, None | Some("module" | "text/javascript" | "application/javascript" | "text/babel")) => Some("js"),
+            ("script", None, Some("text/typescript" | "application/typescript")) => Some("ts"),
+            ("script", None, Some("application/json" | "application/ld+json" | "importmap")) => Some("json"),
+            ("style", Some("scss" | "sass" | "less"), _) => lang.as_deref(),
+            ("style", None, _) | ("style", Some("css"), _) => Some("css"),
+            _ => None,
+        };
 
-                // Pattern analysis (optimized)
-                for keyword in &config.function_keywords {
-                    if line_content.contains(keyword) { 
-                        functions += 1; 
-                        break; 
-                    }
-                }
-                for keyword in &config.class_keywords {
-                    if line_content.contains(keyword) { 
-                        classes += 1; 
-                        break; 
+        // Unknown types (templates, stylus, ...) still need their closing tag skipped
+        let language_key = language_key.filter(|key| custom.config(key).is_some()).unwrap_or("");
+        open = Some((language_key.to_string(), close_tag, index + 1));
+    }
+
+    regions.retain(|region| !region.language_key.is_empty());
+    regions
+}
+
+// Language named by a fence info string: "rust", "rs", "{.python}", "rust,ignore", ...
+fn fence_language(info: &str, custom: &CustomLanguages) -> Option<String> {
+    let name = info.trim_start_matches(['{', '.'])
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '}')
+        .next()?
+        .to_lowercase();
+    if name.is_empty() {
+        return None;
+    }
+    language_key_for_name(&name).map(str::to_string)
+        .or_else(|| custom.config(&name).map(|_| name))
+}
+
+fn fence_regions(lines: &[&str], custom: &CustomLanguages) -> Vec<EmbeddedRegion> {
+    let mut regions = Vec::new();
+    let mut open: Option<(char, usize, Option<String>, usize)> = None;
+
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        let fence_char = match trimmed.chars().next() {
+            Some(c @ ('`' | '~')) => c,
+            _ => continue,
+        };
+        let fence_len = trimmed.chars().take_while(|&c| c == fence_char).count();
+        if fence_len < 3 {
+            continue;
+        }
+
+        match &open {
+            Some((open_char, open_len, language_key, start)) => {
+                if fence_char == *open_char && fence_len >= *open_len && trimmed[fence_len..].trim().is_empty() {
+                    if let Some(language_key) = language_key {
+                        if index > *start {
+                            regions.push(EmbeddedRegion { language_key: language_key.clone(), start: *start, end: index });
+                        }
                     }
+                    open = None;
                 }
-                for keyword in &config.import_keywords {
-                    if line_content.contains(keyword) { 
-                        imports += 1; 
-                        break; 
+            },
+            None => {
+                let info = trimmed[fence_len..].trim();
+                open = Some((fence_char, fence_len, fence_language(info, custom), index + 1));
+            },
+        }
+    }
+
+    regions
+}
+
+// Per-notebook cell counts; cell outputs are never counted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NotebookCells {
+    kernel: String,
+    code_cells: u64,
+    markdown_cells: u64,
+    raw_cells: u64,
+}
+
+// Code cells are counted with the kernel language's config and markdown cells as documentation
+// (comment lines). Handles nbformat 4 (`cells`) and the older `worksheets[].cells` layout.
+fn analyze_notebook(content: &str, custom: &CustomLanguages) -> Option<(LineCounts, NotebookCells)> {
+    let notebook: serde_json::Value = serde_json::from_str(content).ok()?;
+    let cells: Vec<&serde_json::Value> = match notebook.get("cells").and_then(|c| c.as_array()) {
+        Some(cells) => cells.iter().collect(),
+        None => notebook.get("worksheets")?.as_array()?.iter()
+            .filter_map(|worksheet| worksheet.get("cells").and_then(|c| c.as_array()))
+            .flatten()
+            .collect(),
+    };
+
+    let kernel_name = ["/metadata/kernelspec/language", "/metadata/language_info/name", "/metadata/language"].iter()
+        .find_map(|pointer| notebook.pointer(pointer).and_then(|v| v.as_str()))
+        .unwrap_or("python")
+        .to_lowercase();
+    let kernel_key = language_key_for_name(&kernel_name).map(str::to_string)
+        .unwrap_or_else(|| kernel_name.clone());
+    let config = custom.config(&kernel_key).unwrap_or_else(LanguageConfig::get_simple_config);
+
+    let mut counts = LineCounts::default();
+    let mut summary = NotebookCells {
+        kernel: custom.name(&kernel_key),
+        code_cells: 0,
+        markdown_cells: 0,
+        raw_cells: 0,
+    };
+
+    for cell in cells {
+        // `source` is a string or a list of lines; nbformat 3 code cells use `input`
+        let source = match cell.get("source").or_else(|| cell.get("input")) {
+            Some(serde_json::Value::String(text)) => text.clone(),
+            Some(serde_json::Value::Array(parts)) => parts.iter().filter_map(|part| part.as_str()).collect(),
+            _ => String::new(),
+        };
+        let lines: Vec<&str> = source.lines().collect();
+
+        match cell.get("cell_type").and_then(|t| t.as_str()) {
+            Some("code") => {
+                summary.code_cells += 1;
+                counts.add(&count_lines(&lines, &config));
+            },
+            Some("markdown" | "heading") => {
+                summary.markdown_cells += 1;
+                let mut markdown = LineCounts { lines: lines.len() as u64, doc_indicators: 1, ..LineCounts::default() };
+                for line in &lines {
+                    markdown.max_line_length = markdown.max_line_length.max(line.len());
+                    markdown.total_chars += line.len();
+                    if line.trim().is_empty() {
+                        markdown.blank_lines += 1;
+                    } else {
+                        markdown.comment_lines += 1;
                     }
                 }
-            }
+                counts.add(&markdown);
+            },
+            _ => summary.raw_cells += 1,
         }
     }
 
-    let avg_line_length = if total_lines > 0 {
-        total_chars as f64 / total_lines as f64
-    } else { 0.0 };
+    Some((counts, summary))
+}
 
-    // Enhanced complexity calculations (optimized)
-    complexity_score = if code_lines > 0 {
-        complexity_score / code_lines as f64
-    } else { 0.0 };
+fn analyze_file_advanced(file_path: &Path, language_key: &str, config: &LanguageConfig, args: &Args, source: Source, custom: &CustomLanguages) -> Result<(LanguageStats, FileInfo), SkipReason> {
+    let file_size = source.size(file_path)?;
 
-    cyclomatic_complexity = if functions > 0 {
-        (cyclomatic_complexity + functions as f64) / functions as f64
-    } else { 1.0 };
-
-    // Calculate maintainability index (improved with size penalty)
-    let maintainability_index = if code_lines > 0 && total_lines > 0 {
-        let volume = (total_lines as f64 * 2.0).ln().max(1.0);
-        let complexity_factor = cyclomatic_complexity.max(1.0).ln();
-        let comment_ratio = comment_lines as f64 / total_lines as f64;
-        let comment_factor = if comment_ratio > 0.0 { 
-            (comment_ratio * 50.0).min(50.0) 
-        } else { 0.0 };
-        
-        // Test coverage factor
-        let test_factor = if test_indicators > 0 { 5.0 } else { 0.0 };
-        
-        // Documentation factor
-        let doc_factor = if doc_indicators > 0 { 3.0 } else { 0.0 };
-        
-        // Size penalty for very large files
-        let size_penalty = if total_lines > 1000 {
-            let excess_lines = (total_lines - 1000) as f64;
-            let penalty = (excess_lines / 5000.0) * 30.0; // Progressive penalty
-            penalty.min(50.0) // Cap at 50 point penalty
-        } else { 0.0 };
-        
-        // Large file penalty (exponential for huge files)
-        let huge_file_penalty = if total_lines > 10000 {
-            let ratio = total_lines as f64 / 10000.0;
-            (ratio.ln() * 20.0).min(40.0) // Additional penalty for massive files
-        } else { 0.0 };
-        
-        let base_score = 171.0 - 5.2 * volume - 0.23 * complexity_factor + comment_factor + test_factor + doc_factor - size_penalty - huge_file_penalty;
-        base_score.max(0.0).min(100.0)
-    } else { 50.0 };
+    // Non-UTF-8 text is decoded rather than dropped; only binary files are skipped
+    let DecodedText { text: content, encoding } = read_text(file_path, args.use_mmap, source)?;
+
+    // Notebooks are counted cell by cell instead of as JSON; unparsable ones fall back to JSON
+    let notebook = if language_key == "ipynb" { analyze_notebook(&content, custom) } else { None };
+
+    let lines: Vec<&str> = content.lines().collect();
+
+    // Script/style bodies and fenced code blocks are counted in their own language
+    let regions = if notebook.is_some() { Vec::new() } else { embedded_regions(language_key, &lines, custom) };
+    let mut in_region = vec![false; lines.len()];
+    for region in &regions {
+        in_region[region.start..region.end].fill(true);
+    }
+    let host_lines: Vec<&str> = lines.iter().zip(&in_region)
+        .filter(|(_, embedded)| !**embedded)
+        .map(|(line, _)| *line)
+        .collect();
+    let host_counts = match &notebook {
+        Some((counts, _)) => counts.clone(),
+        None => count_lines(&host_lines, config),
+    };
+
+    let mut file_counts = host_counts.clone();
+    let mut embedded_counts: Vec<(String, u64, LineCounts)> = Vec::new();
+    for region in &regions {
+        let Some(region_config) = custom.config(&region.language_key) else { continue };
+        let counts = count_lines(&lines[region.start..region.end], &region_config);
+        file_counts.add(&counts);
+        match embedded_counts.iter_mut().find(|(key, _, _)| *key == region.language_key) {
+            Some((_, blocks, total)) => {
+                *blocks += 1;
+                total.add(&counts);
+            },
+            None => embedded_counts.push((region.language_key.clone(), 1, counts)),
+        }
+    }
+
+    // Embedded lines carry no bytes or files of their own; both stay with the host file
+    let embedded: Vec<EmbeddedStats> = embedded_counts.into_iter()
+        .map(|(key, blocks, counts)| EmbeddedStats {
+            language: custom.name(&key),
+            blocks,
+            stats: counts.to_stats(0, 0),
+        })
+        .collect();
 
-    // Technical debt ratio (improved)
+    // File-level metrics cover the whole file, embedded blocks included
+    let total_lines = file_counts.lines;
+    let file_stats = file_counts.to_stats(file_size, 1);
     let technical_debt_ratio = if total_lines > 0 {
-        (todos + fixmes) as f64 / total_lines as f64 * 100.0
+        (file_stats.todos + file_stats.fixmes) as f64 / total_lines as f64 * 100.0
     } else { 0.0 };
 
-    // Calculate percentages
-    let code_percentage = if total_lines > 0 { code_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
-    let comment_percentage = if total_lines > 0 { comment_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
-    let blank_percentage = if total_lines > 0 { blank_lines as f64 / total_lines as f64 * 100.0 } else { 0.0 };
-
     let (created, modified) = if args.time_analysis {
-        get_file_times(file_path)
+        get_file_times(file_path, source)
     } else {
         (None, None)
     };
 
-    let encoding = if args.encoding {
-        detect_encoding_optimized(file_path)
-    } else {
-        "UTF-8".to_string()
-    };
-
-    let extension = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let language = get_language_name(extension);
-
-    let lang_stats = LanguageStats {
-        total_lines,
-        code_lines,
-        comment_lines,
-        blank_lines,
-        files: 1,
-        total_size: file_size,
-        avg_line_length,
-        max_line_length,
-        complexity_score,
-        functions,
-        classes,
-        imports,
-        todos,
-        fixmes,
-        code_percentage,
-        comment_percentage,
-        blank_percentage,
-        cyclomatic_complexity,
-        maintainability_index,
-    };
+    let language = custom.name(language_key);
+    let lang_stats = host_counts.to_stats(file_size, 1);
 
     let file_info = FileInfo {
         path: file_path.to_path_buf(),
         language,
         lines: total_lines,
+        code_lines: file_stats.code_lines,
+        comment_lines: file_stats.comment_lines,
+        blank_lines: file_stats.blank_lines,
         size: file_size,
         encoding,
-        complexity: complexity_score,
+        complexity: file_stats.complexity_score,
         created,
         modified,
-        todos,
-        fixmes,
-        cyclomatic_complexity,
-        maintainability_index,
+        todos: file_stats.todos,
+        fixmes: file_stats.fixmes,
+        cyclomatic_complexity: file_stats.cyclomatic_complexity,
+        maintainability_index: file_stats.maintainability_index,
         technical_debt_ratio,
+        language_detection: None,
+        embedded,
+        generated: classify_generated(file_path, notebook.is_none().then_some(content.as_str())),
+        notebook: notebook.map(|(_, cells)| cells),
+        churn: None,
+        content_hash: None,
     };
 
-    Some((lang_stats, file_info))
+    Ok((lang_stats, file_info))
 }
 
-fn collect_files_optimized(path: &Path, args: &Args) -> Vec<PathBuf> {
-    let exclude_regex = args.exclude.as_ref()
-        .and_then(|exclude| Regex::new(exclude).ok());
+const CACHE_FILE_NAME: &str = ".loco-cache";
 
-    let include_exts: Option<Vec<String>> = args.include.as_ref().map(|s| 
-        s.split(',').map(|ext| ext.trim().to_lowercase()).collect()
-    );
+// Bumped whenever a change to the analysis itself (scanner, embedded blocks, decoding, ...)
+// alters the result for an unchanged file with unchanged settings
+const CACHE_FORMAT: u32 = 1;
 
-    let default_excludes = [
-        "target", "node_modules", ".git", "build", "dist", "__pycache__", 
-        ".cargo", ".next", ".nuxt", "vendor", "coverage", ".pytest_cache",
-        ".vscode", ".idea", "bin", "obj", ".vs", "packages", ".svn", ".hg",
-        "deps", "tmp", "temp", "cache", ".cache", "logs", ".terraform",
-        "venv", "env", ".env", "bower_components", ".gradle", ".settings",
-        ".metadata", "out", "cmake-build-debug", "cmake-build-release"
-    ];
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    modified_nanos: u64,
+    content_hash: Option<u64>,
+    config_hash: u64,
+    stats: LanguageStats,
+    info: FileInfo,
+}
 
-    let max_size_bytes = args.max_size * 1024 * 1024;
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: String,
+    format: u32,
+    settings_hash: u64,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
 
-    WalkDir::new(path)
-        .into_iter()
-        .par_bridge()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.file_type().is_file())
-        .filter_map(|entry| {
-            let file_path = entry.path();
-            
-            // Quick size check
-            if let Ok(metadata) = file_path.metadata() {
-                if metadata.len() > max_size_bytes {
-                    return None;
-                }
+// On-disk cache of per-file analysis results, keyed by path relative to the scan root
+struct AnalysisCache {
+    cache_path: PathBuf,
+    root: PathBuf,
+    settings_hash: u64,
+    verify_content: bool,
+    previous: HashMap<PathBuf, CacheEntry>,
+    current: DashMap<PathBuf, CacheEntry>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+// FNV-1a. Unlike DefaultHasher its output is fixed, so hashes stored in the cache stay valid
+// when loco is built with another Rust release
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = FnvHasher(0xcbf2_9ce4_8422_2325);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl AnalysisCache {
+    // Several roots share one cache in the working directory, keyed by the paths as given
+    fn load(roots: &[PathBuf], args: &Args) -> Self {
+        let root = match roots {
+            [root] => root.clone(),
+            _ => PathBuf::new(),
+        };
+        let cache_path = args.cache_file.clone().unwrap_or_else(|| {
+            if root.is_dir() { root.join(CACHE_FILE_NAME) } else { PathBuf::from(CACHE_FILE_NAME) }
+        });
+
+        // Every flag and generated-file heuristic that can change a cached result; the language
+        // config each file was counted with is part of its own entry
+        let settings_hash = hash_of(&(
+            (args.encoding, args.time_analysis, args.use_mmap, args.include_unknown, &args.generated),
+            (LOCKFILES, GENERATED_SUFFIXES, MINIFIED_LINE_LENGTH),
+        ));
+
+        // A cache written by another loco version or with different settings is discarded
+        let previous = fs::read(&cache_path).ok()
+            .and_then(|bytes| serde_json::from_slice::<CacheFile>(&bytes).ok())
+            .filter(|cache| {
+                cache.version == env!("CARGO_PKG_VERSION") && cache.format == CACHE_FORMAT && cache.settings_hash == settings_hash
+            })
+            .map(|cache| cache.entries)
+            .unwrap_or_default();
+
+        Self {
+            cache_path,
+            root,
+            settings_hash,
+            verify_content: args.cache_hash,
+            previous,
+            current: DashMap::new(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn analyze(&self, file_path: &Path, language_key: &str, config: &LanguageConfig, args: &Args, custom: &CustomLanguages) -> Result<(LanguageStats, FileInfo), SkipReason> {
+        let metadata = fs::metadata(file_path)?;
+        let size = metadata.len();
+        let modified_nanos = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        // Hosts of <script>/<style> blocks and code fences also depend on the languages they embed
+        let embedded_hash = embeds_languages(language_key).then(|| custom.fingerprint());
+        let config_hash = hash_of(&(language_key, custom.name(language_key), config, embedded_hash));
+        let content_hash = if self.verify_content {
+            Some(hash_of(&fs::read(file_path)?))
+        } else {
+            None
+        };
+
+        let key = file_path.strip_prefix(&self.root).unwrap_or(file_path).to_path_buf();
+
+        // With content hashing, mtime is ignored so fresh CI checkouts still hit
+        if let Some(entry) = self.previous.get(&key) {
+            let unchanged = entry.size == size && entry.config_hash == config_hash && match content_hash {
+                Some(hash) => entry.content_hash == Some(hash),
+                None => entry.modified_nanos == modified_nanos,
+            };
+            if unchanged {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                let mut entry = entry.clone();
+                entry.info.path = file_path.to_path_buf();
+                entry.modified_nanos = modified_nanos;
+                let result = (entry.stats.clone(), entry.info.clone());
+                self.current.insert(key, entry);
+                return Ok(result);
             }
+        }
 
-            let path_str = file_path.to_string_lossy();
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let (stats, info) = analyze_file_advanced(file_path, language_key, config, args, Source::WorkingTree, custom)?;
+        self.current.insert(key, CacheEntry {
+            size,
+            modified_nanos,
+            content_hash,
+            config_hash,
+            stats: stats.clone(),
+            info: info.clone(),
+        });
+        Ok((stats, info))
+    }
 
-            // Regex exclude check
-            if let Some(ref regex) = exclude_regex {
-                if regex.is_match(&path_str) {
-                    return None;
+    fn save(self) {
+        // Only files seen in this run are kept, so deleted files drop out of the cache
+        let cache = CacheFile {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            format: CACHE_FORMAT,
+            settings_hash: self.settings_hash,
+            entries: self.current.into_iter().collect(),
+        };
+        match serde_json::to_vec(&cache) {
+            Ok(bytes) => {
+                if let Err(e) = fs::write(&self.cache_path, bytes) {
+                    eprintln!("⚠️ Could not write cache {}: {}", self.cache_path.display(), e);
                 }
+            },
+            Err(e) => eprintln!("⚠️ Could not serialize cache: {}", e),
+        }
+    }
+}
+
+// Tool, VCS and dependency directories that are skipped unless --no-default-excludes is given.
+// Generic names such as bin/, out/ or packages/ are left to the project's ignore files.
+// The ones that are also VENDOR_DIRS are kept with --generated include, which asks for them
+// to be counted; otherwise pruning them beats classifying every file of node_modules/.
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "target", "node_modules", "build", "dist", "__pycache__",
+    ".cargo", ".next", ".nuxt", "vendor", "coverage", ".pytest_cache",
+    ".vscode", ".idea", ".vs", ".svn", ".hg", ".cache", ".terraform",
+    "venv", ".venv", ".tox", "bower_components", ".gradle", ".settings",
+    ".metadata", "cmake-build-debug", "cmake-build-release"
+];
+
+fn is_default_excluded(name: &str, keep_vendor_dirs: bool) -> bool {
+    DEFAULT_EXCLUDES.contains(&name) && !(keep_vendor_dirs && VENDOR_DIRS.contains(&name))
+}
+
+// Path an unreadable walk entry refers to, if the walker recorded one
+fn walk_error_path(error: &ignore::Error) -> Option<PathBuf> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path.clone()),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => walk_error_path(err),
+        ignore::Error::Loop { child, .. } => Some(child.clone()),
+        _ => None,
+    }
+}
+
+// linguist-* attributes for one file, as GitHub's language statistics read them
+#[derive(Debug, Clone, Default)]
+struct LinguistAttributes {
+    vendored: Option<bool>,
+    generated: Option<bool>,
+    documentation: Option<bool>,
+    detectable: Option<bool>,
+    language: Option<String>,
+}
+
+impl LinguistAttributes {
+    // `attr` sets, `-attr` and `attr=false` unset, `!attr` returns to unspecified
+    fn apply(&mut self, attribute: &str) {
+        let (name, value) = if let Some(name) = attribute.strip_prefix('-') {
+            (name, Some("false"))
+        } else if let Some(name) = attribute.strip_prefix('!') {
+            (name, None)
+        } else {
+            match attribute.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (attribute, Some("true")),
             }
+        };
+        let flag = value.map(|value| value != "false");
+
+        match name {
+            "linguist-vendored" => self.vendored = flag,
+            "linguist-generated" => self.generated = flag,
+            "linguist-documentation" => self.documentation = flag,
+            "linguist-detectable" => self.detectable = flag,
+            "linguist-language" => self.language = value.filter(|v| *v != "true" && *v != "false").map(str::to_string),
+            _ => {}
+        }
+    }
 
-            // Default excludes check (optimized)
-            for exclude in &default_excludes {
-                if path_str.contains(&format!("/{}/", exclude)) || 
-                   path_str.contains(&format!("\\{}\\", exclude)) {
-                    return None;
-                }
+    // Language key for a linguist-language override loco knows about
+    fn language_key(&self, custom: &CustomLanguages) -> Option<String> {
+        language_key_for_linguist(self.language.as_deref()?, custom)
+    }
+
+    // Explicit attributes win over loco's own path and content heuristics, in both directions
+    fn classify(&self, detected: Option<GeneratedInfo>) -> Option<GeneratedInfo> {
+        let marked = [
+            (self.vendored, GeneratedKind::Vendored, "linguist-vendored"),
+            (self.generated, GeneratedKind::Generated, "linguist-generated"),
+            (self.documentation, GeneratedKind::Documentation, "linguist-documentation"),
+        ];
+        if let Some((_, kind, attribute)) = marked.iter().find(|(flag, ..)| *flag == Some(true)) {
+            return Some(GeneratedInfo { kind: *kind, reason: format!(".gitattributes {}", attribute) });
+        }
+
+        detected.filter(|info| {
+            let flag = match info.kind {
+                GeneratedKind::Vendored => self.vendored,
+                GeneratedKind::Generated | GeneratedKind::Minified => self.generated,
+                GeneratedKind::Documentation => self.documentation,
+            };
+            flag != Some(false)
+        })
+    }
+}
+
+struct AttributeRule {
+    pattern: GlobMatcher,
+    attributes: Vec<String>,
+}
+
+// Patterns without a slash match the file name at any depth; others are relative to the
+// .gitattributes file's directory. Directory-only patterns (`dir/`) never match files.
+fn parse_gitattributes(content: &str) -> Vec<AttributeRule> {
+    content.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next().filter(|p| !p.starts_with('#') && !p.starts_with('!') && !p.ends_with('/'))?;
+            let glob = match pattern.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            let pattern = GlobBuilder::new(&glob).literal_separator(true).build().ok()?.compile_matcher();
+            Some(AttributeRule { pattern, attributes: fields.map(str::to_string).collect() })
+        })
+        .collect()
+}
+
+// .gitattributes files that apply below the scan root: those inside it, plus those of the
+// enclosing git checkout when scanning a subdirectory. Each file is parsed on first use.
+struct GitAttributes {
+    root: PathBuf,
+    // Enclosing directories (outermost first) with the scan root's path relative to each
+    outer: Vec<(PathBuf, PathBuf)>,
+    parsed: DashMap<PathBuf, Arc<Vec<AttributeRule>>>,
+}
+
+impl GitAttributes {
+    fn new(root: &Path) -> Self {
+        let mut outer = Vec::new();
+        let canonical_root = root.canonicalize().ok().filter(|dir| !dir.join(".git").exists());
+        if let Some(canonical_root) = canonical_root {
+            let checkout = canonical_root.ancestors().skip(1).position(|dir| dir.join(".git").exists());
+            if let Some(levels) = checkout {
+                outer = canonical_root.ancestors().skip(1).take(levels + 1)
+                    .map(|dir| (dir.to_path_buf(), canonical_root.strip_prefix(dir).unwrap_or(Path::new("")).to_path_buf()))
+                    .collect();
+                outer.reverse();
             }
+        }
+        Self { root: root.to_path_buf(), outer, parsed: DashMap::new() }
+    }
 
-            // Extension filter
-            if let Some(ref include_exts) = include_exts {
-                if let Some(ext) = file_path.extension().and_then(|e| e.to_str()) {
-                    if !include_exts.contains(&ext.to_lowercase()) {
-                        return None;
-                    }
-                } else {
-                    return None;
-                }
-            } else {
-                // Include known extensions OR unknown if --include-unknown is set
-                if let Some(ext) = file_path.extension().and_then(|e| e.to_str()) {
-                    if LanguageConfig::get_config(ext).is_some() || args.include_unknown {
-                        // Include it
-                    } else {
-                        return None;
-                    }
-                } else if args.include_unknown {
-                    // Include extensionless files if include_unknown is set
-                } else {
-                    return None;
+    fn rules(&self, dir: &Path, source: Source) -> Arc<Vec<AttributeRule>> {
+        if let Some(rules) = self.parsed.get(dir) {
+            return Arc::clone(&rules);
+        }
+        let rules = source.read(&dir.join(".gitattributes"))
+            .map(|content| parse_gitattributes(&String::from_utf8_lossy(&content)))
+            .unwrap_or_default();
+        Arc::clone(&self.parsed.entry(dir.to_path_buf()).or_insert(Arc::new(rules)))
+    }
+
+    // Later lines and deeper files override earlier ones, attribute by attribute
+    fn lookup(&self, file_path: &Path, source: Source) -> LinguistAttributes {
+        let mut attributes = LinguistAttributes::default();
+        let Ok(relative) = file_path.strip_prefix(&self.root) else {
+            return attributes;
+        };
+        let mut apply = |dir: &Path, path: &Path| {
+            for rule in self.rules(dir, source).iter().filter(|rule| rule.pattern.is_match(path)) {
+                for attribute in &rule.attributes {
+                    attributes.apply(attribute);
                 }
             }
+        };
 
-            Some(file_path.to_path_buf())
-        })
-        .collect()
+        for (dir, root_within) in &self.outer {
+            apply(dir, &root_within.join(relative));
+        }
+        let mut dir = self.root.clone();
+        let mut path = relative;
+        apply(&dir, path);
+        for component in relative.parent().into_iter().flat_map(|parent| parent.components()) {
+            dir.push(component);
+            path = path.strip_prefix(component).unwrap_or(path);
+            apply(&dir, path);
+        }
+        attributes
+    }
 }
 
-fn detect_hotspots_improved(files_info: &[FileInfo]) -> Vec<FileInfo> {
-    if files_info.is_empty() {
-        return Vec::new();
+#[derive(Debug, Clone)]
+struct PathGlob {
+    pattern: String,
+    include: bool,
+}
+
+// --include-glob and --exclude-glob values in the order they were given, so the last match wins
+fn ordered_path_globs(matches: &clap::ArgMatches) -> Vec<PathGlob> {
+    let mut globs: Vec<(usize, PathGlob)> = Vec::new();
+    for (id, include) in [("include_glob", true), ("exclude_glob", false)] {
+        if let (Some(indices), Some(values)) = (matches.indices_of(id), matches.get_many::<String>(id)) {
+            globs.extend(indices.zip(values).map(|(index, pattern)| (index, PathGlob { pattern: pattern.clone(), include })));
+        }
     }
+    globs.sort_by_key(|(index, _)| *index);
+    globs.into_iter().map(|(_, glob)| glob).collect()
+}
 
-    // Calculate realistic thresholds using statistics
-    let mut lines: Vec<u64> = files_info.iter().map(|f| f.lines).collect();
-    let complexities: Vec<f64> = files_info.iter().map(|f| f.complexity).collect();
-    let todos: Vec<u64> = files_info.iter().map(|f| f.todos).collect();
-    let sizes: Vec<u64> = files_info.iter().map(|f| f.size).collect();
+// Each glob is a one-line gitignore rooted at the scan root, which gives gitignore's `**`,
+// anchoring and `dir/` semantics; a glob matching a directory also matches everything below it
+#[derive(Clone)]
+struct PathGlobMatcher {
+    root: PathBuf,
+    globs: Vec<(Gitignore, bool)>,
+}
 
-    lines.sort_unstable();
-    let mut sorted_complexities = complexities.clone();
-    sorted_complexities.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-    
-    // Use 90th percentile for more aggressive detection
-    let lines_90th = if !lines.is_empty() { 
-        lines[lines.len() * 90 / 100] 
-    } else { 0 };
-    let complexity_90th = if !sorted_complexities.is_empty() { 
-        sorted_complexities[sorted_complexities.len() * 90 / 100] 
-    } else { 0.0 };
-    
-    // More aggressive thresholds
-    let large_file_threshold = std::cmp::max(300, lines_90th);
-    let high_complexity_threshold = complexity_90th.max(0.15);
-    let high_todos_threshold = 5;
-    let large_size_threshold = 100 * 1024; // 100KB
+impl PathGlobMatcher {
+    fn new(root: &Path, globs: &[PathGlob]) -> Result<Self, String> {
+        let globs = globs.iter()
+            .map(|glob| {
+                let mut builder = GitignoreBuilder::new(root);
+                builder.add_line(None, &glob.pattern).map_err(|e| format!("{}: {}", glob.pattern, e))?;
+                let matcher = builder.build().map_err(|e| format!("{}: {}", glob.pattern, e))?;
+                Ok((matcher, glob.include))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { root: root.to_path_buf(), globs })
+    }
 
-    let mut hotspots: Vec<FileInfo> = files_info.iter()
-        .filter_map(|file| {
-            let mut risk_score = 0.0;
+    fn has_includes(&self) -> bool {
+        self.globs.iter().any(|(_, include)| *include)
+    }
 
-            // File size scoring
-            if file.lines > large_file_threshold { 
-                risk_score += 3.0;
-            }
-            if file.lines > large_file_threshold * 2 { 
-                risk_score += 2.0;
+    // Some(true) if the last glob matching the path (or a parent) is an include, Some(false) if
+    // it is an exclude, None if no glob matches
+    fn verdict(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        if relative.has_root() {
+            return None;
+        }
+        self.globs.iter().rev()
+            .find(|(matcher, _)| !matcher.matched_path_or_any_parents(relative, is_dir).is_none())
+            .map(|(_, include)| *include)
+    }
+}
+
+// A scan root with the .gitattributes and glob rules resolved relative to it
+struct ScanRoot {
+    path: PathBuf,
+    gitattributes: GitAttributes,
+    globs: PathGlobMatcher,
+}
+
+// Roots in command-line order minus repeats and roots nested inside another root, which are
+// returned with the root that already covers them
+fn dedupe_roots(paths: &[PathBuf]) -> (Vec<PathBuf>, Vec<(PathBuf, PathBuf)>) {
+    let canonical: Vec<PathBuf> = paths.iter()
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+        .collect();
+    let mut roots = Vec::new();
+    let mut covered = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let covering = (0..paths.len()).find(|&j| {
+            j != i && canonical[i].starts_with(&canonical[j]) && (canonical[i] != canonical[j] || j < i)
+        });
+        match covering {
+            Some(j) => covered.push((path.clone(), paths[j].clone())),
+            None => roots.push(path.clone()),
+        }
+    }
+    (roots, covered)
+}
+
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let mut base = paths.first().cloned().unwrap_or_default();
+    while !paths.iter().all(|path| path.starts_with(&base)) && base.pop() {}
+    base
+}
+
+// Per-file checks shared by the directory walk and --files-from
+struct FileFilter<'a> {
+    args: &'a Args,
+    exclude_regex: Option<Regex>,
+    include_exts: Option<Vec<String>>,
+    max_size_bytes: u64,
+    gitattributes: &'a GitAttributes,
+    path_globs: &'a PathGlobMatcher,
+    source: Source<'a>,
+    custom: &'a CustomLanguages,
+}
+
+impl<'a> FileFilter<'a> {
+    fn new(args: &'a Args, gitattributes: &'a GitAttributes, path_globs: &'a PathGlobMatcher, source: Source<'a>, custom: &'a CustomLanguages) -> Self {
+        Self {
+            args,
+            exclude_regex: args.exclude.as_ref().and_then(|exclude| Regex::new(exclude).ok()),
+            include_exts: args.include.as_ref().map(|s|
+                s.split(',').map(|ext| ext.trim().to_lowercase()).collect()
+            ),
+            max_size_bytes: args.max_size * 1024 * 1024,
+            gitattributes,
+            path_globs,
+            source,
+            custom,
+        }
+    }
+
+    // Ok(true) to analyze the file, Ok(false) to drop it silently, Err to drop it as skipped
+    fn check(&self, file_path: &Path) -> Result<bool, SkipReason> {
+        // Quick size check
+        if let Ok(size) = self.source.size(file_path) {
+            if size > self.max_size_bytes {
+                return Err(SkipReason::TooLarge);
+            }
+        }
+
+        // Never count loco's own cache file
+        if file_path.file_name().is_some_and(|name| name == CACHE_FILE_NAME) {
+            return Ok(false);
+        }
+
+        let path_str = file_path.to_string_lossy();
+        let attributes = self.gitattributes.lookup(file_path, self.source);
+        if attributes.detectable == Some(false) {
+            return Err(SkipReason::NotDetectable);
+        }
+        let language_override = attributes.language_key(self.custom);
+
+        // Regex exclude check
+        if let Some(ref regex) = self.exclude_regex {
+            if regex.is_match(&path_str) {
+                return Err(SkipReason::ExcludedByRegex);
+            }
+        }
+
+        // Glob rules: an exclude is logged, falling outside every include is not (like --include)
+        match self.path_globs.verdict(file_path, false) {
+            Some(false) => return Err(SkipReason::ExcludedByGlob),
+            None if self.path_globs.has_includes() => return Ok(false),
+            _ => {}
+        }
+
+        // Extension filter (also matches the detected language, so `-i py` keeps Python scripts without .py)
+        if let Some(ref include_exts) = self.include_exts {
+            let extension = file_path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+            let language = language_override.unwrap_or_else(|| detect_language(file_path, true, self.source, self.custom));
+            if !extension.is_some_and(|ext| include_exts.contains(&ext)) && !include_exts.contains(&language) {
+                return Ok(false);
+            }
+        } else if !self.args.include_unknown && language_override.is_none()
+            && self.custom.config(&detect_language(file_path, false, self.source, self.custom)).is_none() {
+            // Include known languages OR unknown if --include-unknown is set
+            return Err(SkipReason::UnknownExtension);
+        }
+
+        Ok(true)
+    }
+}
+
+// Files to analyze, the files that were passed over, and the directories pruned from the walk
+fn collect_files_optimized(path: &Path, args: &Args, gitattributes: &GitAttributes, path_globs: &PathGlobMatcher, custom: &CustomLanguages) -> (Vec<PathBuf>, Vec<SkippedFile>, Vec<SkippedFile>) {
+    let filter = FileFilter::new(args, gitattributes, path_globs, Source::WorkingTree, custom);
+    let use_default_excludes = !args.no_default_excludes;
+    let use_ignore_files = !args.no_ignore;
+    let keep_vendor_dirs = args.generated == "include";
+    let skipped = Arc::new(DashMap::<PathBuf, SkipReason>::new());
+    let skipped_dirs = Arc::new(DashMap::<PathBuf, SkipReason>::new());
+    let pruned = Arc::clone(&skipped_dirs);
+    let has_include_globs = path_globs.has_includes();
+    let directory_globs = Arc::new(path_globs.clone());
+
+    let mut walker = WalkBuilder::new(path);
+    walker
+        .hidden(false)
+        .parents(use_ignore_files)
+        .ignore(use_ignore_files)
+        .git_ignore(use_ignore_files)
+        .git_global(use_ignore_files)
+        .git_exclude(use_ignore_files)
+        // Apply .gitignore even when the tree is not (or not yet) a git checkout
+        .require_git(false)
+        .filter_entry(move |entry| {
+            // Prune excluded directories by name (never the scan root itself)
+            if entry.depth() == 0 || !entry.file_type().is_some_and(|t| t.is_dir()) {
+                return true;
+            }
+            let name = entry.file_name().to_string_lossy();
+            if name == ".git" {
+                return false;
+            }
+            if use_default_excludes && is_default_excluded(&name, keep_vendor_dirs) {
+                pruned.insert(entry.path().to_path_buf(), SkipReason::DefaultExcluded);
+                return false;
+            }
+            // With include globs a later include may still match files below an excluded directory
+            if !has_include_globs && directory_globs.verdict(entry.path(), true) == Some(false) {
+                pruned.insert(entry.path().to_path_buf(), SkipReason::ExcludedByGlob);
+                return false;
+            }
+            true
+        });
+    if use_ignore_files {
+        walker.add_custom_ignore_filename(".locoignore");
+    }
+
+    let files = walker.build()
+        .par_bridge()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(error) => {
+                if let Some(error_path) = walk_error_path(&error) {
+                    let log = if error_path.is_dir() { &skipped_dirs } else { &skipped };
+                    log.insert(error_path, SkipReason::Unreadable);
+                }
+                None
+            },
+        })
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .filter_map(|entry| match filter.check(entry.path()) {
+            Ok(true) => Some(entry.into_path()),
+            Ok(false) => None,
+            Err(reason) => {
+                skipped.insert(entry.into_path(), reason);
+                None
+            },
+        })
+        .collect();
+
+    drop(walker);
+    let into_log = |log: Arc<DashMap<PathBuf, SkipReason>>| -> Vec<SkippedFile> {
+        let log = Arc::try_unwrap(log).unwrap_or_else(|arc| (*arc).clone());
+        log.into_iter().map(|(path, reason)| SkippedFile { path, reason }).collect()
+    };
+    (files, into_log(skipped), into_log(skipped_dirs))
+}
+
+// Newline- or NUL-separated paths (`git ls-files -z`, `find -print0`) from a file or stdin ("-")
+fn read_file_list(list: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut bytes = Vec::new();
+    let result = if list == Path::new("-") {
+        std::io::stdin().read_to_end(&mut bytes)
+    } else {
+        File::open(list).and_then(|mut file| file.read_to_end(&mut bytes))
+    };
+    result.map_err(|e| format!("{}: {}", list.display(), e))?;
+
+    let separator = if bytes.contains(&0) { b'\0' } else { b'\n' };
+    let paths = bytes.split(|&b| b == separator)
+        .map(|entry| String::from_utf8_lossy(entry.strip_suffix(b"\r").unwrap_or(entry)).into_owned())
+        .filter(|entry| !entry.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    Ok(paths)
+}
+
+// Directory components (below a root) that exclude a file gathered without a walk, as
+// pruning would during one
+fn in_excluded_dir(relative: &Path, args: &Args) -> bool {
+    relative.parent().into_iter()
+        .flat_map(|parent| parent.components())
+        .filter_map(|component| component.as_os_str().to_str())
+        .any(|name| name == ".git" || (!args.no_default_excludes && is_default_excluded(name, args.generated == "include")))
+}
+
+// Files gathered without a walk (--files-from, --git-ref), each with the index of its root
+type Candidates = Vec<(PathBuf, usize)>;
+
+// Runs each root's FileFilter over the candidates
+fn filter_candidates(candidates: Candidates, roots: &[ScanRoot], args: &Args, source: Source, custom: &CustomLanguages, mut skipped: Vec<SkippedFile>) -> (Vec<PathBuf>, Vec<usize>, Vec<SkippedFile>) {
+    let filters: Vec<FileFilter> = roots.iter()
+        .map(|root| FileFilter::new(args, &root.gitattributes, &root.globs, source, custom))
+        .collect();
+
+    let results: Vec<Result<(PathBuf, usize), SkippedFile>> = candidates.into_par_iter()
+        .filter_map(|(file_path, root_index)| match filters[root_index].check(&file_path) {
+            Ok(true) => Some(Ok((file_path, root_index))),
+            Ok(false) => None,
+            Err(reason) => Some(Err(SkippedFile { path: file_path, reason })),
+        })
+        .collect();
+
+    let mut files = Vec::new();
+    let mut file_roots = Vec::new();
+    for result in results {
+        match result {
+            Ok((file_path, root_index)) => {
+                files.push(file_path);
+                file_roots.push(root_index);
+            },
+            Err(skipped_file) => skipped.push(skipped_file),
+        }
+    }
+    (files, file_roots, skipped)
+}
+
+// Listed files that lie inside one of the roots, rewritten relative to that root like walked
+// files, with the same filters applied except ignore files (the list is taken as given).
+// Files outside every root are logged as skipped.
+fn collect_listed_files(listed: &[PathBuf], roots: &[ScanRoot], args: &Args, custom: &CustomLanguages) -> (Vec<PathBuf>, Vec<usize>, Vec<SkippedFile>) {
+    let canonical_roots: Vec<Option<PathBuf>> = roots.iter().map(|root| root.path.canonicalize().ok()).collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut candidates = Vec::new();
+    let mut skipped = Vec::new();
+
+    for entry in listed {
+        let Ok(canonical) = entry.canonicalize() else {
+            skipped.push(SkippedFile { path: entry.clone(), reason: SkipReason::Missing });
+            continue;
+        };
+        if !canonical.is_file() || !seen.insert(canonical.clone()) {
+            continue;
+        }
+
+        let located = canonical_roots.iter().enumerate()
+            .find_map(|(i, root)| Some((i, canonical.strip_prefix(root.as_ref()?).ok()?)));
+        if let Some((root_index, relative)) = located {
+            let file_path = roots[root_index].path.join(relative);
+            if in_excluded_dir(relative, args) {
+                skipped.push(SkippedFile { path: file_path, reason: SkipReason::DefaultExcluded });
+            } else {
+                candidates.push((file_path, root_index));
+            }
+        } else {
+            skipped.push(SkippedFile { path: entry.clone(), reason: SkipReason::OutsideRoots });
+        }
+    }
+
+    filter_candidates(candidates, roots, args, Source::WorkingTree, custom, skipped)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GitRevision {
+    name: String,
+    commit: String,
+}
+
+// --git-ref: files of a revision, read from the object database instead of the working tree.
+// A blob listed under several paths (a root's .gitattributes is also a candidate) is stored once.
+struct GitSnapshot {
+    revision: GitRevision,
+    blobs: HashMap<PathBuf, Arc<[u8]>>,
+}
+
+// Where an analysis reads file contents from; revision blobs are lent out, never copied
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    WorkingTree,
+    Revision(&'a GitSnapshot),
+}
+
+impl<'a> Source<'a> {
+    fn is_revision(self) -> bool {
+        matches!(self, Source::Revision(_))
+    }
+
+    fn blob(snapshot: &'a GitSnapshot, path: &Path) -> std::io::Result<&'a [u8]> {
+        snapshot.blobs.get(path).map(|blob| &blob[..]).ok_or_else(|| std::io::ErrorKind::NotFound.into())
+    }
+
+    fn read(self, path: &Path) -> std::io::Result<Cow<'a, [u8]>> {
+        match self {
+            Source::Revision(snapshot) => Self::blob(snapshot, path).map(Cow::Borrowed),
+            Source::WorkingTree => fs::read(path).map(Cow::Owned),
+        }
+    }
+
+    fn size(self, path: &Path) -> std::io::Result<u64> {
+        match self {
+            Source::Revision(snapshot) => Self::blob(snapshot, path).map(|blob| blob.len() as u64),
+            Source::WorkingTree => fs::metadata(path).map(|metadata| metadata.len()),
+        }
+    }
+
+    // Up to `len` bytes starting at `offset`, without reading the rest of the file
+    fn read_range(self, path: &Path, offset: u64, len: u64) -> std::io::Result<Cow<'a, [u8]>> {
+        if let Source::Revision(snapshot) = self {
+            let blob = Self::blob(snapshot, path)?;
+            let start = (offset as usize).min(blob.len());
+            let end = start.saturating_add(len as usize).min(blob.len());
+            return Ok(Cow::Borrowed(&blob[start..end]));
+        }
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        file.take(len).read_to_end(&mut bytes)?;
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+fn run_git(dir: &Path, git_args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = std::process::Command::new("git")
+        .args(git_args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("git {}: {}", git_args.join(" "), e))?;
+    if !output.status.success() {
+        return Err(format!("git {}: {}", git_args.join(" "), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(output.stdout)
+}
+
+// Blob contents for `objects`, in order, from a single `git cat-file --batch`
+fn read_git_blobs(repository: &Path, objects: &[String]) -> Result<Vec<Vec<u8>>, String> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut child = std::process::Command::new("git")
+        .args(["cat-file", "--batch"])
+        .current_dir(repository)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("git cat-file: {}", e))?;
+
+    // Requests are written from another thread so a full stdout pipe cannot deadlock us
+    let mut stdin = child.stdin.take().ok_or("git cat-file: no stdin")?;
+    let requests: String = objects.iter().map(|object| format!("{}\n", object)).collect();
+    let writer = std::thread::spawn(move || stdin.write_all(requests.as_bytes()));
+
+    let mut stdout = BufReader::new(child.stdout.take().ok_or("git cat-file: no stdout")?);
+    let mut blobs = Vec::with_capacity(objects.len());
+    for object in objects {
+        let mut header = String::new();
+        stdout.read_line(&mut header).map_err(|e| format!("git cat-file: {}", e))?;
+        let size: usize = header.split_whitespace().nth(2)
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| format!("git cat-file: cannot read {} ({})", object, header.trim()))?;
+        let mut blob = vec![0; size + 1];
+        stdout.read_exact(&mut blob).map_err(|e| format!("git cat-file: {}", e))?;
+        blob.truncate(size);
+        blobs.push(blob);
+    }
+
+    let _ = writer.join();
+    let _ = child.wait();
+    Ok(blobs)
+}
+
+// Files of every root as they exist at `revision`, plus the snapshot holding their contents
+// (and every .gitattributes of the revision, so attributes match the revision too)
+fn collect_git_files(revision: &str, roots: &[ScanRoot], args: &Args) -> Result<(GitSnapshot, Candidates, Vec<SkippedFile>), String> {
+    let git_dir = |root: &Path| -> PathBuf {
+        let dir = if root.is_dir() { root } else { root.parent().unwrap_or(Path::new("")) };
+        if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir.to_path_buf() }
+    };
+    let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).trim().to_string();
+
+    let first = git_dir(&roots[0].path);
+    let toplevel = PathBuf::from(text(run_git(&first, &["rev-parse", "--show-toplevel"])?));
+    let commit = text(run_git(&first, &["rev-parse", "--verify", &format!("{}^{{commit}}", revision)])?);
+
+    // Each root's location inside the repository
+    let mut prefixes = Vec::new();
+    for root in roots {
+        let root_toplevel = PathBuf::from(text(run_git(&git_dir(&root.path), &["rev-parse", "--show-toplevel"])?));
+        if root_toplevel != toplevel {
+            return Err(format!("{} is not in the same repository as {}", root.path.display(), roots[0].path.display()));
+        }
+        let canonical = root.path.canonicalize().map_err(|e| format!("{}: {}", root.path.display(), e))?;
+        prefixes.push(canonical.strip_prefix(&toplevel).map(Path::to_path_buf).unwrap_or_default());
+    }
+
+    let max_size_bytes = args.max_size * 1024 * 1024;
+    let mut candidates = Vec::new();
+    let mut skipped = Vec::new();
+    let mut wanted: Vec<(String, Vec<PathBuf>)> = Vec::new();
+
+    let tree = run_git(&toplevel, &["ls-tree", "-r", "-l", "-z", "--full-tree", &commit])?;
+    for entry in tree.split(|&b| b == 0).filter(|entry| !entry.is_empty()) {
+        // <mode> <type> <object> <size>\t<path>; submodules are commits, symlinks mode 120000
+        let entry = String::from_utf8_lossy(entry);
+        let Some((meta, path)) = entry.split_once('\t') else { continue };
+        let fields: Vec<&str> = meta.split_whitespace().collect();
+        if fields.len() != 4 || fields[1] != "blob" || fields[0] == "120000" {
+            continue;
+        }
+        let (object, size) = (fields[2].to_string(), fields[3].parse::<u64>().unwrap_or(0));
+        let tree_path = Path::new(path);
+
+        let mut keys = Vec::new();
+        if tree_path.file_name().is_some_and(|name| name == ".gitattributes") {
+            keys.push(toplevel.join(tree_path));
+        }
+        let located = prefixes.iter().enumerate()
+            .find_map(|(i, prefix)| Some((i, tree_path.strip_prefix(prefix).ok()?)));
+        if let Some((root_index, relative)) = located {
+            let file_path = if relative.as_os_str().is_empty() { roots[root_index].path.clone() } else { roots[root_index].path.join(relative) };
+            if in_excluded_dir(relative, args) {
+                skipped.push(SkippedFile { path: file_path, reason: SkipReason::DefaultExcluded });
+            } else if size > max_size_bytes {
+                skipped.push(SkippedFile { path: file_path, reason: SkipReason::TooLarge });
+            } else {
+                keys.push(file_path.clone());
+                candidates.push((file_path, root_index));
+            }
+        }
+        if !keys.is_empty() {
+            wanted.push((object, keys));
+        }
+    }
+
+    let objects: Vec<String> = wanted.iter().map(|(object, _)| object.clone()).collect();
+    let mut blobs = HashMap::new();
+    for ((_, keys), blob) in wanted.into_iter().zip(read_git_blobs(&toplevel, &objects)?) {
+        let blob: Arc<[u8]> = blob.into();
+        for key in keys {
+            blobs.insert(key, Arc::clone(&blob));
+        }
+    }
+
+    let snapshot = GitSnapshot {
+        revision: GitRevision { name: revision.to_string(), commit },
+        blobs,
+    };
+    Ok((snapshot, candidates, skipped))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CodeLocation {
+    path: PathBuf,
+    start_line: usize,
+    end_line: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClonePair {
+    lines: usize,
+    first: CodeLocation,
+    second: CodeLocation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DuplicationReport {
+    min_block_lines: usize,
+    analyzed_lines: u64,
+    duplicated_lines: u64,
+    clone_pairs: Vec<ClonePair>,
+}
+
+// A file reduced to its normalized code lines, remembering each line's original number
+struct NormalizedFile {
+    path: PathBuf,
+    line_numbers: Vec<usize>,
+    hashes: Vec<u64>,
+}
+
+fn normalize_for_duplicates(file_path: &Path, config: &LanguageConfig, source: Source) -> Option<NormalizedFile> {
+    let content = read_text(file_path, false, source).ok()?.text;
+    let mut line_numbers = Vec::new();
+    let mut hashes = Vec::new();
+    let mut scanner = CommentScanner::new(config);
+
+    for (index, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || !scanner.scan_line(line).has_code {
+            continue;
+        }
+
+        // Lines made only of punctuation (closing braces etc.) match everywhere and say nothing
+        if !trimmed.chars().any(|c| c.is_alphanumeric()) {
+            continue;
+        }
+
+        let normalized: String = trimmed.split_whitespace().collect::<Vec<_>>().join(" ");
+        line_numbers.push(index + 1);
+        hashes.push(hash_of(&normalized));
+    }
+
+    Some(NormalizedFile { path: file_path.to_path_buf(), line_numbers, hashes })
+}
+
+fn detect_duplicates(paths: &[PathBuf], args: &Args, source: Source, custom: &CustomLanguages) -> DuplicationReport {
+
+    let mut paths = paths.to_vec();
+    paths.sort();
+
+    let files: Vec<NormalizedFile> = paths.par_iter()
+        .filter_map(|path| {
+            let config = custom.config(&detect_language(path, true, source, custom))
+                .unwrap_or_else(LanguageConfig::get_simple_config);
+            normalize_for_duplicates(path, &config, source)
+        })
+        .collect();
+
+    find_clones(&files, args.min_dup_lines.max(2))
+}
+
+fn find_clones(files: &[NormalizedFile], min_lines: usize) -> DuplicationReport {
+    const BASE: u64 = 1_000_003;
+    let analyzed_lines = files.iter().map(|f| f.hashes.len() as u64).sum();
+
+    // Rabin-Karp over line hashes: every window of min_lines lines gets a rolling hash
+    let high_power = (1..min_lines).fold(1u64, |acc, _| acc.wrapping_mul(BASE));
+    let mut windows: HashMap<u64, Vec<(usize, usize)>> = HashMap::new();
+    for (file_index, file) in files.iter().enumerate() {
+        if file.hashes.len() < min_lines {
+            continue;
+        }
+        let mut window_hash = file.hashes[..min_lines].iter()
+            .fold(0u64, |acc, &h| acc.wrapping_mul(BASE).wrapping_add(h));
+        windows.entry(window_hash).or_default().push((file_index, 0));
+        for start in 1..=file.hashes.len() - min_lines {
+            window_hash = window_hash
+                .wrapping_sub(file.hashes[start - 1].wrapping_mul(high_power))
+                .wrapping_mul(BASE)
+                .wrapping_add(file.hashes[start + min_lines - 1]);
+            windows.entry(window_hash).or_default().push((file_index, start));
+        }
+    }
+
+    let mut clone_pairs = Vec::new();
+    let mut duplicated: Vec<Vec<bool>> = files.iter().map(|f| vec![false; f.hashes.len()]).collect();
+
+    let window = |(file_index, start): (usize, usize)| &files[file_index].hashes[start..start + min_lines];
+    for occurrences in windows.values().filter(|o| o.len() > 1) {
+        // Windows that only share a rolling hash are split into groups of identical lines
+        let mut groups: Vec<Vec<(usize, usize)>> = Vec::new();
+        for &occurrence in occurrences {
+            match groups.iter_mut().find(|group| window(group[0]) == window(occurrence)) {
+                Some(group) => group.push(occurrence),
+                None => groups.push(vec![occurrence]),
+            }
+        }
+
+        for group in groups.iter().filter(|group| group.len() > 1) {
+            // Pair every occurrence with the first one instead of all pairs, keeping output linear
+            let (first_file, first_start) = group[0];
+            let a = &files[first_file].hashes;
+            for &(other_file, other_start) in &group[1..] {
+                let b = &files[other_file].hashes;
+
+                // Only report maximal blocks: skip windows that merely continue an earlier match
+                if first_start > 0 && other_start > 0 && a[first_start - 1] == b[other_start - 1] {
+                    continue;
+                }
+
+                let mut length = min_lines;
+                while first_start + length < a.len() && other_start + length < b.len()
+                    && a[first_start + length] == b[other_start + length] {
+                    length += 1;
+                }
+
+                // Self-overlapping repeats within one file are not clones
+                if first_file == other_file && other_start < first_start + length {
+                    continue;
+                }
+
+                for offset in 0..length {
+                    duplicated[first_file][first_start + offset] = true;
+                    duplicated[other_file][other_start + offset] = true;
+                }
+
+                let location = |file: &NormalizedFile, start: usize| CodeLocation {
+                    path: file.path.clone(),
+                    start_line: file.line_numbers[start],
+                    end_line: file.line_numbers[start + length - 1],
+                };
+                clone_pairs.push(ClonePair {
+                    lines: length,
+                    first: location(&files[first_file], first_start),
+                    second: location(&files[other_file], other_start),
+                });
+            }
+        }
+    }
+
+    clone_pairs.sort_by(|a, b| b.lines.cmp(&a.lines)
+        .then_with(|| a.first.path.cmp(&b.first.path))
+        .then_with(|| a.first.start_line.cmp(&b.first.start_line)));
+
+    let duplicated_lines = duplicated.iter()
+        .map(|flags| flags.iter().filter(|&&d| d).count() as u64)
+        .sum();
+
+    DuplicationReport {
+        min_block_lines: min_lines,
+        analyzed_lines,
+        duplicated_lines,
+        clone_pairs,
+    }
+}
+
+fn detect_hotspots_improved(files_info: &[FileInfo]) -> Vec<FileInfo> {
+    if files_info.is_empty() {
+        return Vec::new();
+    }
+
+    // Calculate realistic thresholds using statistics
+    let mut lines: Vec<u64> = files_info.iter().map(|f| f.lines).collect();
+    let complexities: Vec<f64> = files_info.iter().map(|f| f.complexity).collect();
+
+    lines.sort_unstable();
+    let mut sorted_complexities = complexities.clone();
+    sorted_complexities.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    
+    // Use 90th percentile for more aggressive detection
+    let lines_90th = if !lines.is_empty() { 
+        lines[lines.len() * 90 / 100] 
+    } else { 0 };
+    let complexity_90th = if !sorted_complexities.is_empty() { 
+        sorted_complexities[sorted_complexities.len() * 90 / 100] 
+    } else { 0.0 };
+    
+    // More aggressive thresholds
+    let large_file_threshold = std::cmp::max(300, lines_90th);
+    let high_complexity_threshold = complexity_90th.max(0.15);
+    let high_todos_threshold = 5;
+    let large_size_threshold = 100 * 1024; // 100KB
+
+    let mut hotspots: Vec<FileInfo> = files_info.iter()
+        .filter_map(|file| {
+            let mut risk_score = 0.0;
+
+            // File size scoring
+            if file.lines > large_file_threshold { 
+                risk_score += 3.0;
+            }
+            if file.lines > large_file_threshold * 2 { 
+                risk_score += 2.0;
             }
             
             // Complexity scoring
@@ -1436,7 +4204,7 @@ fn detect_hotspots_improved(files_info: &[FileInfo]) -> Vec<FileInfo> {
                      (a.size as f64 / 5000.0) + 
                      (a.todos as f64 * 10.0) + 
                      (a.technical_debt_ratio * 5.0) +
-                     (if a.maintainability_index > 0.0 { (100.0 - a.maintainability_index) } else { 0.0 }) +
+                     (if a.maintainability_index > 0.0 { 100.0 - a.maintainability_index } else { 0.0 }) +
                      (a.cyclomatic_complexity * 10.0);
         
         let risk_b = (b.complexity * 200.0) + 
@@ -1444,7 +4212,7 @@ fn detect_hotspots_improved(files_info: &[FileInfo]) -> Vec<FileInfo> {
                      (b.size as f64 / 5000.0) + 
                      (b.todos as f64 * 10.0) + 
                      (b.technical_debt_ratio * 5.0) +
-                     (if b.maintainability_index > 0.0 { (100.0 - b.maintainability_index) } else { 0.0 }) +
+                     (if b.maintainability_index > 0.0 { 100.0 - b.maintainability_index } else { 0.0 }) +
                      (b.cyclomatic_complexity * 10.0);
                      
         risk_b.partial_cmp(&risk_a).unwrap_or(std::cmp::Ordering::Equal)
@@ -1454,7 +4222,7 @@ fn detect_hotspots_improved(files_info: &[FileInfo]) -> Vec<FileInfo> {
     hotspots
 }
 
-fn generate_html_report(stats: &ProjectStats, _args: &Args) -> String {
+fn generate_html_report(stats: &ProjectStats, args: &Args) -> String {
     let timestamp = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
     
     format!(r#"
@@ -1684,6 +4452,12 @@ fn generate_html_report(stats: &ProjectStats, _args: &Args) -> String {
             {}
 
             {}
+
+            {}
+
+            {}
+
+            {}
         </div>
     </div>
 </body>
@@ -1703,14 +4477,17 @@ fn generate_html_report(stats: &ProjectStats, _args: &Args) -> String {
         stats.quality_metrics.test_coverage_estimate,
         stats.quality_metrics.documentation_ratio,
         generate_git_section_enhanced(stats),
-        generate_hotspots_section_enhanced(stats)
+        generate_directory_section_enhanced(stats, args),
+        generate_hotspots_section_enhanced(stats),
+        generate_baseline_section_enhanced(stats),
+        generate_policy_section_enhanced(stats)
     )
 }
 
 fn generate_language_rows_enhanced(stats: &ProjectStats) -> String {
     let mut rows = String::new();
     let mut sorted_languages: Vec<_> = stats.languages.iter().collect();
-    sorted_languages.sort_by(|a, b| b.1.total_lines.cmp(&a.1.total_lines));
+    sorted_languages.sort_by_key(|(_, lang_stats)| std::cmp::Reverse(lang_stats.total_lines));
 
     for (language, lang_stats) in sorted_languages.iter().take(15) {
         rows.push_str(&format!(
@@ -1784,25 +4561,83 @@ fn generate_git_section_enhanced(stats: &ProjectStats) -> String {
     }
 }
 
-fn generate_hotspots_section_enhanced(stats: &ProjectStats) -> String {
-    if !stats.hotspots.is_empty() {
-        let mut section = String::from(r#"
-        <div class="section">
-            <h2>🔥 Code Hotspots & Risk Analysis</h2>
-            <p style="margin-bottom: 20px; color: #666;">Files that may need attention based on complexity, size, and technical debt indicators.</p>
-        "#);
+fn generate_directory_section_enhanced(stats: &ProjectStats, args: &Args) -> String {
+    if stats.directory_stats.is_empty() {
+        return String::new();
+    }
 
-        for (i, hotspot) in stats.hotspots.iter().enumerate() {
-            section.push_str(&format!(
-                r#"<div class="hotspot">
-                    <div style="display: flex; justify-content: space-between; align-items: center; flex-wrap: wrap;">
+    let mut rows = String::new();
+    for (depth, key) in directory_tree_order(stats, args) {
+        let dir_stats = &stats.directory_stats[key];
+        let name = if depth == 0 {
+            key.clone()
+        } else {
+            format!("{}/", key.rsplit('/').next().unwrap_or(key))
+        };
+        rows.push_str(&format!(
+            r#"<tr>
+                <td style="padding-left: {}px;" title="{}"><strong>{}</strong></td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{:.1}</td>
+            </tr>"#,
+            15 + depth * 20,
+            xml_escape(key),
+            xml_escape(&name),
+            dir_stats.files,
+            dir_stats.total_lines,
+            dir_stats.code_lines,
+            dir_stats.comment_lines,
+            dir_stats.blank_lines,
+            dir_stats.maintainability_index
+        ));
+    }
+
+    format!(r#"
+        <div class="section">
+            <h2>📁 Directory Breakdown</h2>
+            <table class="language-table">
+                <thead>
+                    <tr>
+                        <th>Directory</th>
+                        <th>Files</th>
+                        <th>Lines</th>
+                        <th>Code</th>
+                        <th>Comments</th>
+                        <th>Blank</th>
+                        <th>Maintainability</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    {}
+                </tbody>
+            </table>
+        </div>
+        "#, rows)
+}
+
+fn generate_hotspots_section_enhanced(stats: &ProjectStats) -> String {
+    if !stats.hotspots.is_empty() {
+        let mut section = String::from(r#"
+        <div class="section">
+            <h2>🔥 Code Hotspots & Risk Analysis</h2>
+            <p style="margin-bottom: 20px; color: #666;">Files that may need attention based on complexity, size, and technical debt indicators.</p>
+        "#);
+
+        for (i, hotspot) in stats.hotspots.iter().enumerate() {
+            section.push_str(&format!(
+                r#"<div class="hotspot">
+                    <div style="display: flex; justify-content: space-between; align-items: center; flex-wrap: wrap;">
                         <div style="flex: 1; min-width: 200px;">
                             <strong>#{} {}</strong><br>
                             <small style="opacity: 0.9;">{}</small>
                         </div>
                         <div style="text-align: right;">
                             📏 {} lines | 🧮 {:.3} complexity<br>
-                            📝 {} TODOs | 💾 {:.1} KB | 🔧 {:.1} MI | 🔄 {:.1} CC
+                            📝 {} TODOs | 💾 {:.1} KB | 🔧 {:.1} MI | 🔄 {:.1} CC{}
                         </div>
                     </div>
                 </div>"#,
@@ -1814,7 +4649,10 @@ fn generate_hotspots_section_enhanced(stats: &ProjectStats) -> String {
                 hotspot.todos,
                 hotspot.size as f64 / 1024.0,
                 hotspot.maintainability_index,
-                hotspot.cyclomatic_complexity
+                hotspot.cyclomatic_complexity,
+                hotspot.churn.as_ref().map(|churn| format!("<br>🔁 {} commits | +{}/-{} lines | 👥 {} authors | score {:.1}",
+                    churn.commits, churn.lines_added, churn.lines_deleted, churn.authors, churn.score
+                )).unwrap_or_default()
             ));
         }
         
@@ -1833,14 +4671,493 @@ fn generate_hotspots_section_enhanced(stats: &ProjectStats) -> String {
     }
 }
 
+fn generate_baseline_section_enhanced(stats: &ProjectStats) -> String {
+    let Some(comparison) = &stats.baseline else {
+        return String::new();
+    };
+    let row = |metric: &str, old: String, new: String, change: String| {
+        format!("<tr><td><strong>{}</strong></td><td>{}</td><td>{}</td><td>{}</td></tr>", metric, old, new, change)
+    };
+    let mut rows = String::new();
+    rows.push_str(&row("Files", comparison.files.old.to_string(), comparison.files.new.to_string(), signed(comparison.files.change)));
+    rows.push_str(&row("Lines", comparison.lines.total_lines.old.to_string(), comparison.lines.total_lines.new.to_string(),
+        format!("{} ({:.1}%)", signed(comparison.lines.total_lines.change), comparison.line_growth_percent)));
+    rows.push_str(&row("Code", comparison.lines.code_lines.old.to_string(), comparison.lines.code_lines.new.to_string(), signed(comparison.lines.code_lines.change)));
+    rows.push_str(&row("Maintainability", format!("{:.1}", comparison.maintainability.old), format!("{:.1}", comparison.maintainability.new),
+        signed_float(comparison.maintainability.change)));
+    rows.push_str(&row("TODOs", comparison.todos.old.to_string(), comparison.todos.new.to_string(), signed(comparison.todos.change)));
+    rows.push_str(&row("FIXMEs", comparison.fixmes.old.to_string(), comparison.fixmes.new.to_string(), signed(comparison.fixmes.change)));
+    rows.push_str(&row("New hotspots", String::new(), comparison.new_hotspots.len().to_string(), String::new()));
+
+    let mut notes = String::new();
+    for path in &comparison.new_hotspots {
+        notes.push_str(&format!("<li>🔥 new hotspot <code>{}</code></li>", xml_escape(&path.display().to_string())));
+    }
+    for violation in &comparison.violations {
+        notes.push_str(&format!("<li>❌ {}</li>", xml_escape(violation)));
+    }
+
+    format!(r#"
+        <div class="section">
+            <h2>📐 Baseline Comparison</h2>
+            <p style="margin-bottom: 20px; color: #666;">Against {} ({}).</p>
+            <table class="language-table">
+                <thead><tr><th>Metric</th><th>Baseline</th><th>Current</th><th>Change</th></tr></thead>
+                <tbody>{}</tbody>
+            </table>
+            <ul style="margin-top: 15px; list-style: none;">{}</ul>
+        </div>
+        "#, xml_escape(&comparison.baseline.display().to_string()), xml_escape(&comparison.created_at), rows, notes)
+}
+
+fn generate_policy_section_enhanced(stats: &ProjectStats) -> String {
+    if stats.policy_violations.is_empty() {
+        return String::new();
+    }
+    let rows: String = stats.policy_violations.iter()
+        .map(|violation| {
+            let files = match &violation.path {
+                Some(path) => path.display().to_string(),
+                None => violation.files.iter().map(|file| file.display().to_string()).collect::<Vec<_>>().join(", "),
+            };
+            format!("<tr><td>❌ <strong>{}</strong></td><td>{}</td><td>{}</td></tr>",
+                xml_escape(&violation.rule), xml_escape(&files), violation.value)
+        })
+        .collect();
+    format!(r#"
+        <div class="section">
+            <h2>🚦 Policy Violations</h2>
+            <table class="language-table">
+                <thead><tr><th>Rule</th><th>File</th><th>Value</th></tr></thead>
+                <tbody>{}</tbody>
+            </table>
+        </div>
+        "#, rows)
+}
+
+fn is_structured_format(format: &str) -> bool {
+    matches!(format, "json" | "csv" | "markdown" | "md" | "xml" | "html")
+}
+
+// Languages in the order and selection requested by --sort-by, --min-lines and --top
+fn sorted_languages<'a>(stats: &'a ProjectStats, args: &Args) -> Vec<(&'a String, &'a LanguageStats)> {
+    let mut sorted: Vec<_> = stats.languages.iter().collect();
+
+    match args.sort_by.as_str() {
+        "files" => sorted.sort_by_key(|(_, s)| std::cmp::Reverse(s.files)),
+        "size" => sorted.sort_by_key(|(_, s)| std::cmp::Reverse(s.total_size)),
+        "name" => sorted.sort_by(|a, b| a.0.cmp(b.0)),
+        _ => sorted.sort_by_key(|(_, s)| std::cmp::Reverse(s.total_lines)),
+    }
+
+    sorted.retain(|(_, s)| s.total_lines >= args.min_lines as u64);
+
+    if let Some(top) = args.top {
+        sorted.truncate(top);
+    }
+
+    sorted
+}
+
+// Files belonging to the selected languages, ordered consistently with --sort-by
+fn sorted_files<'a>(stats: &'a ProjectStats, args: &Args) -> Vec<&'a FileInfo> {
+    let languages: Vec<&String> = sorted_languages(stats, args).into_iter().map(|(name, _)| name).collect();
+    let mut files: Vec<&FileInfo> = stats.files_info.iter()
+        .filter(|file| languages.contains(&&file.language))
+        .collect();
+
+    match args.sort_by.as_str() {
+        "size" => files.sort_by_key(|f| std::cmp::Reverse(f.size)),
+        "name" => files.sort_by(|a, b| a.path.cmp(&b.path)),
+        _ => files.sort_by_key(|f| std::cmp::Reverse(f.lines)),
+    }
+
+    files
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// A single table (picked by --csv-table) so the output imports as-is into spreadsheets
+fn generate_csv_report(stats: &ProjectStats, args: &Args) -> String {
+    let mut csv = String::new();
+
+    if args.csv_table == "violations" {
+        csv.push_str("rule,value,path,files\n");
+        for violation in &stats.policy_violations {
+            let files: Vec<String> = violation.files.iter().map(|file| file.display().to_string()).collect();
+            csv.push_str(&format!("{},{},{},{}\n",
+                csv_escape(&violation.rule),
+                violation.value,
+                csv_escape(&violation.path.as_ref().map(|path| path.display().to_string()).unwrap_or_default()),
+                csv_escape(&files.join(";"))
+            ));
+        }
+        return csv;
+    }
+
+    if args.csv_table == "files" {
+        csv.push_str("path,language,lines,size,encoding,complexity,cyclomatic_complexity,maintainability_index,todos,fixmes,technical_debt_ratio\n");
+        for file in sorted_files(stats, args) {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{:.4},{:.4},{:.2},{},{},{:.2}\n",
+                csv_escape(&file.path.display().to_string()),
+                csv_escape(&file.language),
+                file.lines,
+                file.size,
+                csv_escape(&file.encoding),
+                file.complexity,
+                file.cyclomatic_complexity,
+                file.maintainability_index,
+                file.todos,
+                file.fixmes,
+                file.technical_debt_ratio
+            ));
+        }
+        return csv;
+    }
+
+    csv.push_str("language,files,total_lines,code_lines,comment_lines,blank_lines,total_size,code_percentage,comment_percentage,blank_percentage,functions,classes,imports,todos,fixmes,complexity_score,cyclomatic_complexity,maintainability_index\n");
+    for (language, lang_stats) in sorted_languages(stats, args) {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{:.2},{:.2},{:.2},{},{},{},{},{},{:.4},{:.4},{:.2}\n",
+            csv_escape(language),
+            lang_stats.files,
+            lang_stats.total_lines,
+            lang_stats.code_lines,
+            lang_stats.comment_lines,
+            lang_stats.blank_lines,
+            lang_stats.total_size,
+            lang_stats.code_percentage,
+            lang_stats.comment_percentage,
+            lang_stats.blank_percentage,
+            lang_stats.functions,
+            lang_stats.classes,
+            lang_stats.imports,
+            lang_stats.todos,
+            lang_stats.fixmes,
+            lang_stats.complexity_score,
+            lang_stats.cyclomatic_complexity,
+            lang_stats.maintainability_index
+        ));
+    }
+
+    csv
+}
+
+fn markdown_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn generate_markdown_report(stats: &ProjectStats, args: &Args) -> String {
+    let timestamp = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
+    let mut md = String::new();
+
+    md.push_str("# 🚀 LOCO Code Analysis Report\n\n");
+    md.push_str(&format!("_Generated {}_\n\n", timestamp));
+
+    md.push_str("## 📊 Project Overview\n\n");
+    md.push_str("| Metric | Value |\n|---|---:|\n");
+    md.push_str(&format!("| Files | {} |\n", stats.total_files));
+    md.push_str(&format!("| Lines | {} |\n", stats.total_lines));
+    md.push_str(&format!("| Size (MB) | {:.2} |\n", stats.total_size as f64 / 1_048_576.0));
+    if let Some(revision) = &stats.revision {
+        md.push_str(&format!("| Revision | {} ({}) |\n", markdown_escape(&revision.name), &revision.commit[..revision.commit.len().min(12)]));
+    }
+    if !stats.skipped_files.is_empty() {
+        md.push_str(&format!("| Skipped files | {} ({}) |\n", stats.skipped_files.len(), skip_summary(&stats.skipped_files)));
+    }
+    if !stats.skipped_dirs.is_empty() {
+        md.push_str(&format!("| Pruned directories | {} ({}) |\n", stats.skipped_dirs.len(), skip_summary(&stats.skipped_dirs)));
+    }
+    if !stats.generated_files.is_empty() {
+        md.push_str(&format!("| Generated/vendored files | {} ({} lines, not in totals) |\n",
+            stats.generated_files.len(), stats.generated_files.iter().map(|f| f.lines).sum::<u64>()));
+    }
+    md.push_str(&format!("| Analysis time (s) | {:.3} |\n\n", stats.analysis_time));
+
+    md.push_str("## 🔤 Languages\n\n");
+    md.push_str("| Language | Files | Lines | Code | Comments | Blank | Code % | Comments % | Complexity | Maintainability |\n");
+    md.push_str("|---|---:|---:|---:|---:|---:|---:|---:|---:|---:|\n");
+    for (language, lang_stats) in sorted_languages(stats, args) {
+        md.push_str(&format!(
+            "| {} | {} | {} | {} | {} | {} | {:.1}% | {:.1}% | {:.3} | {:.1} |\n",
+            markdown_escape(language),
+            lang_stats.files,
+            lang_stats.total_lines,
+            lang_stats.code_lines,
+            lang_stats.comment_lines,
+            lang_stats.blank_lines,
+            lang_stats.code_percentage,
+            lang_stats.comment_percentage,
+            lang_stats.complexity_score,
+            lang_stats.maintainability_index
+        ));
+    }
+    md.push('\n');
+
+    if !stats.roots.is_empty() {
+        md.push_str("## 📂 Roots\n\n");
+        md.push_str("| Root | Files | Lines | Code | Comments | Blank | Size (KB) |\n");
+        md.push_str("|---|---:|---:|---:|---:|---:|---:|\n");
+        for root in &stats.roots {
+            md.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {} | {:.1} |\n",
+                markdown_escape(&root.path.display().to_string()),
+                root.total_files,
+                root.total_lines,
+                root.languages.values().map(|s| s.code_lines).sum::<u64>(),
+                root.languages.values().map(|s| s.comment_lines).sum::<u64>(),
+                root.languages.values().map(|s| s.blank_lines).sum::<u64>(),
+                root.total_size as f64 / 1024.0
+            ));
+        }
+        md.push('\n');
+    }
+
+    md.push_str("## 📈 Quality Metrics\n\n");
+    md.push_str("| Metric | Value |\n|---|---:|\n");
+    md.push_str(&format!("| Overall maintainability | {:.1} |\n", stats.quality_metrics.overall_maintainability));
+    md.push_str(&format!("| Technical debt ratio | {:.2}% |\n", stats.quality_metrics.technical_debt_ratio));
+    md.push_str(&format!("| Test coverage estimate | {:.1}% |\n", stats.quality_metrics.test_coverage_estimate));
+    md.push_str(&format!("| Documentation ratio | {:.2}% |\n", stats.quality_metrics.documentation_ratio));
+    if stats.duplication.is_some() {
+        md.push_str(&format!("| Code duplication ratio | {:.2}% |\n", stats.quality_metrics.code_duplication_ratio));
+    }
+    md.push('\n');
+
+    if let Some(ref git_info) = stats.git_info {
+        md.push_str("## 🔄 Git Repository\n\n");
+        md.push_str("| Metric | Value |\n|---|---:|\n");
+        md.push_str(&format!("| Commits | {} |\n", git_info.total_commits));
+        md.push_str(&format!("| Contributors | {} |\n", git_info.contributors));
+        md.push_str(&format!("| Branch | {} |\n", markdown_escape(git_info.branch.as_deref().unwrap_or("Unknown"))));
+        md.push_str(&format!("| Last commit | {} |\n", git_info.last_commit_date.as_deref().unwrap_or("Unknown")));
+        md.push_str(&format!("| Most active | {} |\n\n", markdown_escape(git_info.most_active_author.as_deref().unwrap_or("Unknown"))));
+    }
+
+    if !stats.hotspots.is_empty() {
+        // Churn columns only when --since gathered change history
+        let with_churn = stats.hotspots.iter().any(|hotspot| hotspot.churn.is_some());
+        md.push_str("## 🔥 Hotspots\n\n");
+        md.push_str("| # | File | Lines | Complexity | TODOs | MI | CC |");
+        md.push_str(if with_churn { " Commits | Added | Deleted | Authors | Score |\n" } else { "\n" });
+        md.push_str("|---:|---|---:|---:|---:|---:|---:|");
+        md.push_str(if with_churn { "---:|---:|---:|---:|---:|\n" } else { "\n" });
+        for (i, hotspot) in stats.hotspots.iter().enumerate() {
+            md.push_str(&format!(
+                "| {} | `{}` | {} | {:.3} | {} | {:.1} | {:.1} |",
+                i + 1,
+                markdown_escape(&hotspot.path.display().to_string()),
+                hotspot.lines,
+                hotspot.complexity,
+                hotspot.todos,
+                hotspot.maintainability_index,
+                hotspot.cyclomatic_complexity
+            ));
+            if with_churn {
+                let churn = hotspot.churn.clone().unwrap_or_default();
+                md.push_str(&format!(" {} | {} | {} | {} | {:.1} |",
+                    churn.commits, churn.lines_added, churn.lines_deleted, churn.authors, churn.score));
+            }
+            md.push('\n');
+        }
+        md.push('\n');
+    }
+
+    if let Some(ref comparison) = stats.baseline {
+        md.push_str("## 📐 Baseline Comparison\n\n");
+        md.push_str(&format!("Against `{}` ({}).\n\n", markdown_escape(&comparison.baseline.display().to_string()), comparison.created_at));
+        md.push_str("| Metric | Baseline | Current | Change |\n|---|---:|---:|---:|\n");
+        md.push_str(&format!("| Files | {} | {} | {} |\n", comparison.files.old, comparison.files.new, signed(comparison.files.change)));
+        md.push_str(&format!("| Lines | {} | {} | {} ({:.1}%) |\n",
+            comparison.lines.total_lines.old, comparison.lines.total_lines.new,
+            signed(comparison.lines.total_lines.change), comparison.line_growth_percent));
+        md.push_str(&format!("| Code | {} | {} | {} |\n", comparison.lines.code_lines.old, comparison.lines.code_lines.new, signed(comparison.lines.code_lines.change)));
+        md.push_str(&format!("| Maintainability | {:.1} | {:.1} | {} |\n",
+            comparison.maintainability.old, comparison.maintainability.new, signed_float(comparison.maintainability.change)));
+        md.push_str(&format!("| TODOs | {} | {} | {} |\n", comparison.todos.old, comparison.todos.new, signed(comparison.todos.change)));
+        md.push_str(&format!("| FIXMEs | {} | {} | {} |\n", comparison.fixmes.old, comparison.fixmes.new, signed(comparison.fixmes.change)));
+        md.push_str(&format!("| New hotspots | | {} | |\n\n", comparison.new_hotspots.len()));
+        for path in &comparison.new_hotspots {
+            md.push_str(&format!("- 🔥 new hotspot `{}`\n", markdown_escape(&path.display().to_string())));
+        }
+        for violation in &comparison.violations {
+            md.push_str(&format!("- ❌ {}\n", violation));
+        }
+        if !comparison.new_hotspots.is_empty() || !comparison.violations.is_empty() {
+            md.push('\n');
+        }
+    }
+
+    if !stats.policy_violations.is_empty() {
+        md.push_str("## 🚦 Policy Violations\n\n");
+        for violation in &stats.policy_violations {
+            md.push_str(&format!("- ❌ {}\n", markdown_escape(&violation.describe())));
+        }
+        md.push('\n');
+    }
+
+    // The per-file table can be huge, so only include it in verbose mode
+    if args.verbose {
+        md.push_str("## 📄 Files\n\n");
+        md.push_str("| File | Language | Lines | Size | Complexity | TODOs | MI |\n");
+        md.push_str("|---|---|---:|---:|---:|---:|---:|\n");
+        for file in sorted_files(stats, args) {
+            md.push_str(&format!(
+                "| `{}` | {} | {} | {} | {:.3} | {} | {:.1} |\n",
+                markdown_escape(&file.path.display().to_string()),
+                markdown_escape(&file.language),
+                file.lines,
+                file.size,
+                file.complexity,
+                file.todos,
+                file.maintainability_index
+            ));
+        }
+        md.push('\n');
+    }
+
+    md
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters other than tab/newline are not allowed in XML 1.0
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// Serialize a JSON value as XML: objects become nested elements, arrays become <item> lists,
+// and map keys that are not valid element names (paths, language names) become <entry key="...">
+fn push_xml_value(xml: &mut String, name: &str, value: &serde_json::Value, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let (open, close) = if is_xml_name(name) {
+        (name.to_string(), name.to_string())
+    } else {
+        (format!("entry key=\"{}\"", xml_escape(name)), "entry".to_string())
+    };
+
+    match value {
+        serde_json::Value::Null => xml.push_str(&format!("{}<{}/>\n", indent, open)),
+        serde_json::Value::Object(map) => {
+            xml.push_str(&format!("{}<{}>\n", indent, open));
+            for (key, child) in map {
+                push_xml_value(xml, key, child, depth + 1);
+            }
+            xml.push_str(&format!("{}</{}>\n", indent, close));
+        },
+        serde_json::Value::Array(items) => {
+            xml.push_str(&format!("{}<{}>\n", indent, open));
+            for item in items {
+                push_xml_value(xml, "item", item, depth + 1);
+            }
+            xml.push_str(&format!("{}</{}>\n", indent, close));
+        },
+        serde_json::Value::String(s) => {
+            xml.push_str(&format!("{}<{}>{}</{}>\n", indent, open, xml_escape(s), close));
+        },
+        other => xml.push_str(&format!("{}<{}>{}</{}>\n", indent, open, other, close)),
+    }
+}
+
+fn generate_xml_report(stats: &ProjectStats, args: &Args) -> String {
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<loco version=\"{}\" generated=\"{}\">\n", env!("CARGO_PKG_VERSION"), timestamp));
+
+    xml.push_str("  <summary>\n");
+    xml.push_str(&format!("    <total_files>{}</total_files>\n", stats.total_files));
+    xml.push_str(&format!("    <total_lines>{}</total_lines>\n", stats.total_lines));
+    xml.push_str(&format!("    <total_size>{}</total_size>\n", stats.total_size));
+    xml.push_str(&format!("    <analysis_time>{}</analysis_time>\n", stats.analysis_time));
+    xml.push_str("  </summary>\n");
+
+    xml.push_str("  <languages>\n");
+    for (language, lang_stats) in sorted_languages(stats, args) {
+        xml.push_str(&format!("    <language name=\"{}\">\n", xml_escape(language)));
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(lang_stats) {
+            for (key, value) in &fields {
+                push_xml_value(&mut xml, key, value, 3);
+            }
+        }
+        xml.push_str("    </language>\n");
+    }
+    xml.push_str("  </languages>\n");
+
+    xml.push_str("  <files>\n");
+    for file in sorted_files(stats, args) {
+        xml.push_str(&format!("    <file path=\"{}\">\n", xml_escape(&file.path.display().to_string())));
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(file) {
+            for (key, value) in fields.iter().filter(|(key, _)| key.as_str() != "path") {
+                push_xml_value(&mut xml, key, value, 3);
+            }
+        }
+        xml.push_str("    </file>\n");
+    }
+    xml.push_str("  </files>\n");
+
+    // Remaining sections are emitted generically so they stay in sync with ProjectStats
+    if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(stats) {
+        for (key, value) in &fields {
+            match key.as_str() {
+                "languages" | "files_info" | "total_files" | "total_lines" | "total_size" | "analysis_time" => {},
+                _ => push_xml_value(&mut xml, key, value, 1),
+            }
+        }
+    }
+
+    xml.push_str("</loco>\n");
+    xml
+}
+
+fn write_report(content: &str, args: &Args, label: &str) {
+    if let Some(output_path) = &args.output {
+        if let Err(e) = fs::write(output_path, content) {
+            eprintln!("❌ Cannot write {}: {}", output_path.display(), e);
+            std::process::exit(1);
+        }
+        println!("📊 {} saved to: {}", label, output_path.display());
+    } else {
+        print!("{}", content);
+        if !content.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
 fn show_top_files_enhanced(stats: &ProjectStats, metric: &str) {
     let mut files = stats.files_info.clone();
 
     match metric {
-        "lines" => files.sort_by(|a, b| b.lines.cmp(&a.lines)),
+        "lines" => files.sort_by_key(|f| std::cmp::Reverse(f.lines)),
         "complexity" => files.sort_by(|a, b| b.complexity.partial_cmp(&a.complexity).unwrap_or(std::cmp::Ordering::Equal)),
-        "todos" => files.sort_by(|a, b| b.todos.cmp(&a.todos)),
-        "size" => files.sort_by(|a, b| b.size.cmp(&a.size)),
+        "todos" => files.sort_by_key(|f| std::cmp::Reverse(f.todos)),
+        "size" => files.sort_by_key(|f| std::cmp::Reverse(f.size)),
         "maintainability" => files.sort_by(|a, b| a.maintainability_index.partial_cmp(&b.maintainability_index).unwrap_or(std::cmp::Ordering::Equal)),
         "debt" => files.sort_by(|a, b| b.technical_debt_ratio.partial_cmp(&a.technical_debt_ratio).unwrap_or(std::cmp::Ordering::Equal)),
         _ => return,
@@ -1951,8 +5268,7 @@ fn calculate_quality_metrics_improved(stats: &ProjectStats) -> QualityMetrics {
         
         // Boost based on test infrastructure
         let boost = if test_files > 0 {
-            let complexity_factor = if overall_maintainability > 60.0 { 15.0 } else { 5.0 };
-            complexity_factor
+            if overall_maintainability > 60.0 { 15.0 } else { 5.0 }
         } else {
             0.0
         };
@@ -2007,43 +5323,11 @@ fn calculate_quality_metrics_improved(stats: &ProjectStats) -> QualityMetrics {
         0.0
     };
 
-    // Improved code duplication estimation
-    let code_duplication_ratio = if stats.files_info.len() > 10 {
-        // Group files by similar sizes and complexity
-        let mut size_groups: HashMap<u64, usize> = HashMap::new();
-        let mut complexity_groups: HashMap<u64, usize> = HashMap::new();
-        
-        for file in &stats.files_info {
-            let size_bucket = (file.lines / 50) * 50; // Group by 50-line buckets
-            let complexity_bucket = ((file.complexity * 100.0) as u64 / 10) * 10;
-            
-            *size_groups.entry(size_bucket).or_insert(0) += 1;
-            *complexity_groups.entry(complexity_bucket).or_insert(0) += 1;
-        }
-        
-        // Calculate suspicion score based on groupings
-        let size_suspicion = size_groups.values()
-            .filter(|&&count| count > 3)
-            .map(|&count| count as f64)
-            .sum::<f64>() / stats.files_info.len() as f64 * 15.0;
-        
-        let complexity_suspicion = complexity_groups.values()
-            .filter(|&&count| count > 5)
-            .map(|&count| count as f64)
-            .sum::<f64>() / stats.files_info.len() as f64 * 10.0;
-        
-        // Average function/class ratio analysis
-        let avg_functions_per_line = stats.languages.values()
-            .filter(|lang| lang.total_lines > 0)
-            .map(|lang| lang.functions as f64 / lang.total_lines as f64)
-            .sum::<f64>() / stats.languages.len() as f64;
-        
-        let pattern_suspicion = if avg_functions_per_line < 0.005 { 5.0 } else { 0.0 };
-        
-        (size_suspicion + complexity_suspicion + pattern_suspicion).min(30.0)
-    } else {
-        0.0
-    };
+    // Duplication ratio from actual duplicated lines (only known when --duplicates ran)
+    let code_duplication_ratio = stats.duplication.as_ref()
+        .filter(|report| report.analyzed_lines > 0)
+        .map(|report| report.duplicated_lines as f64 / report.analyzed_lines as f64 * 100.0)
+        .unwrap_or(0.0);
 
     QualityMetrics {
         overall_maintainability,
@@ -2054,6 +5338,33 @@ fn calculate_quality_metrics_improved(stats: &ProjectStats) -> QualityMetrics {
     }
 }
 
+// "3 unknown extension, 1 too large" in reason order
+fn skip_summary(skipped: &[SkippedFile]) -> String {
+    let mut counts: std::collections::BTreeMap<SkipReason, usize> = std::collections::BTreeMap::new();
+    for file in skipped {
+        *counts.entry(file.reason).or_default() += 1;
+    }
+    counts.into_iter()
+        .map(|(reason, count)| format!("{} {}", count, reason.label()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Pruned directories are listed with a trailing slash
+fn print_skipped_files(skipped: &[SkippedFile], skipped_dirs: &[SkippedFile]) {
+    let mut entries: Vec<(&SkippedFile, bool)> = skipped.iter().map(|file| (file, false))
+        .chain(skipped_dirs.iter().map(|dir| (dir, true)))
+        .collect();
+    entries.sort_by(|(a, _), (b, _)| a.reason.cmp(&b.reason).then(a.path.cmp(&b.path)));
+
+    println!("\n{} Skipped Files", "⏭️".bright_yellow().bold());
+    println!("{}", "─".repeat(110).bright_black());
+    for (entry, is_dir) in entries {
+        let path = format!("{}{}", entry.path.display(), if is_dir { "/" } else { "" });
+        println!("  {:<18} {}", entry.reason.label().bright_yellow(), path.bright_black());
+    }
+}
+
 fn print_results_optimized(stats: &ProjectStats, args: &Args) {
     println!("{}", "🚀 LOCO - Ultra-Fast Code Intelligence".bright_cyan().bold());
     println!("{}", "═".repeat(80).bright_black());
@@ -2062,6 +5373,38 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
     println!("  📁 {} files analyzed", stats.total_files.to_string().bright_white());
     println!("  📏 {} total lines of code", stats.total_lines.to_string().bright_white());
     println!("  💾 {:.2} MB total size", (stats.total_size as f64 / 1_048_576.0).to_string().bright_white());
+    if let Some(revision) = &stats.revision {
+        println!("  🔖 at {} ({})", revision.name.bright_white(), &revision.commit[..revision.commit.len().min(12)]);
+    }
+
+    let decoded_files = stats.files_info.iter().filter(|f| !is_utf8_encoding(&f.encoding)).count();
+    if decoded_files > 0 {
+        println!("  🔤 {} files decoded from non-UTF-8 encodings (counted)", decoded_files.to_string().bright_yellow());
+    }
+    if !stats.skipped_files.is_empty() {
+        println!("  ⏭️ {} files skipped: {}{}",
+            stats.skipped_files.len().to_string().bright_yellow(),
+            skip_summary(&stats.skipped_files),
+            if args.show_skipped { "" } else { " (--show-skipped to list)" }
+        );
+    }
+    if !stats.skipped_dirs.is_empty() {
+        println!("  🚧 {} directories pruned: {}",
+            stats.skipped_dirs.len().to_string().bright_yellow(),
+            skip_summary(&stats.skipped_dirs)
+        );
+    }
+    if !stats.generated_files.is_empty() {
+        println!("  🏭 {} generated/vendored files ({} lines) reported separately, not in the totals (--generated include to count them)",
+            stats.generated_files.len().to_string().bright_yellow(),
+            stats.generated_files.iter().map(|f| f.lines).sum::<u64>()
+        );
+    } else {
+        let counted = stats.files_info.iter().filter(|f| f.generated.is_some()).count();
+        if counted > 0 {
+            println!("  🏭 {} generated/vendored files included in the totals", counted.to_string().bright_yellow());
+        }
+    }
     
     // Fixed Performance metrics with accurate calculations
     println!("\n{} Performance Metrics", "⚡".bright_yellow().bold());
@@ -2084,6 +5427,9 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
     if stats.quality_metrics.documentation_ratio > 0.0 {
         println!("  📖 {:.1}% documentation ratio", stats.quality_metrics.documentation_ratio.to_string().bright_green());
     }
+    if stats.duplication.is_some() {
+        println!("  🧬 {}% code duplication ratio", format!("{:.2}", stats.quality_metrics.code_duplication_ratio).bright_magenta());
+    }
 
     // Benchmark mode - show additional performance details
     if args.benchmark {
@@ -2093,6 +5439,16 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
             (stats.total_size as f64 / 1024.0 / stats.total_files as f64).to_string().bright_cyan());
         println!("  ⚡ Processing efficiency: {:.2} lines/file avg", 
             (stats.total_lines as f64 / stats.total_files as f64).to_string().bright_white());
+        if args.cache {
+            let lookups = stats.performance_metrics.cache_hits + stats.performance_metrics.cache_misses;
+            let hit_rate = if lookups > 0 {
+                stats.performance_metrics.cache_hits as f64 / lookups as f64 * 100.0
+            } else { 0.0 };
+            println!("  💾 Cache: {} hits | {} misses ({:.1}% hit rate)",
+                stats.performance_metrics.cache_hits.to_string().bright_green(),
+                stats.performance_metrics.cache_misses.to_string().bright_yellow(),
+                hit_rate);
+        }
     }
 
     // Git statistics (unchanged but improved)
@@ -2117,25 +5473,13 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
         println!("  ➖ {} lines deleted (recent)", git_info.lines_deleted.to_string().bright_red());
     }
 
-    let mut sorted_languages: Vec<_> = stats.languages.iter().collect();
-
-    match args.sort_by.as_str() {
-        "files" => sorted_languages.sort_by(|a, b| b.1.files.cmp(&a.1.files)),
-        "size" => sorted_languages.sort_by(|a, b| b.1.total_size.cmp(&a.1.total_size)),
-        "name" => sorted_languages.sort_by(|a, b| a.0.cmp(b.0)),
-        _ => sorted_languages.sort_by(|a, b| b.1.total_lines.cmp(&a.1.total_lines)),
-    }
-
-    if let Some(top) = args.top {
-        sorted_languages.truncate(top);
-    }
+    let sorted_languages = sorted_languages(stats, args);
 
     println!("\n{} Language Intelligence", "🔤".bright_blue().bold());
     println!("{}", "─".repeat(110).bright_black());
 
     for (language, lang_stats) in &sorted_languages {
         let total_lines = lang_stats.total_lines;
-        if total_lines < args.min_lines as u64 { continue; }
 
         println!("\n▶️ {}", language.bright_white().bold());
         
@@ -2156,6 +5500,32 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
             lang_stats.blank_percentage.to_string().bright_black()
         );
 
+        // What this language's files embed: <script>/<style> blocks, fenced code, ...
+        if let Some(hosted) = stats.embedded_languages.get(language.as_str()) {
+            for embedded in hosted {
+                println!("  🧩 embeds {} | {} lines in {} blocks ({} code | {} comments)",
+                    embedded.language.bright_white(),
+                    embedded.stats.total_lines.to_string().bright_green(),
+                    embedded.blocks.to_string().bright_cyan(),
+                    embedded.stats.code_lines,
+                    embedded.stats.comment_lines
+                );
+            }
+        }
+
+        let notebooks: Vec<&NotebookCells> = stats.files_info.iter()
+            .filter(|file| file.language == **language)
+            .filter_map(|file| file.notebook.as_ref())
+            .collect();
+        if !notebooks.is_empty() {
+            println!("  📓 {} notebooks | {} code cells | {} markdown cells | {} raw cells",
+                notebooks.len().to_string().bright_cyan(),
+                notebooks.iter().map(|n| n.code_cells).sum::<u64>().to_string().bright_green(),
+                notebooks.iter().map(|n| n.markdown_cells).sum::<u64>().to_string().bright_blue(),
+                notebooks.iter().map(|n| n.raw_cells).sum::<u64>()
+            );
+        }
+
         if args.complexity || args.verbose {
             println!("  🧮 {:.3} avg complexity | {:.3} cyclomatic complexity", 
                 lang_stats.complexity_score,
@@ -2198,7 +5568,7 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
         if args.size_stats {
             println!("  💾 {:.2} MB ({:.1} KB/file)", 
                 lang_stats.total_size as f64 / 1_048_576.0,
-                lang_stats.total_size as f64 / 1024.0 / lang_stats.files as f64
+                lang_stats.total_size as f64 / 1024.0 / lang_stats.files.max(1) as f64
             );
         }
 
@@ -2210,37 +5580,200 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
         }
     }
 
-    // Show top files if requested
-    if let Some(ref metric) = args.top_files {
-        show_top_files_enhanced(stats, metric);
-    }
-
-    // Show hotspots if requested (improved)
-    if args.hotspots && !stats.hotspots.is_empty() {
-        println!("\n{} Code Hotspots & Risk Analysis", "🔥".bright_red().bold());
+    if !stats.roots.is_empty() {
+        println!("\n{} Roots", "📂".bright_blue().bold());
         println!("{}", "─".repeat(110).bright_black());
-        println!("  Files requiring attention based on complexity, size, and technical debt:\n");
-        
-        for (i, hotspot) in stats.hotspots.iter().enumerate() {
-            let risk_indicator = match i {
-                0..=2 => "🔴",  // High risk
-                3..=6 => "🟡",  // Medium risk
-                _ => "🟠",      // Lower risk
-            };
-            
-            println!("  {} {}. {} | {} lines | {:.3} complexity | {} TODOs | {:.1} MI | {:.1} CC", 
-                risk_indicator,
-                (i + 1).to_string().bright_white(),
-                hotspot.path.display().to_string().bright_red(),
-                hotspot.lines.to_string().bright_white(),
-                hotspot.complexity,
-                hotspot.todos.to_string().bright_yellow(),
-                hotspot.maintainability_index,
-                hotspot.cyclomatic_complexity
+        for root in &stats.roots {
+            let mut languages: Vec<(&String, &LanguageStats)> = root.languages.iter().collect();
+            languages.sort_by(|a, b| b.1.total_lines.cmp(&a.1.total_lines).then(a.0.cmp(b.0)));
+            let top: Vec<String> = languages.iter().take(3)
+                .map(|(language, lang_stats)| {
+                    let share = format!("{:.1}%", lang_stats.total_lines as f64 / root.total_lines.max(1) as f64 * 100.0);
+                    format!("{} {}", language, share)
+                })
+                .collect();
+            println!("  ▶️ {} {} files | {} lines ({} code | {} comments | {} blank) | {}",
+                root.path.display().to_string().bright_white().bold(),
+                root.total_files.to_string().bright_cyan(),
+                root.total_lines.to_string().bright_green(),
+                languages.iter().map(|(_, s)| s.code_lines).sum::<u64>(),
+                languages.iter().map(|(_, s)| s.comment_lines).sum::<u64>(),
+                languages.iter().map(|(_, s)| s.blank_lines).sum::<u64>(),
+                top.join(", ")
             );
         }
     }
 
+    if !stats.generated_languages.is_empty() {
+        let mut generated: Vec<(&String, &LanguageStats)> = stats.generated_languages.iter().collect();
+        generated.sort_by(|a, b| b.1.total_lines.cmp(&a.1.total_lines).then(a.0.cmp(b.0)));
+
+        println!("\n{} Generated & Vendored", "🏭".bright_yellow().bold());
+        println!("{}", "─".repeat(110).bright_black());
+        for (language, lang_stats) in generated {
+            println!("  {} {} files | {} lines ({} code | {} comments | {} blank)",
+                language.bright_white(),
+                lang_stats.files.to_string().bright_cyan(),
+                lang_stats.total_lines.to_string().bright_green(),
+                lang_stats.code_lines,
+                lang_stats.comment_lines,
+                lang_stats.blank_lines
+            );
+        }
+        if args.verbose {
+            for file in &stats.generated_files {
+                if let Some(generated) = &file.generated {
+                    println!("  {:<14} {} ({})",
+                        generated.kind.label().bright_yellow(),
+                        file.path.display().to_string().bright_black(),
+                        generated.reason
+                    );
+                }
+            }
+        }
+    }
+
+    if args.encoding {
+        let mut encodings: HashMap<&str, usize> = HashMap::new();
+        for file in &stats.files_info {
+            *encodings.entry(file.encoding.as_str()).or_default() += 1;
+        }
+        let mut encodings: Vec<(&str, usize)> = encodings.into_iter().collect();
+        encodings.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        println!("\n{} Encodings", "🔤".bright_blue().bold());
+        println!("{}", "─".repeat(110).bright_black());
+        for (encoding, count) in encodings {
+            let note = if is_utf8_encoding(encoding) { "" } else { " (decoded, counted)" };
+            println!("  {} {} files{}", encoding.bright_white(), count.to_string().bright_cyan(), note.bright_black());
+        }
+        for file in stats.skipped_files.iter().filter(|f| f.reason == SkipReason::Binary) {
+            println!("  🚫 {} (binary, skipped)", file.path.display().to_string().bright_black());
+        }
+    }
+
+    if args.show_skipped && !(stats.skipped_files.is_empty() && stats.skipped_dirs.is_empty()) {
+        print_skipped_files(&stats.skipped_files, &stats.skipped_dirs);
+    }
+
+    if args.group_by_dir && !stats.directory_stats.is_empty() {
+        println!("\n{} Directory Breakdown", "📁".bright_blue().bold());
+        println!("{}", "─".repeat(110).bright_black());
+
+        for (depth, key) in directory_tree_order(stats, args) {
+            let dir_stats = &stats.directory_stats[key];
+            let name = if depth == 0 {
+                key.clone()
+            } else {
+                format!("{}/", key.rsplit('/').next().unwrap_or(key))
+            };
+            println!("  {}{} {} files | {} lines | {} code | {} comments | {} blank",
+                "  ".repeat(depth),
+                name.bright_white().bold(),
+                dir_stats.files.to_string().bright_cyan(),
+                dir_stats.total_lines.to_string().bright_green(),
+                dir_stats.code_lines,
+                dir_stats.comment_lines,
+                dir_stats.blank_lines
+            );
+        }
+    }
+
+    // Show top files if requested
+    if let Some(ref metric) = args.top_files {
+        show_top_files_enhanced(stats, metric);
+    }
+
+    // Show hotspots if requested (improved)
+    if args.hotspots && !stats.hotspots.is_empty() {
+        println!("\n{} Code Hotspots & Risk Analysis", "🔥".bright_red().bold());
+        println!("{}", "─".repeat(110).bright_black());
+        println!("  Files requiring attention based on complexity, size, and technical debt:\n");
+        
+        for (i, hotspot) in stats.hotspots.iter().enumerate() {
+            let risk_indicator = match i {
+                0..=2 => "🔴",  // High risk
+                3..=6 => "🟡",  // Medium risk
+                _ => "🟠",      // Lower risk
+            };
+            
+            println!("  {} {}. {} | {} lines | {:.3} complexity | {} TODOs | {:.1} MI | {:.1} CC{}", 
+                risk_indicator,
+                (i + 1).to_string().bright_white(),
+                hotspot.path.display().to_string().bright_red(),
+                hotspot.lines.to_string().bright_white(),
+                hotspot.complexity,
+                hotspot.todos.to_string().bright_yellow(),
+                hotspot.maintainability_index,
+                hotspot.cyclomatic_complexity,
+                hotspot.churn.as_ref().map(|churn| format!(" | 🔁 {} commits +{}/-{} by {} authors (score {:.1})",
+                    churn.commits.to_string().bright_magenta(),
+                    churn.lines_added,
+                    churn.lines_deleted,
+                    churn.authors,
+                    churn.score
+                )).unwrap_or_default()
+            );
+        }
+    }
+
+    if let Some(ref duplication) = stats.duplication {
+        println!("\n{} Duplicate Code Detection", "🧬".bright_magenta().bold());
+        println!("{}", "─".repeat(110).bright_black());
+        println!("  {} clone pairs | {} of {} code lines duplicated (blocks of {}+ lines)\n",
+            duplication.clone_pairs.len().to_string().bright_white(),
+            duplication.duplicated_lines.to_string().bright_yellow(),
+            duplication.analyzed_lines.to_string().bright_white(),
+            duplication.min_block_lines
+        );
+
+        let shown = if args.verbose { duplication.clone_pairs.len() } else { 20 };
+        for (i, pair) in duplication.clone_pairs.iter().take(shown).enumerate() {
+            println!("  🔁 {}. {} lines | {}:{}-{} ⇄ {}:{}-{}",
+                (i + 1).to_string().bright_white(),
+                pair.lines.to_string().bright_yellow(),
+                pair.first.path.display().to_string().bright_cyan(),
+                pair.first.start_line,
+                pair.first.end_line,
+                pair.second.path.display().to_string().bright_cyan(),
+                pair.second.start_line,
+                pair.second.end_line
+            );
+        }
+        if duplication.clone_pairs.len() > shown {
+            println!("  … {} more (use --verbose to list all)", duplication.clone_pairs.len() - shown);
+        }
+    }
+
+    if let Some(ref comparison) = stats.baseline {
+        print_baseline_comparison(comparison, args);
+    }
+
+    if !stats.policy_violations.is_empty() {
+        println!("\n{} Policy Violations", "🚦".bright_red().bold());
+        println!("{}", "─".repeat(110).bright_black());
+        for violation in &stats.policy_violations {
+            println!("  {} {}", "❌".bright_red(), violation.describe());
+        }
+    }
+
+    if args.verbose {
+        let mut disambiguated: Vec<&FileInfo> = stats.files_info.iter()
+            .filter(|f| f.language_detection.is_some())
+            .collect();
+        if !disambiguated.is_empty() {
+            disambiguated.sort_by(|a, b| a.path.cmp(&b.path));
+            println!("\n{} Language Disambiguation", "🔎".bright_cyan().bold());
+            println!("{}", "─".repeat(110).bright_black());
+            for file in disambiguated {
+                println!("  {} {}",
+                    file.path.display().to_string().bright_white(),
+                    file.language_detection.as_deref().unwrap_or_default().bright_black()
+                );
+            }
+        }
+    }
+
     println!("\n{}", "─".repeat(110).bright_black());
     println!("{} LOCO Analysis completed successfully! 🎉", "✅".bright_green().bold());
     println!("📈 Processed {} files, {} lines in {:.3}s", 
@@ -2250,35 +5783,1083 @@ fn print_results_optimized(stats: &ProjectStats, args: &Args) {
     );
 }
 
-fn main() {
-    let args = Args::parse();
+// Old and new value of a count in `loco diff`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Delta {
+    old: u64,
+    new: u64,
+    change: i64,
+}
 
-    if !args.path.exists() {
-        eprintln!("❌ Path does not exist: {}", args.path.display());
-        std::process::exit(1);
+impl Delta {
+    fn new(old: u64, new: u64) -> Self {
+        Delta { old, new, change: new as i64 - old as i64 }
     }
+}
 
-    // Enhanced thread management for optimal performance
-    let optimal_threads = if args.threads > 0 {
-        args.threads
-    } else if args.very_fast {
-        // Very fast mode: maximize parallelization
-        let cpu_cores = num_cpus::get();
-        std::cmp::min(cpu_cores * 4, 64) // Aggressive threading
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct FloatDelta {
+    old: f64,
+    new: f64,
+    change: f64,
+}
+
+impl FloatDelta {
+    fn new(old: f64, new: f64) -> Self {
+        FloatDelta { old, new, change: new - old }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LineDeltas {
+    total_lines: Delta,
+    code_lines: Delta,
+    comment_lines: Delta,
+    blank_lines: Delta,
+}
+
+impl LineDeltas {
+    fn new(old: [u64; 4], new: [u64; 4]) -> Self {
+        LineDeltas {
+            total_lines: Delta::new(old[0], new[0]),
+            code_lines: Delta::new(old[1], new[1]),
+            comment_lines: Delta::new(old[2], new[2]),
+            blank_lines: Delta::new(old[3], new[3]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LanguageDiff {
+    language: String,
+    files: Delta,
+    #[serde(flatten)]
+    lines: LineDeltas,
+    todos: Delta,
+    fixmes: Delta,
+    cyclomatic_complexity: FloatDelta,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FileChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl FileChange {
+    fn label(self) -> &'static str {
+        match self {
+            FileChange::Added => "added",
+            FileChange::Removed => "removed",
+            FileChange::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FileDiff {
+    path: PathBuf,
+    language: String,
+    status: FileChange,
+    #[serde(flatten)]
+    lines: LineDeltas,
+    todos: Delta,
+    cyclomatic_complexity: FloatDelta,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DiffReport {
+    old: String,
+    new: String,
+    files: Delta,
+    files_added: usize,
+    files_removed: usize,
+    files_modified: usize,
+    #[serde(flatten)]
+    totals: LineDeltas,
+    todos: Delta,
+    fixmes: Delta,
+    // Only languages and files whose counts changed
+    languages: Vec<LanguageDiff>,
+    changed_files: Vec<FileDiff>,
+}
+
+// One side of `loco diff`: an existing directory is scanned as a tree, anything else is read as
+// a revision of the repository at --path
+fn diff_side_args(args: &Args, side: &str) -> Args {
+    let mut side_args = args.clone();
+    side_args.command = None;
+    side_args.content_hashes = true;
+    if Path::new(side).exists() {
+        side_args.path = vec![PathBuf::from(side)];
+        side_args.git_ref = None;
     } else {
-        // Intelligent auto-detection based on workload
-        let cpu_cores = num_cpus::get();
-        let physical_cores = num_cpus::get_physical();
-        
-        // For I/O bound work like file processing, use more threads than cores
-        std::cmp::max(std::cmp::min(cpu_cores * 2, 32), physical_cores)
+        side_args.git_ref = Some(side.to_string());
+    }
+    side_args
+}
+
+fn language_lines(stats: &LanguageStats) -> [u64; 4] {
+    [stats.total_lines, stats.code_lines, stats.comment_lines, stats.blank_lines]
+}
+
+fn file_lines(file: &FileInfo) -> [u64; 4] {
+    [file.lines, file.code_lines, file.comment_lines, file.blank_lines]
+}
+
+// Files are matched by their path below the scan root, so two checkouts in different places
+// (or one tree at two revisions) line up
+fn comparison_key(path: &Path, roots: &[PathBuf]) -> PathBuf {
+    match roots {
+        [root] => path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn files_by_key(files: &[FileInfo], roots: &[PathBuf]) -> HashMap<PathBuf, FileInfo> {
+    files.iter()
+        .map(|file| (comparison_key(&file.path, roots), file.clone()))
+        .collect()
+}
+
+fn language_sum(stats: &ProjectStats, field: fn(&LanguageStats) -> u64) -> u64 {
+    stats.languages.values().map(field).sum()
+}
+
+fn line_totals(stats: &ProjectStats) -> [u64; 4] {
+    [
+        stats.total_lines,
+        language_sum(stats, |s| s.code_lines),
+        language_sum(stats, |s| s.comment_lines),
+        language_sum(stats, |s| s.blank_lines),
+    ]
+}
+
+fn diff_projects(old_label: &str, new_label: &str, old: (&ProjectStats, &Args), new: (&ProjectStats, &Args)) -> DiffReport {
+    let (old_stats, new_stats) = (old.0, new.0);
+
+    let mut language_names: Vec<&String> = old_stats.languages.keys().chain(new_stats.languages.keys()).collect();
+    language_names.sort();
+    language_names.dedup();
+    let empty = LanguageStats::default();
+    let languages: Vec<LanguageDiff> = language_names.into_iter()
+        .filter_map(|language| {
+            let before = old_stats.languages.get(language).unwrap_or(&empty);
+            let after = new_stats.languages.get(language).unwrap_or(&empty);
+            let unchanged = language_lines(before) == language_lines(after)
+                && before.files == after.files
+                && before.todos == after.todos
+                && before.fixmes == after.fixmes
+                && before.cyclomatic_complexity == after.cyclomatic_complexity;
+            if unchanged {
+                return None;
+            }
+            Some(LanguageDiff {
+                language: language.clone(),
+                files: Delta::new(before.files, after.files),
+                lines: LineDeltas::new(language_lines(before), language_lines(after)),
+                todos: Delta::new(before.todos, after.todos),
+                fixmes: Delta::new(before.fixmes, after.fixmes),
+                cyclomatic_complexity: FloatDelta::new(before.cyclomatic_complexity, after.cyclomatic_complexity),
+            })
+        })
+        .collect();
+
+    let old_files = files_by_key(&old_stats.files_info, &dedupe_roots(&old.1.path).0);
+    let new_files = files_by_key(&new_stats.files_info, &dedupe_roots(&new.1.path).0);
+    let mut paths: Vec<&PathBuf> = old_files.keys().chain(new_files.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut changed_files: Vec<FileDiff> = paths.into_iter()
+        .filter_map(|path| {
+            let (before, after) = (old_files.get(path), new_files.get(path));
+            let status = match (before, after) {
+                (None, Some(_)) => FileChange::Added,
+                (Some(_), None) => FileChange::Removed,
+                (Some(before), Some(after)) => {
+                    // Same bytes (or, without hashes, the same counts) under the same language
+                    let same_content = match (before.content_hash, after.content_hash) {
+                        (Some(old_hash), Some(new_hash)) => old_hash == new_hash,
+                        _ => file_lines(before) == file_lines(after)
+                            && before.size == after.size
+                            && before.todos == after.todos,
+                    };
+                    if same_content && before.language == after.language {
+                        return None;
+                    }
+                    FileChange::Modified
+                },
+                (None, None) => return None,
+            };
+            let lines = |file: Option<&FileInfo>| file.map(file_lines).unwrap_or_default();
+            Some(FileDiff {
+                path: path.clone(),
+                language: after.or(before).map(|file| file.language.clone()).unwrap_or_default(),
+                status,
+                lines: LineDeltas::new(lines(before), lines(after)),
+                todos: Delta::new(before.map_or(0, |f| f.todos), after.map_or(0, |f| f.todos)),
+                cyclomatic_complexity: FloatDelta::new(
+                    before.map_or(0.0, |f| f.cyclomatic_complexity),
+                    after.map_or(0.0, |f| f.cyclomatic_complexity),
+                ),
+            })
+        })
+        .collect();
+    // Biggest changes first
+    changed_files.sort_by(|a, b| {
+        b.lines.total_lines.change.abs().cmp(&a.lines.total_lines.change.abs()).then_with(|| a.path.cmp(&b.path))
+    });
+
+    let count = |status: FileChange| changed_files.iter().filter(|f| f.status == status).count();
+
+    DiffReport {
+        old: old_label.to_string(),
+        new: new_label.to_string(),
+        files: Delta::new(old_stats.total_files, new_stats.total_files),
+        files_added: count(FileChange::Added),
+        files_removed: count(FileChange::Removed),
+        files_modified: count(FileChange::Modified),
+        totals: LineDeltas::new(line_totals(old_stats), line_totals(new_stats)),
+        todos: Delta::new(language_sum(old_stats, |s| s.todos), language_sum(new_stats, |s| s.todos)),
+        fixmes: Delta::new(language_sum(old_stats, |s| s.fixmes), language_sum(new_stats, |s| s.fixmes)),
+        languages,
+        changed_files,
+    }
+}
+
+fn signed(change: i64) -> String {
+    if change > 0 { format!("+{}", change) } else { change.to_string() }
+}
+
+fn signed_float(change: f64) -> String {
+    if change > 0.0 { format!("+{:.2}", change) } else { format!("{:.2}", change) }
+}
+
+fn colored_change(change: i64) -> ColoredString {
+    match change.cmp(&0) {
+        std::cmp::Ordering::Greater => signed(change).bright_green(),
+        std::cmp::Ordering::Less => signed(change).bright_red(),
+        std::cmp::Ordering::Equal => signed(change).bright_black(),
+    }
+}
+
+fn print_diff(report: &DiffReport, args: &Args) {
+    println!("{}", format!("🔀 LOCO Diff: {} → {}", report.old, report.new).bright_cyan().bold());
+    println!("{}", "═".repeat(80).bright_black());
+
+    println!("\n{} Summary", "📊".bright_magenta().bold());
+    println!("  📁 {} → {} files ({}): {} added, {} removed, {} modified",
+        report.files.old, report.files.new, colored_change(report.files.change),
+        report.files_added.to_string().bright_green(),
+        report.files_removed.to_string().bright_red(),
+        report.files_modified.to_string().bright_yellow()
+    );
+    println!("  📏 {} → {} lines ({}) | code {} | comments {} | blank {}",
+        report.totals.total_lines.old, report.totals.total_lines.new,
+        colored_change(report.totals.total_lines.change),
+        colored_change(report.totals.code_lines.change),
+        colored_change(report.totals.comment_lines.change),
+        colored_change(report.totals.blank_lines.change)
+    );
+    println!("  📝 {} → {} TODOs ({}) | {} → {} FIXMEs ({})",
+        report.todos.old, report.todos.new, colored_change(report.todos.change),
+        report.fixmes.old, report.fixmes.new, colored_change(report.fixmes.change)
+    );
+
+    if !report.languages.is_empty() {
+        println!("\n{} Languages", "🔤".bright_blue().bold());
+        for language in &report.languages {
+            println!("  {:<20} {:>6} files ({}) | {:>8} lines ({}) | code {} | comments {} | blank {} | complexity {} | TODOs {}",
+                language.language,
+                language.files.new, colored_change(language.files.change),
+                language.lines.total_lines.new, colored_change(language.lines.total_lines.change),
+                colored_change(language.lines.code_lines.change),
+                colored_change(language.lines.comment_lines.change),
+                colored_change(language.lines.blank_lines.change),
+                signed_float(language.cyclomatic_complexity.change),
+                colored_change(language.todos.change)
+            );
+        }
+    }
+
+    if !report.changed_files.is_empty() {
+        println!("\n{} Changed Files", "📄".bright_green().bold());
+        let shown = if args.verbose { report.changed_files.len() } else { 20 };
+        for file in report.changed_files.iter().take(shown) {
+            let marker = match file.status {
+                FileChange::Added => "➕",
+                FileChange::Removed => "➖",
+                FileChange::Modified => "✏️",
+            };
+            println!("  {} {:<9} {} {} lines (code {}, comments {}, blank {}){}",
+                marker,
+                file.status.label(),
+                file.path.display().to_string().bright_white(),
+                colored_change(file.lines.total_lines.change),
+                signed(file.lines.code_lines.change),
+                signed(file.lines.comment_lines.change),
+                signed(file.lines.blank_lines.change),
+                if file.todos.change != 0 { format!(", TODOs {}", signed(file.todos.change)) } else { String::new() }
+            );
+        }
+        if report.changed_files.len() > shown {
+            println!("  ... {} more (--verbose to list all)", report.changed_files.len() - shown);
+        }
+    }
+}
+
+fn generate_diff_markdown(report: &DiffReport) -> String {
+    let cell = |delta: &Delta| format!("{} ({})", delta.new, signed(delta.change));
+    let mut md = String::new();
+
+    md.push_str(&format!("# 🔀 LOCO Diff: `{}` → `{}`\n\n", report.old, report.new));
+    md.push_str("| Metric | Old | New | Change |\n|---|---:|---:|---:|\n");
+    for (label, delta) in [
+        ("Files", &report.files),
+        ("Lines", &report.totals.total_lines),
+        ("Code", &report.totals.code_lines),
+        ("Comments", &report.totals.comment_lines),
+        ("Blank", &report.totals.blank_lines),
+        ("TODOs", &report.todos),
+        ("FIXMEs", &report.fixmes),
+    ] {
+        md.push_str(&format!("| {} | {} | {} | {} |\n", label, delta.old, delta.new, signed(delta.change)));
+    }
+    md.push_str(&format!("\n{} files added, {} removed, {} modified.\n\n",
+        report.files_added, report.files_removed, report.files_modified));
+
+    if !report.languages.is_empty() {
+        md.push_str("## 🔤 Languages\n\n");
+        md.push_str("| Language | Files | Lines | Code | Comments | Blank | Complexity | TODOs |\n");
+        md.push_str("|---|---:|---:|---:|---:|---:|---:|---:|\n");
+        for language in &report.languages {
+            md.push_str(&format!("| {} | {} | {} | {} | {} | {} | {:.2} ({}) | {} |\n",
+                markdown_escape(&language.language),
+                cell(&language.files),
+                cell(&language.lines.total_lines),
+                cell(&language.lines.code_lines),
+                cell(&language.lines.comment_lines),
+                cell(&language.lines.blank_lines),
+                language.cyclomatic_complexity.new,
+                signed_float(language.cyclomatic_complexity.change),
+                cell(&language.todos)
+            ));
+        }
+        md.push('\n');
+    }
+
+    if !report.changed_files.is_empty() {
+        md.push_str("## 📄 Changed Files\n\n");
+        md.push_str("| File | Status | Language | Lines | Code | Comments | Blank | Complexity | TODOs |\n");
+        md.push_str("|---|---|---|---:|---:|---:|---:|---:|---:|\n");
+        for file in &report.changed_files {
+            md.push_str(&format!("| {} | {} | {} | {} | {} | {} | {} | {} | {} |\n",
+                markdown_escape(&file.path.display().to_string()),
+                file.status.label(),
+                markdown_escape(&file.language),
+                cell(&file.lines.total_lines),
+                signed(file.lines.code_lines.change),
+                signed(file.lines.comment_lines.change),
+                signed(file.lines.blank_lines.change),
+                signed_float(file.cyclomatic_complexity.change),
+                signed(file.todos.change)
+            ));
+        }
+        md.push('\n');
+    }
+
+    md
+}
+
+// Bumped whenever the snapshot layout changes incompatibly
+const BASELINE_VERSION: u32 = 1;
+
+// Exit status when the run regresses beyond a --baseline tolerance
+const EXIT_BASELINE_REGRESSION: i32 = 3;
+
+// Exit status when a --fail-if/--policy rule is broken
+const EXIT_POLICY_VIOLATION: i32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Baseline {
+    version: u32,
+    created_at: String,
+    // The scan roots, so file paths can be matched from another checkout
+    roots: Vec<PathBuf>,
+    stats: ProjectStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MaintainabilityDrop {
+    path: PathBuf,
+    maintainability_index: FloatDelta,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BaselineComparison {
+    baseline: PathBuf,
+    created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revision: Option<GitRevision>,
+    files: Delta,
+    #[serde(flatten)]
+    lines: LineDeltas,
+    line_growth_percent: f64,
+    maintainability: FloatDelta,
+    // Files in both runs whose maintainability index went down, largest drop first
+    maintainability_drops: Vec<MaintainabilityDrop>,
+    // Hotspots of this run that were not hotspots in the baseline
+    new_hotspots: Vec<PathBuf>,
+    todos: Delta,
+    fixmes: Delta,
+    // Tolerances this run exceeds
+    violations: Vec<String>,
+}
+
+fn save_baseline(stats: &ProjectStats, path: &Path, args: &Args) -> Result<(), String> {
+    let mut stats = stats.clone();
+    stats.baseline = None;
+    let baseline = Baseline {
+        version: BASELINE_VERSION,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        roots: dedupe_roots(&args.path).0,
+        stats,
     };
+    let json = serde_json::to_string_pretty(&baseline).map_err(|e| e.to_string())?;
+    fs::write(path, json).map_err(|e| format!("{}: {}", path.display(), e))
+}
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(optimal_threads)
-        .build_global()
-        .unwrap();
+fn load_baseline(path: &Path) -> Result<Baseline, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    // Check the version first so an old snapshot gets a clear message rather than a field error
+    let version = serde_json::from_str::<serde_json::Value>(&content)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .get("version")
+        .and_then(|version| version.as_u64());
+    if version != Some(BASELINE_VERSION as u64) {
+        return Err(format!("{}: snapshot format {} is not supported (expected {}), save it again with --save-baseline",
+            path.display(),
+            version.map_or_else(|| "unknown".to_string(), |version| version.to_string()),
+            BASELINE_VERSION
+        ));
+    }
+    serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn compare_with_baseline(stats: &ProjectStats, baseline: &Baseline, baseline_path: &Path, args: &Args) -> BaselineComparison {
+    let before = &baseline.stats;
+    let roots = dedupe_roots(&args.path).0;
+    let lines = LineDeltas::new(line_totals(before), line_totals(stats));
+    let line_growth_percent = if before.total_lines > 0 {
+        lines.total_lines.change as f64 / before.total_lines as f64 * 100.0
+    } else {
+        0.0
+    };
+    let maintainability = FloatDelta::new(
+        before.quality_metrics.overall_maintainability,
+        stats.quality_metrics.overall_maintainability,
+    );
+
+    let old_files = files_by_key(&before.files_info, &baseline.roots);
+    let mut maintainability_drops: Vec<MaintainabilityDrop> = stats.files_info.iter()
+        .filter_map(|file| {
+            let path = comparison_key(&file.path, &roots);
+            let old = old_files.get(&path)?;
+            (file.maintainability_index < old.maintainability_index).then(|| MaintainabilityDrop {
+                path,
+                maintainability_index: FloatDelta::new(old.maintainability_index, file.maintainability_index),
+            })
+        })
+        .collect();
+    maintainability_drops.sort_by(|a, b| {
+        a.maintainability_index.change.partial_cmp(&b.maintainability_index.change).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    // Hotspots are recomputed for both runs, so the comparison works without --hotspots
+    let old_hotspots: std::collections::HashSet<PathBuf> = detect_hotspots_improved(&before.files_info).iter()
+        .map(|file| comparison_key(&file.path, &baseline.roots))
+        .collect();
+    let new_hotspots: Vec<PathBuf> = detect_hotspots_improved(&stats.files_info).iter()
+        .map(|file| comparison_key(&file.path, &roots))
+        .filter(|path| !old_hotspots.contains(path))
+        .collect();
+
+    let todos = Delta::new(language_sum(before, |s| s.todos), language_sum(stats, |s| s.todos));
+    let fixmes = Delta::new(language_sum(before, |s| s.fixmes), language_sum(stats, |s| s.fixmes));
+
+    let mut violations = Vec::new();
+    if let Some(limit) = args.max_line_growth {
+        if line_growth_percent > limit {
+            violations.push(format!("lines grew by {:.1}% (limit {}%)", line_growth_percent, limit));
+        }
+    }
+    if let Some(limit) = args.max_maintainability_drop {
+        if -maintainability.change > limit {
+            violations.push(format!("maintainability dropped by {:.1} points (limit {})", -maintainability.change, limit));
+        }
+    }
+    if let Some(limit) = args.max_new_hotspots {
+        if new_hotspots.len() > limit {
+            violations.push(format!("{} new hotspots (limit {})", new_hotspots.len(), limit));
+        }
+    }
+    if let Some(limit) = args.max_todo_increase {
+        let increase = todos.change + fixmes.change;
+        if increase > limit as i64 {
+            violations.push(format!("TODOs and FIXMEs rose by {} (limit {})", increase, limit));
+        }
+    }
+
+    BaselineComparison {
+        baseline: baseline_path.to_path_buf(),
+        created_at: baseline.created_at.clone(),
+        revision: before.revision.clone(),
+        files: Delta::new(before.total_files, stats.total_files),
+        lines,
+        line_growth_percent,
+        maintainability,
+        maintainability_drops,
+        new_hotspots,
+        todos,
+        fixmes,
+        violations,
+    }
+}
+
+fn print_baseline_comparison(comparison: &BaselineComparison, args: &Args) {
+    println!("\n{} Baseline Comparison", "📐".bright_blue().bold());
+    println!("{}", "─".repeat(110).bright_black());
+    println!("  📌 against {} ({}{})",
+        comparison.baseline.display().to_string().bright_white(),
+        comparison.created_at,
+        comparison.revision.as_ref()
+            .map(|revision| format!(", {} at {}", revision.name, &revision.commit[..revision.commit.len().min(12)]))
+            .unwrap_or_default()
+    );
+    println!("  📁 {} → {} files ({})",
+        comparison.files.old, comparison.files.new, colored_change(comparison.files.change));
+    println!("  📏 {} → {} lines ({}, {}%) | code {} | comments {} | blank {}",
+        comparison.lines.total_lines.old, comparison.lines.total_lines.new,
+        colored_change(comparison.lines.total_lines.change),
+        signed_float(comparison.line_growth_percent),
+        colored_change(comparison.lines.code_lines.change),
+        colored_change(comparison.lines.comment_lines.change),
+        colored_change(comparison.lines.blank_lines.change)
+    );
+    let maintainability = format!("{:.1} → {:.1} ({})",
+        comparison.maintainability.old,
+        comparison.maintainability.new,
+        signed_float(comparison.maintainability.change)
+    );
+    println!("  🛠️ maintainability {}", if comparison.maintainability.change < 0.0 {
+        maintainability.bright_red()
+    } else {
+        maintainability.bright_green()
+    });
+    println!("  📝 {} → {} TODOs ({}) | {} → {} FIXMEs ({})",
+        comparison.todos.old, comparison.todos.new, colored_change(comparison.todos.change),
+        comparison.fixmes.old, comparison.fixmes.new, colored_change(comparison.fixmes.change)
+    );
+
+    if !comparison.new_hotspots.is_empty() {
+        println!("  🔥 {} new hotspots:", comparison.new_hotspots.len().to_string().bright_red());
+        for path in &comparison.new_hotspots {
+            println!("     {}", path.display().to_string().bright_red());
+        }
+    }
+    if !comparison.maintainability_drops.is_empty() {
+        let shown = if args.verbose { comparison.maintainability_drops.len() } else { 10 };
+        println!("  📉 {} files lost maintainability:", comparison.maintainability_drops.len().to_string().bright_yellow());
+        for drop in comparison.maintainability_drops.iter().take(shown) {
+            println!("     {} {:.1} → {:.1} ({})",
+                drop.path.display().to_string().bright_white(),
+                drop.maintainability_index.old,
+                drop.maintainability_index.new,
+                signed_float(drop.maintainability_index.change).bright_red()
+            );
+        }
+        if comparison.maintainability_drops.len() > shown {
+            println!("     ... {} more (--verbose to list all)", comparison.maintainability_drops.len() - shown);
+        }
+    }
+    for violation in &comparison.violations {
+        println!("  {} {}", "❌".bright_red(), violation.bright_red());
+    }
+}
+
+// Metrics a --fail-if rule can test. The per-file ones are checked against every file and
+// report each offender; the rest are project totals.
+const POLICY_METRICS: &[&str] = &[
+    "max-file-lines", "max-file-todos", "max-complexity", "max-cyclomatic", "min-file-maintainability",
+    "max-lines", "max-files", "max-todos", "max-fixmes", "min-comment-ratio", "min-maintainability",
+    "max-debt-ratio", "hotspots",
+];
+
+#[derive(Debug, Clone, Copy)]
+enum PolicyOp {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl PolicyOp {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            PolicyOp::Greater => value > threshold,
+            PolicyOp::GreaterOrEqual => value >= threshold,
+            PolicyOp::Less => value < threshold,
+            PolicyOp::LessOrEqual => value <= threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PolicyRule {
+    rule: String,
+    metric: &'static str,
+    op: PolicyOp,
+    threshold: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PolicyViolation {
+    rule: String,
+    value: f64,
+    // The offending file for per-file metrics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    // The files behind a count, such as the hotspots
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    files: Vec<PathBuf>,
+}
+
+impl PolicyViolation {
+    fn describe(&self) -> String {
+        let value = if self.value.fract() == 0.0 { format!("{}", self.value) } else { format!("{:.2}", self.value) };
+        match &self.path {
+            Some(path) => format!("{}: {} ({})", self.rule, path.display(), value),
+            None if self.files.is_empty() => format!("{}: {}", self.rule, value),
+            None => format!("{}: {} ({})", self.rule, value,
+                self.files.iter().map(|file| file.display().to_string()).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(rename = "fail-if", default)]
+    fail_if: Vec<String>,
+}
+
+fn parse_policy_rule(rule: &str) -> Result<PolicyRule, String> {
+    let (at, op, op_len) = [(">=", PolicyOp::GreaterOrEqual), ("<=", PolicyOp::LessOrEqual), (">", PolicyOp::Greater), ("<", PolicyOp::Less)]
+        .iter()
+        .filter_map(|(symbol, op)| rule.find(symbol).map(|at| (at, *op, symbol.len())))
+        .min_by_key(|(at, _, len)| (*at, std::cmp::Reverse(*len)))
+        .ok_or_else(|| format!("'{}': expected METRIC>VALUE or METRIC<VALUE", rule))?;
+    let name = rule[..at].trim();
+    let metric = POLICY_METRICS.iter()
+        .find(|metric| **metric == name)
+        .ok_or_else(|| format!("'{}': unknown metric '{}' (known: {})", rule, name, POLICY_METRICS.join(", ")))?;
+    // NaN never compares true, so a NaN (or infinite) threshold would pass every run
+    let threshold = rule[at + op_len..].trim().trim_end_matches('%').parse::<f64>().ok()
+        .filter(|threshold| threshold.is_finite())
+        .ok_or_else(|| format!("'{}': '{}' is not a number", rule, rule[at + op_len..].trim()))?;
+    Ok(PolicyRule {
+        rule: rule.split_whitespace().collect(),
+        metric,
+        op,
+        threshold,
+    })
+}
+
+// Rules from the --policy file come first, then each --fail-if
+fn load_policy(args: &Args) -> Result<Vec<PolicyRule>, String> {
+    let mut rules = Vec::new();
+    if let Some(path) = &args.policy {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let file: PolicyFile = if is_json {
+            serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?
+        } else {
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+        for rule in &file.fail_if {
+            rules.push(parse_policy_rule(rule).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+    }
+    for rule in &args.fail_if {
+        rules.push(parse_policy_rule(rule)?);
+    }
+    Ok(rules)
+}
+
+fn check_policy(rules: &[PolicyRule], stats: &ProjectStats) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+    for rule in rules {
+        let file_metric: Option<fn(&FileInfo) -> f64> = match rule.metric {
+            "max-file-lines" => Some(|file| file.lines as f64),
+            "max-file-todos" => Some(|file| file.todos as f64),
+            "max-complexity" => Some(|file| file.complexity),
+            "max-cyclomatic" => Some(|file| file.cyclomatic_complexity),
+            "min-file-maintainability" => Some(|file| file.maintainability_index),
+            _ => None,
+        };
+        if let Some(metric) = file_metric {
+            let mut offenders: Vec<&FileInfo> = stats.files_info.iter()
+                .filter(|file| rule.op.holds(metric(file), rule.threshold))
+                .collect();
+            offenders.sort_by(|a, b| a.path.cmp(&b.path));
+            violations.extend(offenders.into_iter().map(|file| PolicyViolation {
+                rule: rule.rule.clone(),
+                value: metric(file),
+                path: Some(file.path.clone()),
+                files: Vec::new(),
+            }));
+            continue;
+        }
+
+        let mut files = Vec::new();
+        let value = match rule.metric {
+            "max-lines" => stats.total_lines as f64,
+            "max-files" => stats.total_files as f64,
+            "max-todos" => language_sum(stats, |s| s.todos) as f64,
+            "max-fixmes" => language_sum(stats, |s| s.fixmes) as f64,
+            "min-comment-ratio" if stats.total_lines > 0 => {
+                language_sum(stats, |s| s.comment_lines) as f64 / stats.total_lines as f64 * 100.0
+            },
+            "min-comment-ratio" => 0.0,
+            "min-maintainability" => stats.quality_metrics.overall_maintainability,
+            "max-debt-ratio" => stats.quality_metrics.technical_debt_ratio,
+            "hotspots" => {
+                // Recomputed so the rule works without --hotspots
+                files = detect_hotspots_improved(&stats.files_info).into_iter().map(|file| file.path).collect();
+                files.len() as f64
+            },
+            _ => unreachable!("metric names are checked when the rule is parsed"),
+        };
+        if rule.op.holds(value, rule.threshold) {
+            violations.push(PolicyViolation { rule: rule.rule.clone(), value, path: None, files });
+        }
+    }
+    violations
+}
+
+fn run_diff(args: &Args, old: &str, new: &str) {
+    if matches!(args.format.as_str(), "csv" | "xml" | "html") {
+        eprintln!("❌ loco diff writes text, json or markdown output, not {}", args.format);
+        std::process::exit(1);
+    }
+
+    let analyze_side = |side: &str| {
+        let side_args = diff_side_args(args, side);
+        status!(args, "🔀 Analyzing {}", side.bright_white());
+        // A side without any matching files compares as empty
+        let stats = analyze_project(&side_args).unwrap_or_default();
+        (stats, side_args)
+    };
+    let (old_stats, old_args) = analyze_side(old);
+    let (new_stats, new_args) = analyze_side(new);
+    let report = diff_projects(old, new, (&old_stats, &old_args), (&new_stats, &new_args));
+
+    match args.format.as_str() {
+        "json" => write_report(&serde_json::to_string_pretty(&report).unwrap(), args, "Diff"),
+        "markdown" | "md" => write_report(&generate_diff_markdown(&report), args, "Markdown diff"),
+        _ => print_diff(&report, args),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct LanguageLines {
+    files: u64,
+    total_lines: u64,
+    code_lines: u64,
+    comment_lines: u64,
+    blank_lines: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct HistoryPoint {
+    revision: String,
+    commit: String,
+    date: String,
+    #[serde(flatten)]
+    lines: LanguageLines,
+    languages: std::collections::BTreeMap<String, LanguageLines>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct HistoryReport {
+    points: Vec<HistoryPoint>,
+}
+
+// `samples` evenly spaced indices into `count` commits, always including the first and last
+fn sample_indices(count: usize, samples: usize) -> Vec<usize> {
+    match (count, samples) {
+        (0, _) | (_, 0) => Vec::new(),
+        (count, samples) if count <= samples => (0..count).collect(),
+        (count, 1) => vec![count - 1],
+        (count, samples) => {
+            let mut picked: Vec<usize> = (0..samples)
+                .map(|i| ((i * (count - 1)) as f64 / (samples - 1) as f64).round() as usize)
+                .collect();
+            picked.dedup();
+            picked
+        },
+    }
+}
+
+// (name, commit, committer date) of the points `loco history` analyzes, oldest first: each tag,
+// or `samples` evenly spaced first-parent commits ending at `end`
+fn history_revisions(dir: &Path, end: &str, samples: usize, tags: bool) -> Result<Vec<(String, String, String)>, String> {
+    let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).to_string();
+    let commit_and_date = |line: &str| line.trim().split_once('\0').map(|(commit, date)| (commit.to_string(), date.to_string()));
+
+    if tags {
+        let names = text(run_git(dir, &["for-each-ref", "--sort=creatordate", "--format=%(refname:short)", "refs/tags"])?);
+        let mut points = Vec::new();
+        for name in names.lines().filter(|name| !name.is_empty()) {
+            // Tags of trees or blobs have no commit to analyze
+            let Ok(log) = run_git(dir, &["log", "-1", "--format=%H%x00%cI", &format!("{}^{{commit}}", name), "--"]) else {
+                continue;
+            };
+            if let Some((commit, date)) = commit_and_date(&text(log)) {
+                points.push((name.to_string(), commit, date));
+            }
+        }
+        return Ok(points);
+    }
+
+    let log = text(run_git(dir, &["log", "--first-parent", "--reverse", "--format=%H%x00%cI", end, "--"])?);
+    let commits: Vec<(String, String)> = log.lines().filter_map(commit_and_date).collect();
+    Ok(sample_indices(commits.len(), samples).into_iter()
+        .map(|i| {
+            let (commit, date) = commits[i].clone();
+            (commit.chars().take(12).collect(), commit, date)
+        })
+        .collect())
+}
+
+fn run_history(args: &Args, samples: usize, tags: bool) {
+    if matches!(args.format.as_str(), "markdown" | "md" | "xml") {
+        eprintln!("❌ loco history writes text, json, csv or html output, not {}", args.format);
+        std::process::exit(1);
+    }
+
+    let root = &args.path[0];
+    let dir = if root.is_dir() { root.as_path() } else { root.parent().unwrap_or(Path::new(".")) };
+    let end = args.git_ref.as_deref().unwrap_or("HEAD");
+    let revisions = history_revisions(dir, end, samples, tags).unwrap_or_else(|e| {
+        eprintln!("❌ Cannot read the history of {}: {}", dir.display(), e);
+        std::process::exit(1);
+    });
+    if revisions.is_empty() {
+        status!(args, "⚠️ No {} to analyze.", if tags { "tags" } else { "commits" });
+        return;
+    }
+
+    let total = revisions.len();
+    let mut points = Vec::new();
+    for (i, (name, commit, date)) in revisions.into_iter().enumerate() {
+        status!(args, "🕰️ [{}/{}] {} ({})", i + 1, total, name.bright_white(), date);
+        let mut point_args = args.clone();
+        point_args.command = None;
+        point_args.git_ref = Some(commit.clone());
+        point_args.files_from = None;
+        // A revision without matching files is a point with no lines
+        let stats = analyze_project(&point_args).unwrap_or_default();
+
+        let line_counts = |s: &LanguageStats| LanguageLines {
+            files: s.files,
+            total_lines: s.total_lines,
+            code_lines: s.code_lines,
+            comment_lines: s.comment_lines,
+            blank_lines: s.blank_lines,
+        };
+        let [total_lines, code_lines, comment_lines, blank_lines] = line_totals(&stats);
+        points.push(HistoryPoint {
+            revision: name,
+            commit,
+            date,
+            lines: LanguageLines { files: stats.total_files, total_lines, code_lines, comment_lines, blank_lines },
+            languages: stats.languages.iter().map(|(language, s)| (language.clone(), line_counts(s))).collect(),
+        });
+    }
+    let report = HistoryReport { points };
+
+    match args.format.as_str() {
+        "json" => write_report(&serde_json::to_string_pretty(&report).unwrap(), args, "History"),
+        "csv" => write_report(&generate_history_csv(&report), args, "History CSV"),
+        "html" => write_report(&generate_history_html(&report), args, "History chart"),
+        _ => print_history(&report),
+    }
+}
+
+fn print_history(report: &HistoryReport) {
+    println!("{}", "🕰️ LOCO History".bright_cyan().bold());
+    println!("{}", "═".repeat(80).bright_black());
+    println!("\n  {:<14} {:<26} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "Revision", "Date", "Files", "Code", "Comments", "Blank", "Total");
+    let mut previous: Option<u64> = None;
+    for point in &report.points {
+        let change = previous.map(|lines| format!(" {}", colored_change(point.lines.total_lines as i64 - lines as i64))).unwrap_or_default();
+        println!("  {:<14} {:<26} {:>8} {:>10} {:>10} {:>10} {:>10}{}",
+            point.revision,
+            point.date,
+            point.lines.files,
+            point.lines.code_lines,
+            point.lines.comment_lines,
+            point.lines.blank_lines,
+            point.lines.total_lines,
+            change
+        );
+        previous = Some(point.lines.total_lines);
+    }
+}
+
+// One row per revision and language, the long format spreadsheets and plotting tools pivot on
+fn generate_history_csv(report: &HistoryReport) -> String {
+    let mut csv = String::from("revision,commit,date,language,files,total_lines,code_lines,comment_lines,blank_lines\n");
+    for point in &report.points {
+        for (language, lines) in &point.languages {
+            csv.push_str(&format!("{},{},{},{},{},{},{},{},{}\n",
+                csv_escape(&point.revision),
+                point.commit,
+                point.date,
+                csv_escape(language),
+                lines.files,
+                lines.total_lines,
+                lines.code_lines,
+                lines.comment_lines,
+                lines.blank_lines
+            ));
+        }
+    }
+    csv
+}
+
+const CHART_COLORS: &[&str] = &[
+    "#667eea", "#e45756", "#54a24b", "#f58518", "#b279a2", "#4c78a8", "#eeca3b", "#9d755d", "#72b7b2", "#ff9da6",
+];
+
+// An inline SVG line chart with one line per language, so the page needs no scripts
+fn history_chart_svg(report: &HistoryReport, languages: &[&String], value: fn(&LanguageLines) -> u64) -> String {
+    let (width, height, left, right, top, bottom) = (900.0, 360.0, 70.0, 20.0, 20.0, 90.0);
+    let plot_width = width - left - right;
+    let plot_height = height - top - bottom;
+    let count = report.points.len();
+    let max = report.points.iter()
+        .flat_map(|point| point.languages.values().map(value))
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+    let x = |i: usize| if count > 1 { left + plot_width * i as f64 / (count - 1) as f64 } else { left + plot_width / 2.0 };
+    let y = |v: u64| top + plot_height * (1.0 - v as f64 / max);
+
+    let mut svg = format!(r#"<svg viewBox="0 0 {} {}" class="chart" xmlns="http://www.w3.org/2000/svg">"#, width, height);
+    for step in 0..=4 {
+        let v = (max * step as f64 / 4.0).round() as u64;
+        svg.push_str(&format!(
+            r##"<line x1="{left}" x2="{}" y1="{y:.1}" y2="{y:.1}" stroke="#e0e0e0"/><text x="{}" y="{:.1}" text-anchor="end" font-size="11">{v}</text>"##,
+            width - right, left - 6.0, y(v) + 4.0, y = y(v), left = left
+        ));
+    }
+    for (i, point) in report.points.iter().enumerate() {
+        svg.push_str(&format!(
+            r#"<text transform="translate({:.1},{}) rotate(-40)" text-anchor="end" font-size="11">{}</text>"#,
+            x(i), height - bottom + 16.0, xml_escape(&point.revision)
+        ));
+    }
+    for (n, language) in languages.iter().enumerate() {
+        let color = CHART_COLORS[n % CHART_COLORS.len()];
+        let coordinates: Vec<String> = report.points.iter().enumerate()
+            .map(|(i, point)| format!("{:.1},{:.1}", x(i), y(point.languages.get(*language).map(value).unwrap_or(0))))
+            .collect();
+        svg.push_str(&format!(
+            r#"<polyline fill="none" stroke="{}" stroke-width="2" points="{}"><title>{}</title></polyline>"#,
+            color, coordinates.join(" "), xml_escape(language)
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn generate_history_html(report: &HistoryReport) -> String {
+    let timestamp = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
+    // Largest languages at the latest point first, so they get the first colors
+    let mut languages: Vec<&String> = report.points.iter().flat_map(|point| point.languages.keys()).collect();
+    languages.sort();
+    languages.dedup();
+    if let Some(last) = report.points.last() {
+        languages.sort_by_key(|language| std::cmp::Reverse(last.languages.get(*language).map_or(0, |lines| lines.total_lines)));
+    }
+
+    let legend: String = languages.iter().enumerate()
+        .map(|(n, language)| format!(
+            r#"<span class="legend-item"><span class="swatch" style="background: {}"></span>{}</span>"#,
+            CHART_COLORS[n % CHART_COLORS.len()], xml_escape(language)
+        ))
+        .collect();
+    let charts: String = [
+        ("💻 Code Lines", (|lines: &LanguageLines| lines.code_lines) as fn(&LanguageLines) -> u64),
+        ("💬 Comment Lines", |lines: &LanguageLines| lines.comment_lines),
+        ("⬜ Blank Lines", |lines: &LanguageLines| lines.blank_lines),
+    ]
+    .iter()
+    .map(|(title, value)| format!(r#"<div class="section"><h2>{}</h2>{}</div>"#, title, history_chart_svg(report, &languages, *value)))
+    .collect();
+    let rows: String = report.points.iter()
+        .map(|point| format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            xml_escape(&point.revision), xml_escape(&point.date), point.lines.files,
+            point.lines.code_lines, point.lines.comment_lines, point.lines.blank_lines, point.lines.total_lines
+        ))
+        .collect();
+
+    format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>LOCO History</title>
+    <style>
+        body {{ font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; color: #333; background: #f4f5fb; padding: 20px; }}
+        .container {{ max-width: 1000px; margin: 0 auto; background: white; border-radius: 20px; padding: 30px; box-shadow: 0 20px 40px rgba(0,0,0,0.1); }}
+        h1 {{ color: #667eea; }}
+        .section h2 {{ border-bottom: 3px solid #667eea; padding-bottom: 6px; }}
+        .chart {{ width: 100%; height: auto; }}
+        .legend-item {{ display: inline-block; margin: 4px 12px 4px 0; }}
+        .swatch {{ display: inline-block; width: 12px; height: 12px; border-radius: 3px; margin-right: 6px; }}
+        table {{ width: 100%; border-collapse: collapse; }}
+        th {{ background: #667eea; color: white; padding: 10px; text-align: left; }}
+        td {{ padding: 8px 10px; border-bottom: 1px solid #eee; }}
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>🕰️ LOCO History</h1>
+        <p>{} revisions • Generated {}</p>
+        <div>{}</div>
+        {}
+        <div class="section">
+            <h2>📊 Totals</h2>
+            <table>
+                <thead><tr><th>Revision</th><th>Date</th><th>Files</th><th>Code</th><th>Comments</th><th>Blank</th><th>Total</th></tr></thead>
+                <tbody>{}</tbody>
+            </table>
+        </div>
+    </div>
+</body>
+</html>
+"#, report.points.len(), timestamp, legend, charts, rows)
+}
 
+// Collects, analyzes and aggregates the files of the roots (or revision) in `args`; None when
+// there is nothing to analyze
+fn analyze_project(args: &Args) -> Option<ProjectStats> {
     let mode_text = if args.very_fast {
         "🏎️ VERY-FAST Mode (Ultra-Optimized)"
     } else if args.fast {
@@ -2287,20 +6868,101 @@ fn main() {
         "🔍 FULL Analysis Mode"
     };
     
-    println!("🚀 Initializing LOCO Ultra-Fast Analysis Engine...");
-    println!("🎯 Target: {}", args.path.display().to_string().bright_white());
-    println!("🔧 Mode: {}", mode_text.bright_yellow());
+    status!(args, "🚀 Initializing LOCO Ultra-Fast Analysis Engine...");
+    let (roots, covered_roots) = dedupe_roots(&args.path);
+    let targets: Vec<String> = roots.iter().map(|root| root.display().to_string()).collect();
+    status!(args, "🎯 Target: {}", targets.join(", ").bright_white());
+    for (root, covering) in &covered_roots {
+        status!(args, "↪️ {} is already covered by {}", root.display(), covering.display());
+    }
+    status!(args, "🔧 Mode: {}", mode_text.bright_yellow());
+
+    let mut custom_languages = CustomLanguages::default();
+    let project_languages: Vec<PathBuf> = roots.iter()
+        .filter_map(|root| LANGUAGE_CONFIG_FILES.iter().map(|name| root.join(name)).find(|path| path.is_file()))
+        .collect();
+    for path in project_languages.iter().chain(args.languages.iter()) {
+        match custom_languages.load(path) {
+            Ok(count) => status!(args, "🧩 Loaded {} language definitions from {}", count, path.display()),
+            Err(e) => {
+                eprintln!("❌ Invalid language definitions in {}", e);
+                std::process::exit(1);
+            },
+        }
+    }
 
     let start_time = Instant::now();
-    let files = collect_files_optimized(&args.path, &args);
+    let scan_roots: Vec<ScanRoot> = roots.iter()
+        .map(|root| ScanRoot {
+            path: root.clone(),
+            gitattributes: GitAttributes::new(root),
+            globs: PathGlobMatcher::new(root, &args.path_globs).unwrap_or_else(|e| {
+                eprintln!("❌ Invalid glob {}", e);
+                std::process::exit(1);
+            }),
+        })
+        .collect();
+
+    // With --git-ref every file is read from the revision's blobs instead of the working tree
+    let mut snapshot = None;
+    // Files from every root (or the --files-from list), with the index of the root each one came from
+    // Only a walk prunes directories; listed and revision files are each logged on their own
+    let mut skipped_dirs = Vec::new();
+    let (files, file_roots, collection_skipped) = match (&args.files_from, &args.git_ref) {
+        (_, Some(revision)) => {
+            let (loaded, candidates, skipped) = collect_git_files(revision, &scan_roots, args).unwrap_or_else(|e| {
+                eprintln!("❌ Cannot read revision {}: {}", revision, e);
+                std::process::exit(1);
+            });
+            status!(args, "🔖 Reading {} ({}) from the git object database",
+                revision.bright_white(),
+                loaded.revision.commit.chars().take(12).collect::<String>()
+            );
+            let snapshot = snapshot.insert(loaded);
+            filter_candidates(candidates, &scan_roots, args, Source::Revision(snapshot), &custom_languages, skipped)
+        },
+        (Some(list), None) => {
+            let listed = read_file_list(list).unwrap_or_else(|e| {
+                eprintln!("❌ Cannot read file list {}", e);
+                std::process::exit(1);
+            });
+            status!(args, "📋 Read {} paths from {}", listed.len(), list.display());
+            collect_listed_files(&listed, &scan_roots, args, &custom_languages)
+        },
+        (None, None) => {
+            let mut files = Vec::new();
+            let mut file_roots = Vec::new();
+            let mut collection_skipped = Vec::new();
+            for (root_index, root) in scan_roots.iter().enumerate() {
+                let (root_files, root_skipped, root_skipped_dirs) = collect_files_optimized(&root.path, args, &root.gitattributes, &root.globs, &custom_languages);
+                file_roots.extend(std::iter::repeat_n(root_index, root_files.len()));
+                files.extend(root_files);
+                collection_skipped.extend(root_skipped);
+                skipped_dirs.extend(root_skipped_dirs);
+            }
+            (files, file_roots, collection_skipped)
+        },
+    };
+    let source = snapshot.as_ref().map_or(Source::WorkingTree, Source::Revision);
+    // Directory keys are relative to the roots' common ancestor, so each root is its own subtree
+    let directory_base = common_ancestor(&roots);
 
     if files.is_empty() {
-        println!("⚠️ No files found matching criteria.");
-        return;
+        status!(args, "⚠️ No files found matching criteria.");
+        if !collection_skipped.is_empty() {
+            status!(args, "⏭️ {} files skipped: {}", collection_skipped.len(), skip_summary(&collection_skipped));
+        }
+        if !skipped_dirs.is_empty() {
+            status!(args, "🚧 {} directories pruned: {}", skipped_dirs.len(), skip_summary(&skipped_dirs));
+        }
+        if args.show_skipped && !(collection_skipped.is_empty() && skipped_dirs.is_empty()) {
+            print_skipped_files(&collection_skipped, &skipped_dirs);
+        }
+        return None;
     }
 
     let thread_count = rayon::current_num_threads();
-    println!("⚙️ Processing {} files with {} threads...", 
+    status!(args, "⚙️ Processing {} files with {} threads...", 
         files.len().to_string().bright_white(),
         thread_count.to_string().bright_white());
 
@@ -2316,9 +6978,21 @@ fn main() {
         None
     };
 
+    // Persistent cache only applies to full analysis of the working tree; fast modes are estimates anyway
+    let cache = if args.cache && !args.fast && !args.very_fast && args.git_ref.is_none() {
+        Some(AnalysisCache::load(&roots, args))
+    } else {
+        None
+    };
+
     // Enhanced concurrent data structures
     let languages = Arc::new(DashMap::<String, LanguageStats>::new());
     let files_info = Arc::new(DashMap::<usize, FileInfo>::new());
+    let skipped_files = Arc::new(DashMap::<usize, SkippedFile>::new());
+    let generated_languages = Arc::new(DashMap::<String, LanguageStats>::new());
+    let generated_files = Arc::new(DashMap::<usize, FileInfo>::new());
+    let directory_stats = Arc::new(DashMap::<String, LanguageStats>::new());
+    let root_languages = Arc::new(DashMap::<usize, HashMap<String, LanguageStats>>::new());
     let creation_dates = Arc::new(DashMap::<usize, u64>::new());
     let modification_dates = Arc::new(DashMap::<usize, u64>::new());
     
@@ -2327,85 +7001,118 @@ fn main() {
 
     // Parallel processing with enhanced performance
     files.par_iter().enumerate().for_each(|(index, file_path)| {
-        let file_result = if args.very_fast {
+        // linguist-language in .gitattributes beats detection; files are only opened for detection
+        // when their name and extension are unknown or the extension is ambiguous
+        let root = &scan_roots[file_roots[index]];
+        let attributes = root.gitattributes.lookup(file_path, source);
+        let detection = match attributes.language_key(&custom_languages) {
+            Some(key) => LanguageDetection {
+                key,
+                reason: attributes.language.as_ref().map(|name| format!(".gitattributes linguist-language={}", name)),
+            },
+            None => detect_language_detailed(file_path, !args.very_fast, source, &custom_languages),
+        };
+        let language_key = detection.key;
+
+        // Notebooks always get a full parse: their raw JSON line counts are meaningless
+        let is_notebook = language_key == "ipynb";
+        let file_result = if args.very_fast && !is_notebook {
             // Very fast mode - ultra optimized with estimation
-            analyze_file_very_fast(file_path, &args)
-        } else if args.fast {
+            analyze_file_very_fast(file_path, &language_key, args, source, &custom_languages)
+        } else if args.fast && !is_notebook {
             // Fast mode - minimal analysis
-            analyze_file_fast(file_path, &args)
+            analyze_file_fast(file_path, &language_key, args, source, &custom_languages)
         } else {
             // Full analysis mode
-            if let Some(extension) = file_path.extension().and_then(|e| e.to_str()) {
-                if let Some(config) = LanguageConfig::get_config(extension) {
-                    analyze_file_advanced(file_path, &config, &args)
-                } else if args.include_unknown {
-                    // Simple parsing for unknown files
-                    let simple_config = LanguageConfig::get_simple_config();
-                    analyze_file_advanced(file_path, &simple_config, &args)
-                } else {
-                    None
-                }
+            let config = if let Some(config) = custom_languages.config(&language_key) {
+                Some(config)
             } else if args.include_unknown {
-                // Handle extensionless files
-                let simple_config = LanguageConfig::get_simple_config();
-                analyze_file_advanced(file_path, &simple_config, &args)
+                // Simple parsing for unknown files
+                Some(LanguageConfig::get_simple_config())
             } else {
                 None
+            };
+
+            match config {
+                Some(config) => match cache {
+                    Some(ref cache) => cache.analyze(file_path, &language_key, &config, args, &custom_languages),
+                    None => analyze_file_advanced(file_path, &language_key, &config, args, source, &custom_languages),
+                },
+                None => Err(SkipReason::UnknownExtension),
             }
         };
 
-        if let Some((file_stats, file_info)) = file_result {
+        let file_result = file_result.and_then(|(file_stats, mut file_info)| {
+            let detected = classify_vendored(file_path, &root.path).or(file_info.generated.take());
+            file_info.generated = attributes.classify(detected);
+            match (&file_info.generated, args.generated.as_str()) {
+                (Some(generated), "exclude") => Err(generated.kind.skip_reason()),
+                _ => Ok((file_stats, file_info)),
+            }
+        });
+
+        if let Err(reason) = file_result {
+            skipped_files.insert(index, SkippedFile { path: file_path.clone(), reason });
+        }
+
+        if let Ok((file_stats, mut file_info)) = file_result {
+            file_info.language_detection = detection.reason;
+            if args.content_hashes {
+                file_info.content_hash = source.read(file_path).ok().map(|bytes| hash_of(&bytes[..]));
+            }
             let language = file_info.language.clone();
 
-            // Update language stats using DashMap (optimized)
-            languages.entry(language).and_modify(|entry| {
-                entry.total_lines += file_stats.total_lines;
-                entry.code_lines += file_stats.code_lines;
-                entry.comment_lines += file_stats.comment_lines;
-                entry.blank_lines += file_stats.blank_lines;
-                entry.files += 1;
-                entry.total_size += file_stats.total_size;
-                
-                // Update weighted averages (optimized calculation)
-                let old_count = entry.files - 1;
-                if old_count > 0 {
-                    let weight_old = old_count as f64;
-                    let weight_new = entry.files as f64;
-                    
-                    entry.avg_line_length = (entry.avg_line_length * weight_old + file_stats.avg_line_length) / weight_new;
-                    entry.complexity_score = (entry.complexity_score * weight_old + file_stats.complexity_score) / weight_new;
-                    entry.maintainability_index = (entry.maintainability_index * weight_old + file_stats.maintainability_index) / weight_new;
-                    entry.cyclomatic_complexity = (entry.cyclomatic_complexity * weight_old + file_stats.cyclomatic_complexity) / weight_new;
-                } else {
-                    entry.avg_line_length = file_stats.avg_line_length;
-                    entry.complexity_score = file_stats.complexity_score;
-                    entry.maintainability_index = file_stats.maintainability_index;
-                    entry.cyclomatic_complexity = file_stats.cyclomatic_complexity;
+            // Generated/vendored files get their own bucket and stay out of every other metric
+            if file_info.generated.is_some() && args.generated == "separate" {
+                generated_languages.entry(language)
+                    .and_modify(|entry| entry.merge(&file_stats))
+                    .or_insert(file_stats);
+                generated_files.insert(index, file_info.clone());
+            } else {
+                // Roll the file up into every enclosing directory when grouping by directory
+                if args.group_by_dir {
+                    for directory in directory_keys(file_path, &directory_base, args.dir_depth) {
+                        let mut entry = directory_stats.entry(directory).or_default();
+                        entry.merge(&file_stats);
+                        for embedded in &file_info.embedded {
+                            entry.merge(&embedded.stats);
+                        }
+                    }
                 }
-                
-                entry.max_line_length = entry.max_line_length.max(file_stats.max_line_length);
-                entry.functions += file_stats.functions;
-                entry.classes += file_stats.classes;
-                entry.imports += file_stats.imports;
-                entry.todos += file_stats.todos;
-                entry.fixmes += file_stats.fixmes;
-                
-                // Update percentages
-                if entry.total_lines > 0 {
-                    entry.code_percentage = entry.code_lines as f64 / entry.total_lines as f64 * 100.0;
-                    entry.comment_percentage = entry.comment_lines as f64 / entry.total_lines as f64 * 100.0;
-                    entry.blank_percentage = entry.blank_lines as f64 / entry.total_lines as f64 * 100.0;
+
+                if args.per_root {
+                    let mut root_entry = root_languages.entry(file_roots[index]).or_default();
+                    root_entry.entry(language.clone())
+                        .and_modify(|entry| entry.merge(&file_stats))
+                        .or_insert_with(|| file_stats.clone());
+                    for embedded in &file_info.embedded {
+                        root_entry.entry(embedded.language.clone())
+                            .and_modify(|entry| entry.merge(&embedded.stats))
+                            .or_insert_with(|| embedded.stats.clone());
+                    }
+                }
+
+                // Update language stats using DashMap (optimized)
+                languages.entry(language)
+                    .and_modify(|entry| entry.merge(&file_stats))
+                    .or_insert(file_stats);
+
+                // Embedded blocks count toward their own language without adding files
+                for embedded in &file_info.embedded {
+                    languages.entry(embedded.language.clone())
+                        .and_modify(|entry| entry.merge(&embedded.stats))
+                        .or_insert_with(|| embedded.stats.clone());
                 }
-            }).or_insert(file_stats);
 
-            // Store file info
-            files_info.insert(index, file_info.clone());
+                // Store file info
+                files_info.insert(index, file_info.clone());
 
-            // Store timestamps if available and requested
-            if args.time_analysis {
-                if let (Some(created), Some(modified)) = (file_info.created, file_info.modified) {
-                    creation_dates.insert(index, created);
-                    modification_dates.insert(index, modified);
+                // Store timestamps if available and requested
+                if args.time_analysis {
+                    if let (Some(created), Some(modified)) = (file_info.created, file_info.modified) {
+                        creation_dates.insert(index, created);
+                        modification_dates.insert(index, modified);
+                    }
                 }
             }
 
@@ -2428,10 +7135,60 @@ fn main() {
         let languages_ref = Arc::try_unwrap(languages).unwrap_or_else(|arc| (*arc).clone());
         languages_ref.into_iter().collect()
     };
-    let final_files_info: Vec<FileInfo> = {
+    let mut final_files_info: Vec<FileInfo> = {
         let files_info_ref = Arc::try_unwrap(files_info).unwrap_or_else(|arc| (*arc).clone());
         files_info_ref.into_iter().map(|(_, v)| v).collect()
     };
+    let mut final_skipped_files: Vec<SkippedFile> = {
+        let skipped_files_ref = Arc::try_unwrap(skipped_files).unwrap_or_else(|arc| (*arc).clone());
+        skipped_files_ref.into_iter().map(|(_, v)| v).collect()
+    };
+    final_skipped_files.extend(collection_skipped);
+    final_skipped_files.sort_by(|a, b| a.path.cmp(&b.path));
+    skipped_dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    let final_generated_languages: HashMap<String, LanguageStats> = {
+        let generated_languages_ref = Arc::try_unwrap(generated_languages).unwrap_or_else(|arc| (*arc).clone());
+        generated_languages_ref.into_iter().collect()
+    };
+    let mut final_generated_files: Vec<FileInfo> = {
+        let generated_files_ref = Arc::try_unwrap(generated_files).unwrap_or_else(|arc| (*arc).clone());
+        generated_files_ref.into_iter().map(|(_, v)| v).collect()
+    };
+    final_generated_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut embedded_languages: HashMap<String, Vec<EmbeddedStats>> = HashMap::new();
+    for file in &final_files_info {
+        for embedded in &file.embedded {
+            let hosted = embedded_languages.entry(file.language.clone()).or_default();
+            match hosted.iter_mut().find(|entry| entry.language == embedded.language) {
+                Some(entry) => {
+                    entry.blocks += embedded.blocks;
+                    entry.stats.merge(&embedded.stats);
+                },
+                None => hosted.push(embedded.clone()),
+            }
+        }
+    }
+    for hosted in embedded_languages.values_mut() {
+        hosted.sort_by_key(|entry| std::cmp::Reverse(entry.stats.total_lines));
+    }
+    let mut final_roots: Vec<RootStats> = {
+        let root_languages_ref = Arc::try_unwrap(root_languages).unwrap_or_else(|arc| (*arc).clone());
+        root_languages_ref.into_iter()
+            .map(|(root_index, languages)| RootStats {
+                path: roots[root_index].clone(),
+                total_files: languages.values().map(|s| s.files).sum(),
+                total_lines: languages.values().map(|s| s.total_lines).sum(),
+                total_size: languages.values().map(|s| s.total_size).sum(),
+                languages,
+            })
+            .collect()
+    };
+    final_roots.sort_by_key(|root_stats| roots.iter().position(|root| *root == root_stats.path));
+    let final_directory_stats: HashMap<String, LanguageStats> = {
+        let directory_stats_ref = Arc::try_unwrap(directory_stats).unwrap_or_else(|arc| (*arc).clone());
+        directory_stats_ref.into_iter().collect()
+    };
     let final_creation_dates: Vec<u64> = {
         let creation_dates_ref = Arc::try_unwrap(creation_dates).unwrap_or_else(|arc| (*arc).clone());
         creation_dates_ref.into_iter().map(|(_, v)| v).collect()
@@ -2441,6 +7198,15 @@ fn main() {
         modification_dates_ref.into_iter().map(|(_, v)| v).collect()
     };
 
+    let (cache_hits, cache_misses) = match cache {
+        Some(cache) => {
+            let counts = (cache.hits.load(Ordering::Relaxed), cache.misses.load(Ordering::Relaxed));
+            cache.save();
+            counts
+        },
+        None => (0, 0),
+    };
+
     let analysis_time = start_time.elapsed().as_secs_f64();
     let total_bytes = total_bytes_processed.load(Ordering::Relaxed);
 
@@ -2451,11 +7217,14 @@ fn main() {
         bytes_per_second: total_bytes as f64 / analysis_time,
         peak_memory_usage: 0, // Would need system monitoring
         cpu_utilization: thread_count as f64 / num_cpus::get() as f64 * 100.0,
+        cache_hits,
+        cache_misses,
     };
 
     // Get git stats if requested (skip in very-fast mode)
-    let git_info = if args.git_stats && !args.very_fast {
-        get_git_stats(&args.path)
+    // Repository-wide statistics say nothing about a single revision (diff/history sides)
+    let git_info = if args.git_stats && !args.very_fast && !source.is_revision() {
+        get_git_stats(&roots[0])
     } else {
         None
     };
@@ -2464,13 +7233,46 @@ fn main() {
     let files_info_for_quality = final_files_info.clone();
     let languages_for_quality = final_languages.clone();
 
+    // Churn over the --since window (skip in very-fast mode)
+    let has_churn = match &args.since {
+        Some(since) if !args.very_fast => {
+            match collect_churn(&roots, since, args.git_ref.as_deref().unwrap_or("HEAD")) {
+                Ok(churn) => {
+                    apply_churn(&mut final_files_info, &roots, &churn);
+                    status!(args, "🔁 Churn since {}: {} of the analyzed files changed",
+                        since,
+                        final_files_info.iter().filter(|f| f.churn.is_some()).count()
+                    );
+                    true
+                },
+                Err(e) => {
+                    status!(args, "⚠️ No churn data: {}", e);
+                    false
+                },
+            }
+        },
+        Some(_) => {
+            status!(args, "⚠️ --since is ignored in very fast mode (no churn data)");
+            false
+        },
+        None => false,
+    };
+
     // Detect hotspots if requested (skip in very-fast mode)
     let hotspots = if args.hotspots && !args.very_fast {
-        detect_hotspots_improved(&final_files_info)
+        if has_churn { detect_churn_hotspots(&final_files_info) } else { detect_hotspots_improved(&final_files_info) }
     } else {
         Vec::new()
     };
 
+    // Detect duplicated blocks across the analyzed files (skip in fast modes)
+    let duplication = if args.duplicates && !args.fast && !args.very_fast {
+        let analyzed_paths: Vec<PathBuf> = final_files_info.iter().map(|f| f.path.clone()).collect();
+        Some(detect_duplicates(&analyzed_paths, args, source, &custom_languages))
+    } else {
+        None
+    };
+
     // Calculate project stats
     let total_files_counted = final_languages.values().map(|s| s.files).sum();
     let total_lines_counted = final_languages.values().map(|s| s.total_lines).sum();
@@ -2487,7 +7289,17 @@ fn main() {
         modification_dates: final_modification_dates,
         files_info: final_files_info,
         hotspots,
-        directory_stats: HashMap::new(),
+        duplication: duplication.clone(),
+        directory_stats: final_directory_stats,
+        embedded_languages,
+        skipped_files: final_skipped_files,
+        skipped_dirs,
+        generated_languages: final_generated_languages,
+        generated_files: final_generated_files,
+        roots: final_roots,
+        revision: snapshot.as_ref().map(|snapshot| snapshot.revision.clone()),
+        baseline: None,
+        policy_violations: Vec::new(),
         performance_metrics,
         quality_metrics: calculate_quality_metrics_improved(&ProjectStats {
             languages: languages_for_quality,
@@ -2500,13 +7312,25 @@ fn main() {
             modification_dates: vec![],
             files_info: files_info_for_quality,
             hotspots: vec![],
+            duplication,
             directory_stats: HashMap::new(),
+            embedded_languages: HashMap::new(),
+            skipped_files: vec![],
+            skipped_dirs: vec![],
+            generated_languages: HashMap::new(),
+            generated_files: vec![],
+            roots: vec![],
+            revision: None,
+            baseline: None,
+            policy_violations: vec![],
             performance_metrics: PerformanceMetrics {
                 files_per_second: 0.0,
                 lines_per_second: 0.0,
                 bytes_per_second: 0.0,
                 peak_memory_usage: 0,
                 cpu_utilization: 0.0,
+                cache_hits: 0,
+                cache_misses: 0,
             },
             quality_metrics: QualityMetrics {
                 overall_maintainability: 0.0,
@@ -2518,25 +7342,121 @@ fn main() {
         }),
     };
 
+    Some(project_stats)
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.path_globs = ordered_path_globs(&matches);
+
+    if let Some(missing) = args.path.iter().find(|path| !path.exists()) {
+        eprintln!("❌ Path does not exist: {}", missing.display());
+        std::process::exit(1);
+    }
+
+    // Enhanced thread management for optimal performance
+    let optimal_threads = if args.threads > 0 {
+        args.threads
+    } else if args.very_fast {
+        // Very fast mode: maximize parallelization
+        let cpu_cores = num_cpus::get();
+        std::cmp::min(cpu_cores * 4, 64) // Aggressive threading
+    } else {
+        // Intelligent auto-detection based on workload
+        let cpu_cores = num_cpus::get();
+        let physical_cores = num_cpus::get_physical();
+        
+        // For I/O bound work like file processing, use more threads than cores
+        std::cmp::max(std::cmp::min(cpu_cores * 2, 32), physical_cores)
+    };
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(optimal_threads)
+        .build_global()
+        .unwrap();
+
+    if args.git_stats && args.git_ref.is_some() {
+        eprintln!("❌ --git-stats cannot be used with --git-ref (the statistics cover the whole repository, not one revision)");
+        std::process::exit(1);
+    }
+
+    // Baselines and policy rules judge a single analysis; diff and history never apply them
+    if let Some(command) = &args.command {
+        let single_run_flags = [
+            ("--fail-if", !args.fail_if.is_empty()),
+            ("--policy", args.policy.is_some()),
+            ("--baseline", args.baseline.is_some()),
+            ("--save-baseline", args.save_baseline.is_some()),
+        ];
+        if let Some((flag, _)) = single_run_flags.iter().find(|(_, given)| *given) {
+            let name = match command {
+                Command::Diff { .. } => "diff",
+                Command::History { .. } => "history",
+            };
+            eprintln!("❌ {} cannot be used with `loco {}`", flag, name);
+            std::process::exit(1);
+        }
+    }
+
+    let policy = load_policy(&args).unwrap_or_else(|e| {
+        eprintln!("❌ Invalid policy rule {}", e);
+        std::process::exit(1);
+    });
+
+    match &args.command {
+        Some(Command::Diff { old, new }) => {
+            run_diff(&args, old, new);
+            return;
+        },
+        Some(Command::History { samples, tags }) => {
+            run_history(&args, *samples, *tags);
+            return;
+        },
+        None => {},
+    }
+
+    let Some(mut project_stats) = analyze_project(&args) else {
+        return;
+    };
+
+    if let Some(path) = &args.baseline {
+        let baseline = load_baseline(path).unwrap_or_else(|e| {
+            eprintln!("❌ Cannot read baseline {}", e);
+            std::process::exit(1);
+        });
+        project_stats.baseline = Some(compare_with_baseline(&project_stats, &baseline, path, &args));
+    }
+    project_stats.policy_violations = check_policy(&policy, &project_stats);
+    if let Some(path) = &args.save_baseline {
+        if let Err(e) = save_baseline(&project_stats, path, &args) {
+            eprintln!("❌ Cannot save baseline {}", e);
+            std::process::exit(1);
+        }
+        status!(args, "💾 Baseline saved to: {}", path.display().to_string().bright_green());
+    }
+
     // Output results
     match args.format.as_str() {
         "json" => {
             let json = serde_json::to_string_pretty(&project_stats).unwrap();
-            if let Some(output_path) = &args.output {
-                fs::write(output_path, &json).unwrap();
-                println!("📊 Results saved to: {}", output_path.display());
-            } else {
-                println!("{}", json);
-            }
+            write_report(&json, &args, "Results");
         },
         "html" => {
             let html = generate_html_report(&project_stats, &args);
-            if let Some(output_path) = &args.output {
-                fs::write(output_path, &html).unwrap();
-                println!("📊 HTML report saved to: {}", output_path.display());
-            } else {
-                println!("{}", html);
-            }
+            write_report(&html, &args, "HTML report");
+        },
+        "csv" => {
+            let csv = generate_csv_report(&project_stats, &args);
+            write_report(&csv, &args, "CSV report");
+        },
+        "markdown" | "md" => {
+            let markdown = generate_markdown_report(&project_stats, &args);
+            write_report(&markdown, &args, "Markdown report");
+        },
+        "xml" => {
+            let xml = generate_xml_report(&project_stats, &args);
+            write_report(&xml, &args, "XML report");
         },
         _ => {
             print_results_optimized(&project_stats, &args);
@@ -2547,14 +7467,590 @@ fn main() {
     if args.report {
         let report_path = args.output.clone().unwrap_or_else(|| PathBuf::from("loco_ultra_report.html"));
         let html_report = generate_html_report(&project_stats, &args);
-        fs::write(&report_path, &html_report).unwrap();
-        println!("\n📊 Ultra-Fast HTML report saved to: {}", report_path.display().to_string().bright_green());
+        if let Err(e) = fs::write(&report_path, &html_report) {
+            eprintln!("❌ Cannot write {}: {}", report_path.display(), e);
+            std::process::exit(1);
+        }
+        status!(args, "\n📊 Ultra-Fast HTML report saved to: {}", report_path.display().to_string().bright_green());
     }
 
-    println!("\n{} LOCO Analysis completed successfully! 🎉", "✅".bright_green().bold());
-    println!("📈 Processed {} files, {} lines in {:.3}s", 
+    status!(args, "\n{} LOCO Analysis completed successfully! 🎉", "✅".bright_green().bold());
+    status!(args, "📈 Processed {} files, {} lines in {:.3}s", 
         project_stats.total_files.to_string().bright_cyan(),
         project_stats.total_lines.to_string().bright_cyan(),
-        analysis_time.to_string().bright_yellow()
+        project_stats.analysis_time.to_string().bright_yellow()
     );
+
+    let regressions = project_stats.baseline.as_ref().map_or(0, |comparison| comparison.violations.len());
+    if regressions > 0 {
+        eprintln!("❌ {} regressions beyond the baseline tolerances: {}",
+            regressions,
+            project_stats.baseline.as_ref().map(|comparison| comparison.violations.join("; ")).unwrap_or_default()
+        );
+    }
+    if !project_stats.policy_violations.is_empty() {
+        // Listed on stderr whatever the format, so CI logs show what failed
+        for violation in &project_stats.policy_violations {
+            eprintln!("  ❌ {}", violation.describe());
+        }
+        eprintln!("❌ {} policy violations", project_stats.policy_violations.len());
+        std::process::exit(EXIT_POLICY_VIOLATION);
+    }
+    if regressions > 0 {
+        std::process::exit(EXIT_BASELINE_REGRESSION);
+    }
+                }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "code", "comment", "mixed" or "blank" per line, scanned the way analyze_file_advanced does
+    fn classify(language_key: &str, source: &str) -> Vec<&'static str> {
+        let config = LanguageConfig::builtin(language_key).expect("built-in language");
+        let mut scanner = CommentScanner::new(&config);
+        source.lines()
+            .map(|line| {
+                let scan = scanner.scan_line(line);
+                match (scan.has_code, scan.has_comment) {
+                    (true, true) => "mixed",
+                    (true, false) => "code",
+                    (false, true) => "comment",
+                    (false, false) => "blank",
                 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rust_strings_hide_comment_markers() {
+        let source = "let s = \"// not a comment\";\nlet r = r#\"/* \"still\" raw\"#;\nlet m = \"line one\n/* inside */ line two\";";
+        assert_eq!(classify("rs", source), ["code", "code", "code", "code"]);
+    }
+
+    #[test]
+    fn rust_lifetimes_are_not_char_literals() {
+        let source = "fn f<'a>(x: &'a str) -> char { '\"' } // quote\nlet c = '/'; // slash";
+        assert_eq!(classify("rs", source), ["mixed", "mixed"]);
+    }
+
+    #[test]
+    fn python_docstrings_and_triple_quoted_strings() {
+        let source = "\"\"\"Module docstring\nspanning lines\"\"\"\nx = \"\"\"not # a comment\n# still string\"\"\"\ny = 1  # trailing";
+        assert_eq!(classify("py", source), ["comment", "comment", "code", "code", "mixed"]);
+    }
+
+    #[test]
+    fn javascript_template_literals() {
+        let source = "const t = `multi\n// line ${x}\n`;\n// real comment";
+        assert_eq!(classify("js", source), ["code", "code", "code", "comment"]);
+    }
+
+    #[test]
+    fn go_raw_strings_and_cpp_raw_literals() {
+        assert_eq!(classify("go", "s := `/* raw\n*/`\n/* c */"), ["code", "code", "comment"]);
+        assert_eq!(classify("cpp", "auto s = R\"(// raw)\";\n// c"), ["code", "comment"]);
+    }
+
+
+    #[test]
+    fn rust_nested_block_comments() {
+        let source = "/* a /* b */ still comment */\nlet x = 1;\n/* open\n /* inner */\n*/ let y = 2;";
+        assert_eq!(classify("rs", source), ["comment", "code", "comment", "comment", "mixed"]);
+    }
+
+    #[test]
+    fn d_nests_plus_comments_only() {
+        let source = "/* a /* b */\nint x;\nint y;\n/+ a /+ b +/ still +/\nint z;";
+        assert_eq!(classify("d", source), ["comment", "code", "code", "comment", "code"]);
+    }
+
+    #[test]
+    fn c_block_comments_do_not_nest() {
+        let source = "/* a /* b */\nint x;";
+        assert_eq!(classify("c", source), ["comment", "code"]);
+    }
+
+
+    #[test]
+    fn decoding_text() {
+        let decoded = decode_text(b"plain").unwrap();
+        assert_eq!((decoded.text.as_str(), decoded.encoding.as_str()), ("plain", "ASCII"));
+
+        let decoded = decode_text("caf\u{e9}".as_bytes()).unwrap();
+        assert_eq!((decoded.text.as_str(), decoded.encoding.as_str()), ("caf\u{e9}", "UTF-8"));
+
+        let decoded = decode_text(b"\xEF\xBB\xBFbom").unwrap();
+        assert_eq!((decoded.text.as_str(), decoded.encoding.as_str()), ("bom", "UTF-8 BOM"));
+
+        let decoded = decode_text(b"\xFF\xFEh\x00i\x00").unwrap();
+        assert_eq!((decoded.text.as_str(), decoded.encoding.as_str()), ("hi", "UTF-16 LE"));
+
+        let decoded = decode_text(b"\x00\x00\xFE\xFF\x00\x00\x00h").unwrap();
+        assert_eq!((decoded.text.as_str(), decoded.encoding.as_str()), ("h", "UTF-32 BE"));
+
+        assert_eq!(decode_text(b"").unwrap().encoding, "Empty");
+        assert_eq!(decode_text(b"ELF\x00\x01\x02").err(), Some(SkipReason::Binary));
+
+        // Not UTF-8: guessed, decoded and counted
+        let decoded = decode_text(b"na\xEFve caf\xE9 cr\xE8me br\xFBl\xE9e\n").unwrap();
+        assert_eq!(decoded.text, "na\u{ef}ve caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n");
+    }
+
+
+    #[test]
+    fn gitattributes_patterns() {
+        let rules = parse_gitattributes("# comment\n*.gen.rs linguist-generated\n/docs/** linguist-documentation\nlib/*.js -linguist-vendored\nbuild/ linguist-vendored\n!*.txt text\n");
+        assert_eq!(rules.len(), 3);
+
+        assert!(rules[0].pattern.is_match(Path::new("src/deep/api.gen.rs")));
+        assert_eq!(rules[0].attributes, ["linguist-generated"]);
+        assert!(rules[1].pattern.is_match(Path::new("docs/guide/intro.md")));
+        assert!(!rules[1].pattern.is_match(Path::new("src/docs/intro.md")));
+        assert!(rules[2].pattern.is_match(Path::new("lib/app.js")));
+        assert!(!rules[2].pattern.is_match(Path::new("lib/sub/app.js")));
+    }
+
+
+    #[test]
+    fn file_lists_split_on_newlines_or_nul() {
+        let dir = std::env::temp_dir().join(format!("loco-file-list-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let lines = dir.join("lines.txt");
+        fs::write(&lines, "src/a.rs\r\n\nsrc/b c.rs\n").unwrap();
+        assert_eq!(read_file_list(&lines).unwrap(), [PathBuf::from("src/a.rs"), PathBuf::from("src/b c.rs")]);
+
+        let nul = dir.join("nul.txt");
+        fs::write(&nul, "src/a.rs\0odd\nname.rs\0").unwrap();
+        assert_eq!(read_file_list(&nul).unwrap(), [PathBuf::from("src/a.rs"), PathBuf::from("odd\nname.rs")]);
+
+        assert!(read_file_list(&dir.join("missing.txt")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    // Args as the command line would build them, for tests that need a full set of flags
+    // Parsed the way main does, including the ordered --include-glob/--exclude-glob list
+    fn test_args(extra: &[&str]) -> Args {
+        let matches = Args::command().get_matches_from(std::iter::once("loco").chain(extra.iter().copied()));
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        args.path_globs = ordered_path_globs(&matches);
+        args
+    }
+
+    fn test_file(path: &str, language: &str, lines: u64) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(path),
+            language: language.to_string(),
+            lines,
+            code_lines: lines,
+            comment_lines: 0,
+            blank_lines: 0,
+            size: lines * 10,
+            encoding: "ASCII".to_string(),
+            complexity: 0.0,
+            created: None,
+            modified: None,
+            todos: 0,
+            fixmes: 0,
+            cyclomatic_complexity: 1.0,
+            maintainability_index: 100.0,
+            technical_debt_ratio: 0.0,
+            language_detection: None,
+            embedded: vec![],
+            notebook: None,
+            generated: None,
+            churn: None,
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn csv_report_is_one_table() {
+        let mut stats = ProjectStats::default();
+        stats.languages.insert("Rust 🦀".to_string(), LanguageStats { files: 1, total_lines: 3, ..Default::default() });
+        stats.files_info.push(test_file("src/a, b.rs", "Rust 🦀", 3));
+
+        let languages = generate_csv_report(&stats, &test_args(&[]));
+        let rows: Vec<&str> = languages.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("language,files,total_lines,"));
+        assert!(rows[1].starts_with("Rust 🦀,1,3,"));
+
+        let files = generate_csv_report(&stats, &test_args(&["--csv-table", "files"]));
+        let rows: Vec<&str> = files.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("path,language,lines,"));
+        assert!(rows[1].starts_with("\"src/a, b.rs\",Rust 🦀,3,30,ASCII,"));
+    }
+
+
+    #[test]
+    fn policy_rules() {
+        let rule = parse_policy_rule("max-file-lines>=500").unwrap();
+        assert_eq!(rule.metric, "max-file-lines");
+        assert!(matches!(rule.op, PolicyOp::GreaterOrEqual));
+        assert_eq!(rule.threshold, 500.0);
+
+        let rule = parse_policy_rule(" min-comment-ratio < 10% ").unwrap();
+        assert_eq!(rule.metric, "min-comment-ratio");
+        assert!(matches!(rule.op, PolicyOp::Less));
+        assert_eq!(rule.threshold, 10.0);
+
+        assert!(parse_policy_rule("max-lines=5").is_err());
+        assert!(parse_policy_rule("max-bananas>5").is_err());
+        assert!(parse_policy_rule("max-lines>lots").is_err());
+        assert!(parse_policy_rule("max-lines>NaN").is_err());
+        assert!(parse_policy_rule("max-lines<inf").is_err());
+    }
+
+    #[test]
+    fn policy_violations_name_the_offending_file() {
+        let mut stats = ProjectStats::default();
+        stats.files_info.push(test_file("src/big.rs", "Rust 🦀", 800));
+        stats.files_info.push(test_file("src/small.rs", "Rust 🦀", 20));
+        let rules = vec![parse_policy_rule("max-file-lines>500").unwrap()];
+
+        stats.policy_violations = check_policy(&rules, &stats);
+        assert_eq!(stats.policy_violations.len(), 1);
+        assert_eq!(stats.policy_violations[0].describe(), "max-file-lines>500: src/big.rs (800)");
+
+        let csv = generate_csv_report(&stats, &test_args(&["--csv-table", "violations"]));
+        assert_eq!(csv, "rule,value,path,files\nmax-file-lines>500,800,src/big.rs,\n");
+
+        let html = generate_html_report(&stats, &test_args(&[]));
+        assert!(html.contains("Policy Violations") && html.contains("max-file-lines&gt;500") && html.contains("src/big.rs"));
+    }
+
+
+    fn baseline_of(files: &[FileInfo], todos: u64) -> Baseline {
+        let mut stats = ProjectStats::default();
+        for file in files {
+            stats.total_files += 1;
+            stats.total_lines += file.lines;
+            stats.files_info.push(file.clone());
+        }
+        stats.languages.insert("Rust 🦀".to_string(), LanguageStats { todos, ..Default::default() });
+        stats.quality_metrics.overall_maintainability = 80.0;
+        Baseline { version: 1, created_at: "2026-01-01".to_string(), roots: vec![PathBuf::from(".")], stats }
+    }
+
+    #[test]
+    fn baseline_tolerances() {
+        let baseline = baseline_of(&[test_file("./a.rs", "Rust 🦀", 100)], 1);
+        let mut current = baseline_of(&[test_file("./a.rs", "Rust 🦀", 100), test_file("./b.rs", "Rust 🦀", 10)], 3);
+        current.stats.quality_metrics.overall_maintainability = 75.0;
+        let current = current.stats;
+
+        // 10% growth is at the limit, not over it
+        let args = test_args(&["--max-line-growth", "10", "--max-maintainability-drop", "5", "--max-todo-increase", "2"]);
+        let comparison = compare_with_baseline(&current, &baseline, Path::new("base.json"), &args);
+        assert!((comparison.line_growth_percent - 10.0).abs() < 1e-9);
+        assert_eq!(comparison.files.change, 1);
+        assert_eq!(comparison.todos.change, 2);
+        assert!(comparison.violations.is_empty(), "{:?}", comparison.violations);
+
+        let args = test_args(&["--max-line-growth", "9.9", "--max-maintainability-drop", "4.9", "--max-todo-increase", "1"]);
+        let comparison = compare_with_baseline(&current, &baseline, Path::new("base.json"), &args);
+        assert_eq!(comparison.violations.len(), 3, "{:?}", comparison.violations);
+        assert!(comparison.violations[0].starts_with("lines grew by 10.0%"));
+
+        // Without limits nothing is a violation
+        let comparison = compare_with_baseline(&current, &baseline, Path::new("base.json"), &test_args(&[]));
+        assert!(comparison.violations.is_empty());
+    }
+
+    #[test]
+    fn html_report_renders_baseline() {
+        let baseline = baseline_of(&[test_file("./a.rs", "Rust 🦀", 100)], 0);
+        let mut current = baseline.stats.clone();
+        current.total_lines = 150;
+        let args = test_args(&["--max-line-growth", "10"]);
+        current.baseline = Some(compare_with_baseline(&current, &baseline, Path::new("base<1>.json"), &args));
+
+        let html = generate_html_report(&current, &args);
+        assert!(html.contains("Baseline Comparison"));
+        assert!(html.contains("base&lt;1&gt;.json"));
+        assert!(html.contains("lines grew by 50.0%"));
+
+        assert!(!generate_html_report(&baseline.stats, &args).contains("Baseline Comparison"));
+    }
+
+    fn regions(host_key: &str, source: &str) -> Vec<(String, usize, usize)> {
+        let lines: Vec<&str> = source.lines().collect();
+        embedded_regions(host_key, &lines, &CustomLanguages::default()).into_iter()
+            .map(|region| (region.language_key, region.start, region.end))
+            .collect()
+    }
+
+    #[test]
+    fn html_script_and_style_regions() {
+        let html = "<html>\n<script type=\"module\">\nlet a = 1;\n</script>\n<STYLE>\nb { }\n</Style>\n<script>init()</script>\n<script lang=\"ts\">\nlet c: number = 2;\nlet d = 3;\n</script>\n<template>\n</template>\n";
+        assert_eq!(regions("html", html), vec![
+            ("js".to_string(), 2, 3),
+            ("css".to_string(), 5, 6),
+            ("ts".to_string(), 9, 11),
+        ]);
+        // Unknown script types are skipped but still closed
+        assert_eq!(regions("vue", "<script type=\"text/x-template\">\n<div/>\n</script>\n<style>\na { }\n</style>\n"), vec![("css".to_string(), 4, 5)]);
+    }
+
+    #[test]
+    fn tag_regions_survive_case_folding_that_changes_length() {
+        // The Kelvin sign lowercases to a 1-byte 'k', which used to shift the byte offsets
+        assert_eq!(regions("html", "\u{212A}<script>日\nx = 1;\n</script>\n"), vec![("js".to_string(), 1, 2)]);
+        assert!(regions("html", "\u{212A}<script>日</script>\n").is_empty());
+    }
+
+    #[test]
+    fn markdown_fence_regions() {
+        let markdown = "# Title\n```rust\nfn main() {}\n```\n~~~~ {.python}\nx = 1\n~~~\ny = 2\n~~~~\n```\nplain\n```\n````text\n```rust\n````\n";
+        assert_eq!(regions("md", markdown), vec![
+            ("rs".to_string(), 2, 3),
+            ("py".to_string(), 5, 8),
+        ]);
+    }
+
+    // A fresh directory under the system temp dir holding the given files
+    fn temp_tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("loco-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn hashed(mut file: FileInfo, hash: u64) -> FileInfo {
+        file.content_hash = Some(hash);
+        file
+    }
+
+    #[test]
+    fn diff_matches_files_below_each_root() {
+        let old = ProjectStats {
+            files_info: vec![
+                hashed(test_file("old/same.rs", "Rust 🦀", 10), 1),
+                hashed(test_file("old/edited.rs", "Rust 🦀", 10), 2),
+                hashed(test_file("old/gone.rs", "Rust 🦀", 5), 3),
+            ],
+            ..Default::default()
+        };
+        let new = ProjectStats {
+            files_info: vec![
+                hashed(test_file("new/same.rs", "Rust 🦀", 10), 1),
+                // Same counts, different bytes
+                hashed(test_file("new/edited.rs", "Rust 🦀", 10), 4),
+                hashed(test_file("new/added.rs", "Rust 🦀", 20), 5),
+            ],
+            ..Default::default()
+        };
+        let (old_args, new_args) = (test_args(&["-p", "old"]), test_args(&["-p", "new"]));
+
+        let report = diff_projects("old", "new", (&old, &old_args), (&new, &new_args));
+        let changes: Vec<(String, FileChange)> = report.changed_files.iter()
+            .map(|file| (file.path.display().to_string(), file.status))
+            .collect();
+        assert_eq!(changes, vec![
+            ("added.rs".to_string(), FileChange::Added),
+            ("gone.rs".to_string(), FileChange::Removed),
+            ("edited.rs".to_string(), FileChange::Modified),
+        ]);
+        assert_eq!((report.files_added, report.files_removed, report.files_modified), (1, 1, 1));
+    }
+
+    #[test]
+    fn each_analysis_uses_its_own_language_definitions() {
+        let plain = temp_tree("languages-plain", &[("x.foo", "a\n# c\n")]);
+        let custom = temp_tree("languages-custom", &[
+            ("x.foo", "a\n# c\n"),
+            (".loco-languages.toml", "[languages.foo]\nextensions = [\"foo\"]\nline_comments = [\"#\"]\nname = \"Foo\"\n"),
+        ]);
+        let analyze = |root: &Path| analyze_project(&test_args(&["-p", root.to_str().unwrap(), "--format", "json"]));
+
+        let first = analyze(&custom).expect("the custom language is counted");
+        assert_eq!(first.languages["Foo"].comment_lines, 1);
+        // The definitions from the first run must not leak into the second
+        assert!(analyze(&plain).is_none());
+        assert!(analyze(&custom).is_some());
+
+        fs::remove_dir_all(plain).unwrap();
+        fs::remove_dir_all(custom).unwrap();
+    }
+
+    #[test]
+    fn duplicate_blocks_across_files() {
+        let block = "total = 0\nfor item in items:\n    total += item.price\n    count += 1\naverage = total / count\nprint(average)\n";
+        let root = temp_tree("duplicates", &[
+            ("a.py", &format!("import os\n\n{}", block)),
+            ("b.py", &format!("# copied\nx = 1\n{}y = 2\n", block)),
+            ("c.py", "print('unrelated')\n"),
+        ]);
+        let paths: Vec<PathBuf> = ["a.py", "b.py", "c.py"].iter().map(|name| root.join(name)).collect();
+
+        let report = detect_duplicates(&paths, &test_args(&[]), Source::WorkingTree, &CustomLanguages::default());
+        assert_eq!(report.clone_pairs.len(), 1);
+        let pair = &report.clone_pairs[0];
+        assert_eq!(pair.lines, 6);
+        assert_eq!((pair.first.start_line, pair.first.end_line), (3, 8));
+        assert_eq!((pair.second.start_line, pair.second.end_line), (3, 8));
+        assert_eq!(report.duplicated_lines, 12);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_are_grouped_by_their_lines_not_the_rolling_hash() {
+        // [0, BASE] and [1, 0] share the rolling hash BASE
+        let file = |name: &str, hashes: Vec<u64>| NormalizedFile {
+            path: PathBuf::from(name),
+            line_numbers: (1..=hashes.len()).collect(),
+            hashes,
+        };
+        let files = vec![
+            file("a.rs", vec![0, 1_000_003]),
+            file("b.rs", vec![1, 0]),
+            file("c.rs", vec![1, 0]),
+        ];
+
+        let report = find_clones(&files, 2);
+        assert_eq!(report.clone_pairs.len(), 1);
+        assert_eq!(report.clone_pairs[0].first.path, PathBuf::from("b.rs"));
+        assert_eq!(report.clone_pairs[0].second.path, PathBuf::from("c.rs"));
+        assert_eq!(report.duplicated_lines, 4);
+    }
+
+    #[test]
+    fn cache_hits_until_the_file_or_settings_change() {
+        let root = temp_tree("cache", &[("a.py", "x = 1\n# note\n")]);
+        let file = root.join("a.py");
+        let cache_file = root.join("cache.json");
+        let config = LanguageConfig::builtin("py").unwrap();
+        let custom = CustomLanguages::default();
+        let run = |extra: &[&str]| {
+            let mut flags = vec!["--cache", "--cache-file", cache_file.to_str().unwrap()];
+            flags.extend_from_slice(extra);
+            let args = test_args(&flags);
+            let cache = AnalysisCache::load(std::slice::from_ref(&root), &args);
+            let (stats, _) = cache.analyze(&file, "py", &config, &args, &custom).unwrap();
+            let hits = cache.hits.load(Ordering::Relaxed);
+            cache.save();
+            (hits, stats.comment_lines)
+        };
+
+        assert_eq!(run(&[]), (0, 1));
+        assert_eq!(run(&[]), (1, 1));
+
+        // Same size, different content: the content hash catches what mtime may not
+        fs::write(&file, "y = 2\nz = 3\n").unwrap();
+        assert_eq!(run(&["--cache-hash"]), (0, 0));
+        assert_eq!(run(&["--cache-hash"]), (1, 0));
+
+        // Any flag that reaches the analyzer starts a fresh cache
+        assert_eq!(run(&["--cache-hash", "--include-unknown"]), (0, 0));
+        assert_eq!(run(&["--cache-hash", "--include-unknown", "--generated", "include"]), (0, 0));
+        assert_eq!(run(&["--cache-hash", "--include-unknown", "--generated", "include"]), (1, 0));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn directory_keys_roll_up_to_the_root() {
+        let root = Path::new("/repo");
+        assert_eq!(directory_keys(Path::new("/repo/main.rs"), root, 0), vec!["."]);
+        assert_eq!(directory_keys(Path::new("/repo/src/net/http/client.rs"), root, 0),
+            vec![".", "src", "src/net", "src/net/http"]);
+        // --dir-depth caps the rollup; deeper files count towards their ancestor at that depth
+        assert_eq!(directory_keys(Path::new("/repo/src/net/http/client.rs"), root, 2), vec![".", "src", "src/net"]);
+        assert_eq!(directory_keys(Path::new("./src/lib.rs"), Path::new("."), 1), vec![".", "src"]);
+
+        assert_eq!(parent_directory_key("src/net/http"), Some("src/net"));
+        assert_eq!(parent_directory_key("src"), Some("."));
+        assert_eq!(parent_directory_key("."), None);
+    }
+
+    #[test]
+    fn notebook_cells_are_counted_by_kind() {
+        let notebook = r##"{
+            "metadata": {"kernelspec": {"language": "python"}},
+            "nbformat": 4,
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title\n", "\n", "Some text"]},
+                {"cell_type": "code", "source": ["import os\n", "# comment\n", "x = 1"],
+                 "outputs": [{"output_type": "stream", "text": ["a\n", "b\n", "c\n"]}]},
+                {"cell_type": "code", "source": "y = 2\n\nz = 3"},
+                {"cell_type": "raw", "source": "ignored"}
+            ]
+        }"##;
+        let (counts, cells) = analyze_notebook(notebook, &CustomLanguages::default()).unwrap();
+        assert_eq!((cells.code_cells, cells.markdown_cells, cells.raw_cells), (2, 1, 1));
+        assert_eq!(cells.kernel, builtin_language_name("py"));
+        // Outputs and raw cells are not counted; markdown counts as documentation
+        assert_eq!(counts.lines, 9);
+        assert_eq!((counts.code_lines, counts.comment_lines, counts.blank_lines), (4, 3, 2));
+
+        // nbformat 3 keeps cells in worksheets and code in `input`
+        let legacy = r##"{"metadata": {"language": "julia"}, "worksheets": [{"cells": [
+            {"cell_type": "code", "input": ["a = 1\n", "b = 2"]},
+            {"cell_type": "heading", "source": "Results"}
+        ]}]}"##;
+        let (counts, cells) = analyze_notebook(legacy, &CustomLanguages::default()).unwrap();
+        assert_eq!((cells.code_cells, cells.markdown_cells), (1, 1));
+        assert_eq!((counts.code_lines, counts.comment_lines), (2, 1));
+
+        assert!(analyze_notebook("not json", &CustomLanguages::default()).is_none());
+    }
+
+    #[test]
+    fn last_matching_glob_wins() {
+        let args = test_args(&[
+            "--exclude-glob", "tests/**",
+            "--include-glob", "tests/fixtures/keep.rs",
+            "--exclude-glob", "*.snap",
+            "--include-glob", "src/",
+        ]);
+        let root = Path::new("/repo");
+        let globs = PathGlobMatcher::new(root, &args.path_globs).unwrap();
+        let verdict = |path: &str| globs.verdict(&root.join(path), false);
+
+        assert!(globs.has_includes());
+        assert_eq!(verdict("tests/unit.rs"), Some(false));
+        assert_eq!(verdict("tests/fixtures/keep.rs"), Some(true));
+        assert_eq!(verdict("tests/fixtures/keep.snap"), Some(false));
+        // A directory glob covers everything below it, even after an earlier exclude
+        assert_eq!(verdict("src/output.snap"), Some(true));
+        assert_eq!(verdict("README.md"), None);
+        assert_eq!(globs.verdict(Path::new("/elsewhere/tests/a.rs"), false), None);
+    }
+
+    #[test]
+    fn history_samples_are_evenly_spaced() {
+        assert_eq!(sample_indices(0, 5), Vec::<usize>::new());
+        assert_eq!(sample_indices(10, 0), Vec::<usize>::new());
+        assert_eq!(sample_indices(3, 5), vec![0, 1, 2]);
+        // A single sample is the newest commit
+        assert_eq!(sample_indices(10, 1), vec![9]);
+        assert_eq!(sample_indices(10, 2), vec![0, 9]);
+        assert_eq!(sample_indices(11, 5), vec![0, 3, 5, 8, 10]);
+        assert_eq!(sample_indices(101, 3), vec![0, 50, 100]);
+    }
+
+    #[test]
+    fn numstat_records_keep_paths_verbatim() {
+        // As printed by `git log -z --numstat --format=%x01%aE`: paths are neither quoted nor escaped
+        let log = b"\x01ana@example.com\0\n3\t1\tsrc/caf\xc3\xa9 menu.rs\0-\t-\tassets/logo.png\0\
+\x01bo@example.com\0\n2\t2\tsrc/caf\xc3\xa9 menu.rs\0\x01ana@example.com\0\n1\t0\tweird\tname\nwith newline.txt\0";
+        let mut churn = HashMap::new();
+        add_numstat_churn(log, Path::new("/repo"), &mut churn);
+
+        let menu = &churn[Path::new("/repo/src/café menu.rs")];
+        assert_eq!((menu.commits, menu.lines_added, menu.lines_deleted, menu.authors), (2, 5, 3, 2));
+        let logo = &churn[Path::new("/repo/assets/logo.png")];
+        assert_eq!((logo.commits, logo.lines_added, logo.lines_deleted, logo.authors), (1, 0, 0, 1));
+        assert!(churn.contains_key(Path::new("/repo/weird\tname\nwith newline.txt")));
+        assert_eq!(churn.len(), 3);
+    }
+}